@@ -0,0 +1,100 @@
+//! Scoring cells against every thumbnail one at a time, against scoring them in one
+//! batched pass over the pool's columns, on one thread against a pool of random
+//! thumbnails. Run with `cargo bench --features bench --bench batched_scoring`
+
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, Rgb, RgbImage};
+use imagegrid::{
+    DifferenceFunction, ResizeFilter, SampleStat, ThumbnailDb, ThumbnailStore, WhiteBalance,
+    bench::Scorer,
+};
+
+/// Thumbnails in the pool
+const THUMBS: usize = 3000;
+/// Cells scored against it, as in a 40x30 grid
+const CELLS: usize = 1200;
+/// Runs of each setup, the fastest of which is reported
+const RUNS: usize = 5;
+
+/// A deterministic stream of bytes
+fn next(seed: &mut u64) -> u8 {
+    *seed = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (*seed >> 33) as u8
+}
+
+/// A stand-in for a real thumbnail: noise around a random base colour
+fn noise(seed: &mut u64) -> RgbImage {
+    let base = [next(seed), next(seed), next(seed)];
+
+    RgbImage::from_fn(16, 16, |_, _| {
+        Rgb(base.map(|c| c.saturating_add(next(seed) / 4).saturating_sub(32)))
+    })
+}
+
+fn pool(sampleres: u32) -> ThumbnailDb {
+    let mut thumbs_db = ThumbnailDb::default();
+    let mut thumbs_store = ThumbnailStore::new(false);
+    let mut seed = 7;
+    let sampling = (
+        sampleres,
+        SampleStat::Mean,
+        ResizeFilter::CatmullRom,
+        None,
+        WhiteBalance::None,
+    );
+
+    for i in 0..THUMBS {
+        let image = DynamicImage::ImageRgb8(noise(&mut seed));
+        thumbs_store.register_image(&format!("thumb-{i:05}"), image, sampling, &mut thumbs_db);
+    }
+
+    thumbs_db
+}
+
+/// Fastest of `RUNS` passes of `score` over every cell, and the scores it gave
+fn time(cells: &[Vec<[u8; 3]>], score: impl Fn(&[[u8; 3]]) -> Vec<f64>) -> (Duration, Vec<f64>) {
+    let mut fastest = Duration::MAX;
+    let mut scores = Vec::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        scores = cells.iter().flat_map(|cell| score(cell)).collect();
+        fastest = fastest.min(start.elapsed());
+    }
+
+    (fastest, scores)
+}
+
+fn main() {
+    println!(
+        "{:>10} {:>10} {:>12} {:>12}",
+        "algorithm", "sampleres", "one by one", "batched"
+    );
+    for algorithm in [DifferenceFunction::Rgb, DifferenceFunction::Oklab] {
+        for sampleres in [4, 8] {
+            let scorer = Scorer::new(pool(sampleres), algorithm).unwrap();
+            let mut seed = 1;
+            let samples = (sampleres * sampleres) as usize;
+            let cells: Vec<Vec<[u8; 3]>> = (0..CELLS)
+                .map(|_| {
+                    let sample = |_| [next(&mut seed), next(&mut seed), next(&mut seed)];
+                    (0..samples).map(sample).collect()
+                })
+                .collect();
+
+            let (scan, scan_scores) = time(&cells, |cell| scorer.scan(cell));
+            let (batched, batched_scores) = time(&cells, |cell| scorer.batched(cell));
+            assert_eq!(scan_scores, batched_scores, "{algorithm:?} at {sampleres}");
+
+            println!(
+                "{:>10} {:>10} {:>9.1} ms {:>9.1} ms",
+                format!("{algorithm:?}"),
+                sampleres,
+                scan.as_secs_f64() * 1000.0,
+                batched.as_secs_f64() * 1000.0
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Matching time scoring every thumbnail against finding each cell's match through
+//! `Config::spatial_index`, on one thread against pools of random thumbnails. Run with
+//! `cargo bench --bench spatial_index`
+
+use std::{path::PathBuf, time::Duration};
+
+use image::{DynamicImage, Rgb, RgbImage};
+use imagegrid::{
+    Config, ResizeFilter, SampleStat, ThumbnailDb, ThumbnailStore, WhiteBalance,
+    generate_mosaic_from,
+};
+
+/// Runs of each setup, the fastest of which is reported
+const RUNS: usize = 5;
+
+/// A deterministic stand-in for real thumbnails: noise around a random base colour
+fn noise(seed: &mut u64, width: u32, height: u32) -> RgbImage {
+    let mut next = || {
+        *seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (*seed >> 33) as u8
+    };
+    let base = [next(), next(), next()];
+
+    RgbImage::from_fn(width, height, |_, _| {
+        Rgb(base.map(|c| c.saturating_add(next() / 4).saturating_sub(32)))
+    })
+}
+
+/// A pool of `count` thumbnails sampled at `sampleres`
+fn pool(count: usize, sampleres: u32) -> (ThumbnailDb, ThumbnailStore) {
+    let mut thumbs_db = ThumbnailDb::default();
+    let mut thumbs_store = ThumbnailStore::new(false);
+    let mut seed = 7;
+    let sampling = (
+        sampleres,
+        SampleStat::Mean,
+        ResizeFilter::CatmullRom,
+        None,
+        WhiteBalance::None,
+    );
+
+    for i in 0..count {
+        let image = DynamicImage::ImageRgb8(noise(&mut seed, 16, 16));
+        thumbs_store.register_image(&format!("thumb-{i:05}"), image, sampling, &mut thumbs_db);
+    }
+
+    (thumbs_db, thumbs_store)
+}
+
+/// Fastest matching phase of `RUNS` renders of `config`
+fn matching_time(config: &Config, count: usize) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let (thumbs_db, thumbs_store) = pool(count, config.sampleres);
+            let mosaic = generate_mosaic_from(config, thumbs_db, thumbs_store).unwrap();
+            let phases = mosaic.profile.phases;
+            phases
+                .iter()
+                .find(|(phase, _)| *phase == "matching")
+                .unwrap()
+                .1
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let input: PathBuf = std::env::temp_dir().join("imagegrid-bench-spatial-index.png");
+    // 40x30 cells of 16px
+    noise(&mut 1, 640, 480).save(&input).unwrap();
+
+    println!(
+        "{:>7} {:>10} {:>12} {:>12}",
+        "thumbs", "sampleres", "scan", "k-d tree"
+    );
+    for count in [1000, 3000] {
+        for sampleres in [2, 4] {
+            let config = Config {
+                input: input.clone(),
+                sampleres,
+                thumbsize: 16,
+                threads: Some(1),
+                match_only: true,
+                exact: true,
+                ..Config::default()
+            };
+            let scan = matching_time(&config, count);
+            let tree = matching_time(
+                &Config {
+                    spatial_index: true,
+                    ..config.clone()
+                },
+                count,
+            );
+
+            println!(
+                "{:>7} {:>10} {:>9.1} ms {:>9.1} ms",
+                count,
+                sampleres,
+                scan.as_secs_f64() * 1000.0,
+                tree.as_secs_f64() * 1000.0
+            );
+        }
+    }
+
+    let _ = std::fs::remove_file(input);
+}
@@ -0,0 +1,145 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::matching::{best_index, ranked};
+
+/// How cells are given their thumbnails once they're scored
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum AssignMode {
+    /// Every cell gets its own best match, however often that repeats a thumbnail
+    Greedy,
+    /// Spread the pool over the whole grid, each thumbnail placed about as often as the
+    /// others, for as low a total score as can be found
+    Global,
+}
+
+/// Most passes over every pair of cells looking for swaps. Each pass is quadratic in the
+/// cell count, and later passes rarely find much
+const MAX_SWAP_PASSES: usize = 8;
+
+/// A cell waiting for the thumbnail at `rank` in its own order
+struct Candidate {
+    score: f64,
+    cell: usize,
+    thumb: usize,
+    rank: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    /// Reversed so the heap pops the lowest score first, earlier cells first among ties
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .score
+            .total_cmp(&self.score)
+            .then(other.cell.cmp(&self.cell))
+    }
+}
+
+/// Pick a pool index for each cell scored in `scores`, placing thumbnail `i` at most
+/// `capacity[i]` times, which must add up to at least the number of cells. The cheapest
+/// pairing of a cell with a thumbnail it can still have is made first, then pairs of cells
+/// swap thumbnails, or a cell moves to a thumbnail with uses to spare, while that lowers
+/// the total score. Returns the picks with the total score before and after the swaps
+pub(crate) fn assign_global(scores: &[&[f64]], mut capacity: Vec<u32>) -> (Vec<usize>, f64, f64) {
+    let cells = scores.len();
+    let mut chosen = vec![usize::MAX; cells];
+    // Ranking a cell's whole pool is only worth it once its best match is taken
+    let mut orders: Vec<Option<Vec<usize>>> = vec![None; cells];
+
+    let mut queue: BinaryHeap<Candidate> = scores
+        .iter()
+        .enumerate()
+        .filter_map(|(cell, scores)| {
+            let thumb = best_index(scores)?;
+            Some(Candidate {
+                score: scores[thumb],
+                cell,
+                thumb,
+                rank: 0,
+            })
+        })
+        .collect();
+    while let Some(candidate) = queue.pop() {
+        if capacity[candidate.thumb] > 0 {
+            capacity[candidate.thumb] -= 1;
+            chosen[candidate.cell] = candidate.thumb;
+            continue;
+        }
+
+        // Used up, so the cell queues again for the next thumbnail it can still have
+        let order = orders[candidate.cell].get_or_insert_with(|| ranked(scores[candidate.cell]));
+        if let Some((rank, &thumb)) = order
+            .iter()
+            .enumerate()
+            .skip(candidate.rank + 1)
+            .find(|&(_, &thumb)| capacity[thumb] > 0)
+        {
+            queue.push(Candidate {
+                score: scores[candidate.cell][thumb],
+                cell: candidate.cell,
+                thumb,
+                rank,
+            });
+        }
+    }
+    drop(orders);
+
+    let cost = |cell: usize, thumb: usize| scores[cell][thumb];
+    let total = |chosen: &[usize]| -> f64 {
+        chosen
+            .iter()
+            .enumerate()
+            .map(|(cell, &thumb)| cost(cell, thumb))
+            .sum()
+    };
+    let greedy_total = total(&chosen);
+
+    for _ in 0..MAX_SWAP_PASSES {
+        let mut improved = false;
+        for a in 0..cells {
+            let spare = (0..capacity.len())
+                .filter(|&thumb| capacity[thumb] > 0)
+                .min_by(|&x, &y| cost(a, x).total_cmp(&cost(a, y)));
+            if let Some(spare) = spare
+                && cost(a, spare) < cost(a, chosen[a])
+            {
+                capacity[chosen[a]] += 1;
+                capacity[spare] -= 1;
+                chosen[a] = spare;
+                improved = true;
+            }
+
+            for b in a + 1..cells {
+                let (thumb_a, thumb_b) = (chosen[a], chosen[b]);
+                if thumb_a != thumb_b
+                    && cost(a, thumb_b) + cost(b, thumb_a) < cost(a, thumb_a) + cost(b, thumb_b)
+                {
+                    chosen.swap(a, b);
+                    improved = true;
+                }
+            }
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    let swapped_total = total(&chosen);
+    (chosen, greedy_total, swapped_total)
+}
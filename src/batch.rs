@@ -0,0 +1,182 @@
+use crate::{db::ThumbnailData, matching::DifferenceFunction};
+
+/// Most samples whose summed squared RGB differences are sure to fit in an `i32`
+const RGB_BLOCK_SAMPLES: usize = (i32::MAX / (3 * 255 * 255)) as usize;
+
+/// The pool's colours laid out component by component, so a chunk is scored against
+/// every thumbnail in one pass that the compiler can vectorise across thumbnails. Each
+/// thumbnail's sum is still accumulated sample by sample in the same order as
+/// `compare_thumbs_u8` and `compare_thumbs_f32`, so the scores come out bit for bit the same
+pub(crate) struct PoolColumns {
+    /// Number of thumbnails
+    len: usize,
+    /// Samples per thumbnail
+    samples: usize,
+    values: Columns,
+}
+
+/// Value `c` of sample `s` of thumbnail `i` is at `(s * 3 + c) * len + i`
+enum Columns {
+    Rgb(Vec<i32>),
+    /// Weighted Oklab or CIELAB, or average Oklab
+    Lab(Vec<f32>),
+}
+
+impl PoolColumns {
+    /// Lay out `pool` for `algorithm`, or `None` if it isn't compared by summed squared
+    /// differences or the thumbnails don't all have the same number of samples. Average
+    /// colours are laid out as a single sample
+    pub(crate) fn new(pool: &[ThumbnailData], algorithm: DifferenceFunction) -> Option<Self> {
+        let members: Vec<usize> = (0..pool.len()).collect();
+        Self::subset(pool, &members, algorithm)
+    }
+
+    /// Lay out the `members` of `pool` like `new`, scored in the order they're listed
+    pub(crate) fn subset(
+        pool: &[ThumbnailData],
+        members: &[usize],
+        algorithm: DifferenceFunction,
+    ) -> Option<Self> {
+        let thumb = |i: usize| &pool[members[i]];
+        let samples = match algorithm {
+            DifferenceFunction::AverageColor => 1,
+            _ => pool.get(*members.first()?)?.colors.len(),
+        };
+        let lab = |i: usize| match algorithm {
+            DifferenceFunction::Cielab => &thumb(i).cielab[..],
+            DifferenceFunction::AverageColor => thumb(i).mean_oklab.as_slice(),
+            _ => &thumb(i).oklab[..],
+        };
+        let averaged = algorithm == DifferenceFunction::AverageColor;
+        if (0..members.len())
+            .any(|i| (!averaged && thumb(i).colors.len() != samples) || lab(i).len() != samples)
+        {
+            return None;
+        }
+
+        let len = members.len();
+        let values = match algorithm {
+            DifferenceFunction::Rgb => Columns::Rgb(columns(len, samples, |i, s, c| {
+                thumb(i).colors[s][c] as i32
+            })),
+            DifferenceFunction::Oklab
+            | DifferenceFunction::Cielab
+            | DifferenceFunction::AverageColor => {
+                Columns::Lab(columns(len, samples, |i, s, c| lab(i)[s][c]))
+            }
+            DifferenceFunction::Ciede2000 | DifferenceFunction::Hsv => return None,
+        };
+
+        Some(PoolColumns {
+            len,
+            samples,
+            values,
+        })
+    }
+
+    /// Score the sampled chunk colours, or their weighted Oklab or CIELAB in `lab`, against every
+    /// thumbnail in pool order, each sample scaled by `weights` unless it's empty
+    pub(crate) fn scores(&self, pixels: &[[u8; 3]], lab: &[[f32; 3]], weights: &[f32]) -> Vec<f64> {
+        match &self.values {
+            Columns::Rgb(values) => {
+                if pixels.len() != self.samples {
+                    return vec![f64::MAX; self.len];
+                }
+                if weights.is_empty() {
+                    return self.rgb(values, pixels);
+                }
+                self.rgb_weighted(values, pixels, weights)
+            }
+            Columns::Lab(values) => {
+                if lab.len() != self.samples {
+                    return vec![f32::MAX as f64; self.len];
+                }
+                self.lab(values, lab, weights)
+            }
+        }
+    }
+
+    /// The three columns of sample `s`
+    fn sample<'a, T>(&self, values: &'a [T], s: usize) -> (&'a [T], &'a [T], &'a [T]) {
+        let values = &values[s * 3 * self.len..(s + 1) * 3 * self.len];
+        let (c0, rest) = values.split_at(self.len);
+        let (c1, c2) = rest.split_at(self.len);
+
+        (c0, c1, c2)
+    }
+
+    /// Sums are kept in `i32`s a block of samples at a time, which vectorises twice as
+    /// wide, and carried into `i64`s between blocks
+    fn rgb(&self, values: &[i32], pixels: &[[u8; 3]]) -> Vec<f64> {
+        let mut diff = vec![0i64; self.len];
+        let mut block = vec![0i32; self.len];
+        for (b, pixels) in pixels.chunks(RGB_BLOCK_SAMPLES).enumerate() {
+            for (s, x) in pixels.iter().enumerate() {
+                let x = x.map(i32::from);
+                let (c0, c1, c2) = self.sample(values, b * RGB_BLOCK_SAMPLES + s);
+                for (((d, y0), y1), y2) in block.iter_mut().zip(c0).zip(c1).zip(c2) {
+                    *d += (x[0] - y0).pow(2u32) + (x[1] - y1).pow(2u32) + (x[2] - y2).pow(2u32);
+                }
+            }
+            for (d, block) in diff.iter_mut().zip(&mut block) {
+                *d += std::mem::take(block) as i64;
+            }
+        }
+
+        diff.into_iter().map(|d| d as f64).collect()
+    }
+
+    fn rgb_weighted(&self, values: &[i32], pixels: &[[u8; 3]], weights: &[f32]) -> Vec<f64> {
+        let mut diff = vec![0f64; self.len];
+        for ((s, x), w) in pixels.iter().enumerate().zip(weights) {
+            let x = x.map(i32::from);
+            let w = *w as f64;
+            let (c0, c1, c2) = self.sample(values, s);
+            for (((d, y0), y1), y2) in diff.iter_mut().zip(c0).zip(c1).zip(c2) {
+                let term = (x[0] - y0).pow(2u32) + (x[1] - y1).pow(2u32) + (x[2] - y2).pow(2u32);
+                *d += term as f64 * w;
+            }
+        }
+
+        diff
+    }
+
+    fn lab(&self, values: &[f32], lab: &[[f32; 3]], weights: &[f32]) -> Vec<f64> {
+        let mut diff = vec![0f32; self.len];
+        for (s, x) in lab.iter().enumerate() {
+            let (c0, c1, c2) = self.sample(values, s);
+            let terms = diff.iter_mut().zip(c0).zip(c1).zip(c2);
+            match weights.get(s) {
+                None => {
+                    for (((d, y0), y1), y2) in terms {
+                        *d += (x[0] - y0).powi(2i32)
+                            + (x[1] - y1).powi(2i32)
+                            + (x[2] - y2).powi(2i32);
+                    }
+                }
+                Some(w) => {
+                    for (((d, y0), y1), y2) in terms {
+                        let term = (x[0] - y0).powi(2i32)
+                            + (x[1] - y1).powi(2i32)
+                            + (x[2] - y2).powi(2i32);
+                        *d += term * w;
+                    }
+                }
+            }
+        }
+
+        diff.into_iter().map(f64::from).collect()
+    }
+}
+
+/// `value(thumb, sample, component)` for every thumbnail, laid out as `Columns` describes
+fn columns<T>(len: usize, samples: usize, value: impl Fn(usize, usize, usize) -> T) -> Vec<T> {
+    let mut values = Vec::with_capacity(len * samples * 3);
+    for s in 0..samples {
+        for c in 0..3 {
+            values.extend((0..len).map(|i| value(i, s, c)));
+        }
+    }
+
+    values
+}
@@ -0,0 +1,45 @@
+//! Scoring internals for `benches/`, which otherwise only see the public API. Only built
+//! with the `bench` feature
+
+use crate::{
+    batch::PoolColumns,
+    db::{ThumbnailData, ThumbnailDb},
+    matching::{DifferenceFunction, Metric, SampleWeights, Search, score_pixels},
+};
+
+/// A locked pool that sampled chunk colours can be scored against either one thumbnail
+/// at a time or in one batched pass, with unweighted Oklab
+pub struct Scorer {
+    pool: Vec<ThumbnailData>,
+    metric: Metric,
+    columns: Search,
+}
+
+impl Scorer {
+    /// Lock `thumbs_db` for `algorithm`, or `None` if it can't be batched
+    pub fn new(thumbs_db: ThumbnailDb, algorithm: DifferenceFunction) -> Option<Self> {
+        let pool: Vec<ThumbnailData> = thumbs_db.thumbs.into_iter().collect();
+        let columns = Search::Batched(PoolColumns::new(&pool, algorithm)?);
+
+        Some(Scorer {
+            pool,
+            metric: Metric {
+                algorithm,
+                lab_weights: [1.0; 3],
+                sample_weights: SampleWeights::Flat,
+                structure_weight: 0.0,
+            },
+            columns,
+        })
+    }
+
+    /// Every thumbnail's score against `pixels`, scoring them one after another
+    pub fn scan(&self, pixels: &[[u8; 3]]) -> Vec<f64> {
+        score_pixels(pixels, self.metric, &self.pool, &Search::Scan)
+    }
+
+    /// Every thumbnail's score against `pixels`, in one pass over the pool's columns
+    pub fn batched(&self, pixels: &[[u8; 3]]) -> Vec<f64> {
+        score_pixels(pixels, self.metric, &self.pool, &self.columns)
+    }
+}
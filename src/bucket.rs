@@ -0,0 +1,139 @@
+use crate::{
+    batch::PoolColumns,
+    db::ThumbnailData,
+    matching::{DifferenceFunction, Metric, mean_lab, oklab_colors},
+};
+
+/// Buckets along each Oklab axis
+const BINS: usize = 8;
+
+/// Fewest thumbnails a chunk is scored against. Rings of neighbouring buckets are added
+/// until there are at least this many, so a chunk in a sparse part of the colour space
+/// still has a choice
+const MIN_CANDIDATES: usize = 64;
+
+/// The pool grouped by average Oklab colour into a `BINS`³ grid spanning the pool's
+/// range, built once the pool is locked. A chunk is only scored against the thumbnails
+/// in and around its own bucket, which is much faster on large collections but can miss
+/// a best match that lies further out
+pub(crate) struct ColorBuckets {
+    /// Lowest average of the pool along each axis
+    min: [f32; 3],
+    /// Buckets per unit along each axis
+    scale: [f32; 3],
+    /// Indexed by `(l * BINS + a) * BINS + b`
+    buckets: Vec<Bucket>,
+    /// Number of thumbnails
+    len: usize,
+}
+
+struct Bucket {
+    /// Pool indices in pool order
+    members: Vec<usize>,
+    /// The members laid out to be scored together, like the whole pool would be
+    columns: Option<PoolColumns>,
+}
+
+impl ColorBuckets {
+    pub(crate) fn new(pool: &[ThumbnailData], algorithm: DifferenceFunction) -> Self {
+        let means: Vec<[f32; 3]> = pool.iter().map(|thumb| mean_oklab(&thumb.colors)).collect();
+
+        let mut min = [f32::INFINITY; 3];
+        let mut max = [f32::NEG_INFINITY; 3];
+        for mean in &means {
+            for c in 0..3 {
+                min[c] = min[c].min(mean[c]);
+                max[c] = max[c].max(mean[c]);
+            }
+        }
+        let scale = std::array::from_fn(|c| match max[c] > min[c] {
+            true => BINS as f32 / (max[c] - min[c]),
+            false => 0.0,
+        });
+
+        let mut index = ColorBuckets {
+            min,
+            scale,
+            buckets: Vec::new(),
+            len: pool.len(),
+        };
+        let mut members = vec![Vec::new(); BINS.pow(3)];
+        for (i, mean) in means.iter().enumerate() {
+            let [l, a, b] = index.bin(mean);
+            members[(l * BINS + a) * BINS + b].push(i);
+        }
+        index.buckets = members
+            .into_iter()
+            .map(|members| Bucket {
+                columns: PoolColumns::subset(pool, &members, algorithm),
+                members,
+            })
+            .collect();
+
+        index
+    }
+
+    /// Bucket along each axis that an average colour falls in, clamped to the grid
+    fn bin(&self, mean: &[f32; 3]) -> [usize; 3] {
+        std::array::from_fn(|c| {
+            (((mean[c] - self.min[c]) * self.scale[c]).max(0.0) as usize).min(BINS - 1)
+        })
+    }
+
+    /// Score the thumbnails in and around the bucket of the sampled chunk colours
+    /// `pixels`, widening the search until there are enough. Takes the same arguments as
+    /// `Metric::score_thumb`, and everything not scored scores infinity
+    pub(crate) fn scores(
+        &self,
+        pixels: &[[u8; 3]],
+        lab: &[[f32; 3]],
+        weights: &[f32],
+        metric: Metric,
+        thumbs: &[ThumbnailData],
+    ) -> Vec<f64> {
+        let centre = self.bin(&mean_oklab(pixels));
+        let around =
+            |c: usize, radius: usize| c.saturating_sub(radius)..=(c + radius).min(BINS - 1);
+
+        let mut nearby = Vec::new();
+        // Radius BINS - 1 reaches every bucket from anywhere
+        for radius in 1..BINS {
+            nearby.clear();
+            for l in around(centre[0], radius) {
+                for a in around(centre[1], radius) {
+                    for b in around(centre[2], radius) {
+                        nearby.push(&self.buckets[(l * BINS + a) * BINS + b]);
+                    }
+                }
+            }
+            let candidates: usize = nearby.iter().map(|bucket| bucket.members.len()).sum();
+            if candidates >= MIN_CANDIDATES.min(self.len) {
+                break;
+            }
+        }
+
+        let mut scores = vec![f64::INFINITY; self.len];
+        for bucket in nearby {
+            match &bucket.columns {
+                Some(columns) => {
+                    let bucket_scores = columns.scores(pixels, lab, weights);
+                    for (&i, score) in bucket.members.iter().zip(bucket_scores) {
+                        scores[i] = score;
+                    }
+                }
+                None => {
+                    for &i in &bucket.members {
+                        scores[i] = metric.score_thumb(pixels, lab, weights, &thumbs[i]);
+                    }
+                }
+            }
+        }
+
+        scores
+    }
+}
+
+/// Average Oklab of `colors`
+fn mean_oklab(colors: &[[u8; 3]]) -> [f32; 3] {
+    mean_lab(&oklab_colors(colors))
+}
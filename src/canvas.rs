@@ -0,0 +1,696 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+use crate::{
+    Assignment, Error,
+    db::ThumbnailStore,
+    matching::Flip,
+    sample::{ResizeFilter, WhiteBalance, flatten_alpha, white_balance},
+};
+
+/// Fixed-point scale of `Canvas::feathered`, fine enough to keep the darkest 8-bit
+/// shades apart in linear light
+const FEATHER_SCALE: f32 = (1 << 20) as f32;
+
+/// The output image, and the resized thumbnails that get drawn into it
+pub(crate) struct Canvas {
+    pub(crate) image: RgbImage,
+    /// Grid row at the top of `image`, which only holds a band of rows when compositing
+    /// in strips
+    pub(crate) first_row: u32,
+    /// Tile width and height
+    pub(crate) tile_size: (u32, u32),
+    pub(crate) native_tiles: bool,
+    /// Filter tiles are resized with, unless they're native
+    pub(crate) filter: ResizeFilter,
+    /// How far each placed tile is pulled toward its cell's colour, 0 leaves it untouched
+    pub(crate) blend: f32,
+    /// Average linear RGB of each input cell in raster order, empty without `blend`
+    pub(crate) cell_colors: Vec<[f32; 3]>,
+    /// Cells per row of `cell_colors` and `cell_luminance`
+    pub(crate) columns: u32,
+    /// Largest factor a placed tile's brightness is scaled by, either way, to match its
+    /// cell's. `None` leaves tiles as they are
+    pub(crate) max_adjust: Option<f32>,
+    /// Scale each tile's contrast to its cell's as well as its brightness
+    pub(crate) match_contrast: bool,
+    /// Mean and standard deviation of the linear luminance of each input cell in raster
+    /// order, after toning, empty without `max_adjust`
+    pub(crate) cell_luminance: Vec<(f32, f32)>,
+    /// Colour transparent thumbnails are composited over, instead of dropping their alpha
+    pub(crate) alpha_background: Option<[u8; 3]>,
+    /// How tiles' colour casts are evened out, the same way they were when sampled
+    pub(crate) white_balance: WhiteBalance,
+    /// Shadow and highlight colours tiles are mapped to by luminance, keeping their own
+    /// colours if `None`
+    pub(crate) tone: Option<[[u8; 3]; 2]>,
+    /// The input at its cropped size, before it's blurred or toned for matching, that
+    /// fallback cells are filled from. `None` places their thumbnails instead
+    pub(crate) original: Option<RgbImage>,
+    /// Cell width and height in `original`
+    pub(crate) cell_size: (u32, u32),
+    /// Pixels each tile extends past its cell on every side, feathered into its
+    /// neighbours. 0 draws tiles edge to edge
+    pub(crate) overlap: u32,
+    /// Width and colour of the line drawn round the inside of each tile, after any
+    /// feathering. A width of 0 draws none
+    pub(crate) border: (u32, [u8; 3]),
+    /// Linear RGB scaled by weight, and the weight, summed over the feathered tiles
+    /// covering each pixel of `image` in units of `1 / FEATHER_SCALE`. Integer sums come
+    /// out the same whatever order tiles are placed in. Empty without `overlap`
+    pub(crate) feathered: Vec<[u32; 4]>,
+    pub(crate) thumbs_cache: TileCache,
+    pub(crate) thumbs_store: ThumbnailStore,
+}
+
+impl Canvas {
+    /// Place `cell`'s thumbnail, or the input itself if it's a fallback cell
+    pub(crate) fn place_cell(&mut self, cell: &Assignment) -> Result<(), Error> {
+        match cell.fallback && self.original.is_some() {
+            true => self.place_original(cell.x, cell.y),
+            false => self.place(&cell.path, cell.x, cell.y, cell.flip)?,
+        }
+        // Feathered tiles only reach `image` on `finish`, which draws their borders then
+        if self.overlap == 0 {
+            self.draw_border(cell.x, cell.y);
+        }
+
+        Ok(())
+    }
+
+    /// Resize a thumbnail to `tile_size` (cached by path) and draw it into the grid cell at
+    /// `x`, `y`, mirrored as `flip` says. With `overlap` the tile is feathered into
+    /// `feathered` instead, and only reaches `image` on `finish`
+    pub(crate) fn place(&mut self, path: &str, x: u32, y: u32, flip: Flip) -> Result<(), Error> {
+        let best_image = match self.thumbs_cache.get(path) {
+            Some(image) => image.to_rgb8(),
+            None => {
+                let image = self.load_tile(path)?;
+                let rgb = image.to_rgb8();
+                self.thumbs_cache.insert(path.to_string(), image);
+                rgb
+            }
+        };
+        let mut best_image = match flip {
+            Flip::None => best_image,
+            flip => flip.apply(&best_image),
+        };
+
+        let (px, py) = (
+            x * self.tile_size.0,
+            (y - self.first_row) * self.tile_size.1,
+        );
+        let cell = (y * self.columns + x) as usize;
+
+        if self.overlap > 0 {
+            let region = (0, 0, best_image.width(), best_image.height());
+            self.adjust(&mut best_image, cell, region);
+            self.feather(&best_image, px, py);
+            return Ok(());
+        }
+
+        image::imageops::overlay(&mut self.image, &best_image, px as i64, py as i64);
+
+        // Edge cells overhang the canvas, so only adjust the part that was drawn
+        let width = self.tile_size.0.min(self.image.width() - px);
+        let height = self.tile_size.1.min(self.image.height() - py);
+        let mut image = std::mem::take(&mut self.image);
+        self.adjust(&mut image, cell, (px, py, width, height));
+        self.image = image;
+
+        Ok(())
+    }
+
+    /// Draw the input's own cell at `x`, `y`, scaled up to the tile size. With `overlap`
+    /// it takes in enough of the input around the cell to feather like a tile does
+    fn place_original(&mut self, x: u32, y: u32) {
+        let Some(original) = self.original.as_ref() else {
+            return;
+        };
+        let (cell_width, cell_height) = self.cell_size;
+        let dpr = self.tile_size.0 / cell_width;
+        let margin = self.overlap.div_ceil(dpr);
+
+        // Edge cells and the margin reach past the input, where its edge pixels repeat
+        let (left, top) = (
+            (x * cell_width) as i64 - margin as i64,
+            (y * cell_height) as i64 - margin as i64,
+        );
+        let (width, height) = original.dimensions();
+        let region =
+            RgbImage::from_fn(cell_width + 2 * margin, cell_height + 2 * margin, |i, j| {
+                let sx = (left + i as i64).clamp(0, width as i64 - 1) as u32;
+                let sy = (top + j as i64).clamp(0, height as i64 - 1) as u32;
+                *original.get_pixel(sx, sy)
+            });
+        let scaled = image::imageops::resize(
+            &region,
+            region.width() * dpr,
+            region.height() * dpr,
+            self.filter.into(),
+        );
+        let trim = margin * dpr - self.overlap;
+        let tile = image::imageops::crop_imm(
+            &scaled,
+            trim,
+            trim,
+            self.tile_size.0 + 2 * self.overlap,
+            self.tile_size.1 + 2 * self.overlap,
+        )
+        .to_image();
+
+        let (px, py) = (
+            x * self.tile_size.0,
+            (y - self.first_row) * self.tile_size.1,
+        );
+        match self.overlap {
+            0 => image::imageops::overlay(&mut self.image, &tile, px as i64, py as i64),
+            _ => self.feather(&tile, px, py),
+        }
+    }
+
+    /// Apply `max_adjust` and `blend` for `cell` to the tile drawn in `region` of `image`
+    fn adjust(&self, image: &mut RgbImage, cell: usize, region: (u32, u32, u32, u32)) {
+        if let Some(max_adjust) = self.max_adjust {
+            self.match_luminance(image, self.cell_luminance[cell], region, max_adjust);
+        }
+
+        if self.blend > 0.0 {
+            let target = self.cell_colors[cell];
+            let (px, py, width, height) = region;
+
+            for ty in py..py + height {
+                for tx in px..px + width {
+                    let pixel = image.get_pixel_mut(tx, ty);
+                    for c in 0..3 {
+                        let linear = srgb_to_linear(pixel[c]);
+                        pixel[c] = linear_to_srgb(linear + (target[c] - linear) * self.blend);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add `tile`, which overhangs the cell at `px`, `py` by `overlap` on every side, to
+    /// `feathered` in linear light. Its weight ramps up from the tile's edges over twice
+    /// the overlap, so where neighbours overlap their weights sum to one
+    fn feather(&mut self, tile: &RgbImage, px: u32, py: u32) {
+        let linear: [f32; 256] = std::array::from_fn(|c| srgb_to_linear(c as u8));
+        let ramp = |i: u32, len: u32| {
+            let edge = i.min(len - 1 - i) as f32 + 0.5;
+            (edge / (2 * self.overlap) as f32).min(1.0)
+        };
+        let (width, height) = self.image.dimensions();
+        let (left, top) = (
+            px as i64 - self.overlap as i64,
+            py as i64 - self.overlap as i64,
+        );
+
+        for (tx, ty, pixel) in tile.enumerate_pixels() {
+            let (cx, cy) = (left + tx as i64, top + ty as i64);
+            if cx < 0 || cy < 0 || cx >= width as i64 || cy >= height as i64 {
+                continue;
+            }
+            let weight = ramp(tx, tile.width()) * ramp(ty, tile.height());
+            let sum = &mut self.feathered[(cy as u64 * width as u64 + cx as u64) as usize];
+            for c in 0..3 {
+                sum[c] += (linear[pixel[c] as usize] * weight * FEATHER_SCALE).round() as u32;
+            }
+            sum[3] += (weight * FEATHER_SCALE).round() as u32;
+        }
+    }
+
+    /// Resolve the tiles feathered in with `overlap` into `image`. Does nothing without
+    /// overlap
+    pub(crate) fn finish(&mut self) {
+        let feathered = std::mem::take(&mut self.feathered);
+        if feathered.is_empty() {
+            return;
+        }
+
+        for (pixel, sum) in self.image.pixels_mut().zip(feathered) {
+            if sum[3] > 0 {
+                pixel.0 = std::array::from_fn(|c| linear_to_srgb(sum[c] as f32 / sum[3] as f32));
+            }
+        }
+
+        let columns = self.image.width().div_ceil(self.tile_size.0);
+        let rows = self.image.height().div_ceil(self.tile_size.1);
+        for y in self.first_row..self.first_row + rows {
+            for x in 0..columns {
+                self.draw_border(x, y);
+            }
+        }
+    }
+
+    /// Draw `border` round the inside of the cell at `x`, `y`, clipped to `image` like
+    /// an edge cell's tile is, so the outermost lines frame the output
+    fn draw_border(&mut self, x: u32, y: u32) {
+        let (width, color) = self.border;
+        if width == 0 {
+            return;
+        }
+
+        let (px, py) = (
+            x * self.tile_size.0,
+            (y - self.first_row) * self.tile_size.1,
+        );
+        let right = (px + self.tile_size.0).min(self.image.width());
+        let bottom = (py + self.tile_size.1).min(self.image.height());
+        for ty in py..bottom {
+            for tx in px..right {
+                let inside = tx - px >= width
+                    && right - 1 - tx >= width
+                    && ty - py >= width
+                    && bottom - 1 - ty >= width;
+                if !inside {
+                    self.image.put_pixel(tx, ty, image::Rgb(color));
+                }
+            }
+        }
+    }
+
+    /// Scale the colours of `image` in `region` so their mean luminance, and their standard
+    /// deviation with `match_contrast`, become `target`'s, each by a factor of at most
+    /// `max_adjust` either way so tiles aren't distorted beyond recognition
+    fn match_luminance(
+        &self,
+        image: &mut RgbImage,
+        (target_mean, target_spread): (f32, f32),
+        (px, py, width, height): (u32, u32, u32, u32),
+        max_adjust: f32,
+    ) {
+        let linear: [f32; 256] = std::array::from_fn(|c| srgb_to_linear(c as u8));
+        let mut luma = Vec::with_capacity((width * height) as usize);
+        for ty in py..py + height {
+            for tx in px..px + width {
+                let pixel = image.get_pixel(tx, ty).0;
+                luma.push(luminance(pixel.map(|c| linear[c as usize])));
+            }
+        }
+
+        let (mean, spread) = mean_deviation(&luma);
+        let bound = |factor: f32| factor.clamp(1.0 / max_adjust, max_adjust);
+        let gain = match mean > 0.0 {
+            true => bound(target_mean / mean),
+            false => 1.0,
+        };
+        let contrast = match self.match_contrast && spread > 0.0 {
+            true => bound(target_spread / spread),
+            false => 1.0,
+        };
+
+        let mut luma = luma.into_iter();
+        for ty in py..py + height {
+            for tx in px..px + width {
+                let y = luma.next().unwrap_or_default();
+                let adjusted = (mean * gain + (y - mean) * contrast).max(0.0);
+                let pixel = image.get_pixel_mut(tx, ty);
+                // Scaling keeps each pixel's chromaticity, but black has none to keep
+                pixel.0 = match y > 0.0 {
+                    true => pixel
+                        .0
+                        .map(|c| linear_to_srgb(linear[c as usize] * adjusted / y)),
+                    false => [linear_to_srgb(adjusted); 3],
+                };
+            }
+        }
+    }
+
+    /// Load a thumbnail and resize it to `tile_size`, plus `overlap` on every side
+    fn load_tile(&self, path: &str) -> Result<DynamicImage, Error> {
+        let mut image = self.thumbs_store.load(path)?;
+        if let Some(background) = self.alpha_background {
+            image = flatten_alpha(image, background);
+        }
+        image = white_balance(image, self.white_balance);
+        if let Some(tone) = self.tone {
+            image = DynamicImage::ImageRgb8(tone_image(&image, tone));
+        }
+        let (tile_width, tile_height) = (
+            self.tile_size.0 + 2 * self.overlap,
+            self.tile_size.1 + 2 * self.overlap,
+        );
+
+        Ok(if self.native_tiles {
+            if image.width() < tile_width || image.height() < tile_height {
+                log::warn!(
+                    "{} is smaller than the {}x{}px tile and will be upscaled",
+                    path,
+                    tile_width,
+                    tile_height
+                );
+            }
+            image.resize_to_fill(
+                tile_width,
+                tile_height,
+                image::imageops::FilterType::Lanczos3,
+            )
+        } else {
+            image.resize_exact(tile_width, tile_height, self.filter.into())
+        })
+    }
+}
+
+/// Resized tiles by path. With a capacity, the least recently placed tile is dropped
+/// to make room for a new one
+#[derive(Default)]
+pub(crate) struct TileCache {
+    capacity: Option<usize>,
+    tiles: HashMap<String, (DynamicImage, u64)>,
+    /// Incremented on every lookup, so a tile's stamp says when it was last placed
+    clock: u64,
+}
+
+impl TileCache {
+    pub(crate) fn new(capacity: Option<usize>) -> Self {
+        TileCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, path: &str) -> Option<&DynamicImage> {
+        self.clock += 1;
+        let (image, last_used) = self.tiles.get_mut(path)?;
+        *last_used = self.clock;
+
+        Some(image)
+    }
+
+    fn insert(&mut self, path: String, image: DynamicImage) {
+        if self.capacity == Some(0) {
+            return;
+        }
+        if let Some(capacity) = self.capacity
+            && self.tiles.len() >= capacity
+            && let Some(oldest) = self
+                .tiles
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(path, _)| path.clone())
+        {
+            self.tiles.remove(&oldest);
+        }
+
+        self.tiles.insert(path, (image, self.clock));
+    }
+}
+
+/// Average colour of `image` in linear RGB, so blending toward it doesn't darken
+pub(crate) fn mean_linear(image: &RgbImage) -> [f32; 3] {
+    let mut sum = [0f32; 3];
+    for pixel in image.pixels() {
+        for c in 0..3 {
+            sum[c] += srgb_to_linear(pixel[c]);
+        }
+    }
+
+    let n = (image.width() * image.height()).max(1) as f32;
+    sum.map(|c| c / n)
+}
+
+/// `colors`, the linear RGB of each cell in raster order `columns` to a row, painted as
+/// solid `tile_size` blocks over an image of `size`. Blocks at the edges are clipped
+pub(crate) fn flat_image(
+    colors: &[[f32; 3]],
+    columns: u32,
+    tile_size: (u32, u32),
+    size: (u32, u32),
+) -> RgbImage {
+    let colors: Vec<Rgb<u8>> = colors.iter().map(|c| Rgb(c.map(linear_to_srgb))).collect();
+    RgbImage::from_fn(size.0, size.1, |x, y| {
+        colors[(y / tile_size.1 * columns + x / tile_size.0) as usize]
+    })
+}
+
+/// Relative luminance of a linear RGB colour, with the Rec. 709 weights of sRGB's primaries
+fn luminance(c: [f32; 3]) -> f32 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
+
+/// Black and white, the tone that turns colours into their grey
+pub(crate) const GREYSCALE: [[u8; 3]; 2] = [[0; 3], [255; 3]];
+
+/// A linear RGB colour's luminance mapped onto the ramp from `shadows` to `highlights`,
+/// mixed in linear light, so `GREYSCALE` gives the colour's grey
+pub(crate) fn tone_linear(c: [f32; 3], tone: [[u8; 3]; 2]) -> [f32; 3] {
+    ramp(luminance(c), tone.map(|end| end.map(srgb_to_linear)))
+}
+
+/// `image` with every pixel mapped through `tone_linear`
+pub(crate) fn tone_image(image: &DynamicImage, tone: [[u8; 3]; 2]) -> RgbImage {
+    let linear: [f32; 256] = std::array::from_fn(|c| srgb_to_linear(c as u8));
+    let ends = tone.map(|end| end.map(srgb_to_linear));
+    let mut rgb = image.to_rgb8();
+    for pixel in rgb.pixels_mut() {
+        let y = luminance(pixel.0.map(|c| linear[c as usize]));
+        pixel.0 = ramp(y, ends).map(linear_to_srgb);
+    }
+
+    rgb
+}
+
+/// Mean and standard deviation of the linear luminance of `image`, with its colours
+/// mapped through `tone` first if there is one
+pub(crate) fn luminance_stats(image: &RgbImage, tone: Option<[[u8; 3]; 2]>) -> (f32, f32) {
+    let linear: [f32; 256] = std::array::from_fn(|c| srgb_to_linear(c as u8));
+    let luma: Vec<f32> = image
+        .pixels()
+        .map(|pixel| luminance(pixel.0.map(|c| linear[c as usize])))
+        .collect();
+    let (mean, spread) = mean_deviation(&luma);
+
+    // Toning maps luminance linearly onto the ramp between the two ends
+    match tone {
+        Some(tone) => {
+            let [shadows, highlights] = tone.map(|end| luminance(end.map(srgb_to_linear)));
+            (
+                shadows + (highlights - shadows) * mean,
+                (highlights - shadows).abs() * spread,
+            )
+        }
+        None => (mean, spread),
+    }
+}
+
+fn mean_deviation(values: &[f32]) -> (f32, f32) {
+    let n = values.len().max(1) as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+
+    (mean, variance.sqrt())
+}
+
+/// The grey with the same luminance as an sRGB colour
+pub(crate) fn grey(c: [u8; 3]) -> [u8; 3] {
+    tone_linear(c.map(srgb_to_linear), GREYSCALE).map(linear_to_srgb)
+}
+
+/// The linear colour `y` of the way from `shadows` to `highlights`
+fn ramp(y: f32, [shadows, highlights]: [[f32; 3]; 2]) -> [f32; 3] {
+    std::array::from_fn(|i| shadows[i] + (highlights[i] - shadows[i]) * y)
+}
+
+pub(crate) fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub(crate) fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+
+    (c * 255.0).round() as u8
+}
+
+/// How an SVG mosaic refers to the thumbnail files it places
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum SvgLinks {
+    /// By absolute path
+    #[default]
+    Absolute,
+    /// By path relative to this directory, such as the one the SVG is served from
+    RelativeTo(PathBuf),
+    /// Each file embedded once as a data URI, so the SVG works on its own
+    Embedded,
+}
+
+/// Formats browsers show in an SVG `<image>`, which are embedded as they are. Anything
+/// else is converted to PNG first
+const WEB_FORMATS: [ImageFormat; 7] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Avif,
+    ImageFormat::Bmp,
+    ImageFormat::Ico,
+];
+
+/// An SVG document placing each assigned thumbnail file at its cell, referred to as
+/// `links` says
+pub(crate) fn mosaic_svg(
+    (width, height): (u32, u32),
+    (tile_width, tile_height): (u32, u32),
+    native_tiles: bool,
+    assignments: &[Assignment],
+    links: &SvgLinks,
+) -> Result<String, Error> {
+    // Match how tiles are fitted into the raster output
+    let aspect = if native_tiles {
+        "xMidYMid slice"
+    } else {
+        "none"
+    };
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\n"
+    );
+
+    let mut cells = Vec::from_iter(assignments);
+    cells.sort_by_key(|a| (a.y, a.x));
+
+    let base = match links {
+        SvgLinks::RelativeTo(base) => {
+            Some(fs::canonicalize(base).map_err(|e| Error::Io(base.clone(), e))?)
+        }
+        _ => None,
+    };
+    // Embedded thumbnails are defined once each, in the order they're first placed, and
+    // every cell showing one refers to its definition
+    let mut embedded: HashMap<&str, usize> = HashMap::new();
+    if *links == SvgLinks::Embedded {
+        svg += "  <defs>\n";
+        for cell in &cells {
+            let next = embedded.len();
+            if !embedded.contains_key(cell.path.as_str()) {
+                embedded.insert(&cell.path, next);
+                svg += &format!(
+                    "    <image id=\"t{next}\" href=\"{}\" width=\"{tile_width}\" height=\"{tile_height}\" preserveAspectRatio=\"{aspect}\"/>\n",
+                    data_uri(Path::new(&cell.path))?,
+                );
+            }
+        }
+        svg += "  </defs>\n";
+    }
+
+    for cell in cells {
+        let (x, y) = (cell.x * tile_width, cell.y * tile_height);
+        // Mirror or turn about the tile's own centre. Only square tiles are turned, so
+        // the diagonals can use the width alone
+        let (sum, diff) = (x + y + tile_width, x as i64 - y as i64);
+        let transform = match cell.flip {
+            Flip::None => String::new(),
+            Flip::Horizontal => format!(" transform=\"matrix(-1 0 0 1 {} 0)\"", 2 * x + tile_width),
+            Flip::Vertical => format!(" transform=\"matrix(1 0 0 -1 0 {})\"", 2 * y + tile_height),
+            Flip::Both => format!(
+                " transform=\"matrix(-1 0 0 -1 {} {})\"",
+                2 * x + tile_width,
+                2 * y + tile_height
+            ),
+            Flip::Rotate90 => format!(" transform=\"matrix(0 1 -1 0 {} {})\"", sum, -diff),
+            Flip::Rotate270 => format!(" transform=\"matrix(0 -1 1 0 {} {})\"", diff, sum),
+            Flip::Transpose => format!(" transform=\"matrix(0 1 1 0 {} {})\"", diff, -diff),
+            Flip::Transverse => format!(" transform=\"matrix(0 -1 -1 0 {} {})\"", sum, sum),
+        };
+
+        // A `use` places its definition at x, y before the transform, just like an
+        // `image` drawn there
+        if let Some(id) = embedded.get(cell.path.as_str()) {
+            svg += &format!("  <use href=\"#t{id}\" x=\"{x}\" y=\"{y}\"{transform}/>\n");
+            continue;
+        }
+
+        let absolute = fs::canonicalize(&cell.path);
+        let href = match (&absolute, &base) {
+            (Ok(path), Some(base)) => relative_path(path, base).to_string_lossy().into_owned(),
+            (Ok(path), None) => path.to_string_lossy().into_owned(),
+            (Err(_), _) => cell.path.clone(),
+        };
+        svg += &format!(
+            "  <image href=\"{}\" x=\"{x}\" y=\"{y}\" width=\"{tile_width}\" height=\"{tile_height}\" preserveAspectRatio=\"{aspect}\"{transform}/>\n",
+            xml_escape(&href),
+        );
+    }
+
+    Ok(svg + "</svg>\n")
+}
+
+/// `path` relative to the directory `base`, both absolute
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let path: Vec<_> = path.components().collect();
+    let base: Vec<_> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    relative.extend(&path[common..]);
+
+    relative
+}
+
+/// The image file at `path` as a base64 data URI, converted to PNG unless browsers can
+/// show it as it is
+fn data_uri(path: &Path) -> Result<String, Error> {
+    let bytes = fs::read(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    let format = image::guess_format(&bytes).ok();
+    if let Some(format) = format.filter(|format| WEB_FORMATS.contains(format)) {
+        return Ok(format!(
+            "data:{};base64,{}",
+            format.to_mime_type(),
+            base64(&bytes)
+        ));
+    }
+
+    let image_error = |e| Error::Image(path.display().to_string(), e);
+    let image = image::load_from_memory(&bytes).map_err(image_error)?;
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, ImageFormat::Png)
+        .map_err(image_error)?;
+
+    Ok(format!("data:image/png;base64,{}", base64(png.get_ref())))
+}
+
+/// Standard padded base64 of `bytes`
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let byte = |i: usize| chunk.get(i).copied().unwrap_or(0) as u32;
+        let group = (byte(0) << 16) | (byte(1) << 8) | byte(2);
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+
+    encoded
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
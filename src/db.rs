@@ -0,0 +1,1088 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use image::{DynamicImage, imageops::FilterType};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, Profile,
+    matching::{cielab_colors, hsv_colors, mean_lab, oklab_colors},
+    progress::Progress,
+    sample::{
+        ResizeFilter, SampleStat, WhiteBalance, decode_image, flatten_alpha, load_image,
+        resample_colors, sample_image, structure, white_balance,
+    },
+};
+
+/// Colour features of one thumbnail, sampled at one resolution
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ThumbnailData {
+    pub path: String,
+    pub res: u32,
+    #[serde(default)]
+    pub stat: SampleStat,
+    /// Filter `colors` were resampled with
+    #[serde(default)]
+    pub filter: ResizeFilter,
+    pub colors: Vec<[u8; 3]>,
+    /// `colors` converted to Oklab at import. Scaled by `Config::lab_weights` once
+    /// locked into the matching pool
+    #[serde(default)]
+    pub oklab: Vec<[f32; 3]>,
+    /// `colors` converted to CIELAB at import, for CIEDE2000 matching
+    #[serde(default)]
+    pub cielab: Vec<[f32; 3]>,
+    /// `colors` converted to HSV at import, for HSV matching
+    #[serde(default)]
+    pub hsv: Vec<[f32; 3]>,
+    /// Modification time of the file when it was imported, in nanoseconds since the
+    /// Unix epoch. Entries without one are re-imported on the next run
+    #[serde(default)]
+    pub mtime: Option<u64>,
+    /// Difference hash of the whole image, for `--dedupe`. Missing in entries imported
+    /// before it was recorded
+    #[serde(default)]
+    pub dhash: Option<u64>,
+    /// Whether the image has an alpha channel. Unknown for entries imported before it was
+    /// recorded
+    #[serde(default)]
+    pub alpha: Option<bool>,
+    /// Colour the image's transparency was composited over before sampling, `None` if its
+    /// alpha was dropped or it has none
+    #[serde(default)]
+    pub background: Option<[u8; 3]>,
+    /// Luma contrast and average horizontal and vertical gradient of the whole image, for
+    /// `--structure-weight`. Missing in entries imported before it was recorded
+    #[serde(default)]
+    pub structure: Option<[f32; 3]>,
+    /// Whether the file's EXIF orientation turns or mirrors it. Unknown for entries imported
+    /// before it was recorded
+    #[serde(default)]
+    pub turned: Option<bool>,
+    /// Whether that orientation was applied before sampling, `false` if it was ignored or
+    /// there is none
+    #[serde(default)]
+    pub exif_applied: bool,
+    /// How the image's colour cast was evened out before sampling
+    #[serde(default)]
+    pub white_balance: WhiteBalance,
+    /// Average of `oklab`, all `DifferenceFunction::AverageColor` compares. Filled in on
+    /// load for entries imported before it was recorded
+    #[serde(default)]
+    pub mean_oklab: Option<[f32; 3]>,
+}
+
+/// Sample resolution, statistic, resize filter, alpha background and white balance a band
+/// is sampled with
+type Sampling = (u32, SampleStat, ResizeFilter, Option<[u8; 3]>, WhiteBalance);
+
+impl ThumbnailData {
+    /// The cached Oklab, CIELAB, HSV and average colours, hash, alpha and orientation flags,
+    /// structure and mtime describe `colors` rather than add to it, so they're left out of
+    /// comparisons
+    fn key(&self) -> (&str, Sampling, bool, &[[u8; 3]]) {
+        (
+            &self.path,
+            (
+                self.res,
+                self.stat,
+                self.filter,
+                self.background,
+                self.white_balance,
+            ),
+            self.exif_applied,
+            &self.colors,
+        )
+    }
+
+    /// Whether `colors` are what sampling with transparency composited over `background`
+    /// gives, which only depends on it for images with an alpha channel
+    fn fits_background(&self, background: Option<[u8; 3]>) -> bool {
+        match self.alpha {
+            Some(true) => self.background == background,
+            Some(false) => true,
+            // Entries this old always dropped alpha
+            None => background.is_none(),
+        }
+    }
+
+    /// Whether `colors` are what sampling the file turned upright, or as stored with
+    /// `ignore_exif`, gives, which only depends on it for files whose orientation turns them
+    fn fits_orientation(&self, ignore_exif: bool) -> bool {
+        match self.turned {
+            Some(true) => self.exif_applied != ignore_exif,
+            Some(false) => true,
+            // Entries this old were always sampled as stored
+            None => ignore_exif,
+        }
+    }
+}
+
+impl PartialEq for ThumbnailData {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for ThumbnailData {}
+
+impl PartialOrd for ThumbnailData {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ThumbnailData {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// How `thumbdata` is encoded on disk. Either one is read back, whichever was written
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum DbFormat {
+    /// Human readable, but large and slow to parse for big collections
+    Ron,
+    /// Compact binary, several times smaller and faster to load
+    Bincode,
+}
+
+/// Start of a bincode database, which can't be mistaken for the start of RON. Bincode
+/// isn't self-describing, so the last byte is the version of the layout
+const BINCODE_MAGIC: &[u8] = b"\0imagegrid-db\x08";
+
+/// Every indexed thumbnail band, as stored in `thumbdata`
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct ThumbnailDb {
+    /// Ordered by path so indexing the same thumbnails always writes the same bytes
+    pub thumbs: BTreeSet<ThumbnailData>,
+    /// Thumbnails left out by `--dedupe`, each mapped to the indexed thumbnail it
+    /// duplicates
+    #[serde(default)]
+    pub duplicates: BTreeMap<String, String>,
+}
+
+/// Decoded thumbnails registered from memory, keyed by identifier. Any identifier
+/// not registered here is treated as a path and read from disk
+#[derive(Default)]
+pub struct ThumbnailStore {
+    images: HashMap<String, DynamicImage>,
+    /// Decode thumbnails as stored rather than turned upright, as in `Config`
+    pub(crate) ignore_exif: bool,
+}
+
+impl ThumbnailStore {
+    /// An empty store that decodes thumbnails as `Config::ignore_exif` says
+    pub fn new(ignore_exif: bool) -> Self {
+        ThumbnailStore {
+            images: HashMap::new(),
+            ignore_exif,
+        }
+    }
+
+    /// Register an already decoded thumbnail under `id` and add its features to
+    /// `thumbs_db`. `sampling` is the resolution `Config::index_res` gives, the sample
+    /// stat, the filter, the colour transparency is composited over and the white balance,
+    /// as in `Config`
+    pub fn register_image(
+        &mut self,
+        id: &str,
+        image: DynamicImage,
+        sampling: (u32, SampleStat, ResizeFilter, Option<[u8; 3]>, WhiteBalance),
+        thumbs_db: &mut ThumbnailDb,
+    ) {
+        thumbs_db
+            .thumbs
+            .insert(thumb_data(id, &image, sampling, (false, false), None));
+        self.images.insert(id.to_string(), image);
+    }
+
+    /// Decode an encoded thumbnail held in memory, turned upright unless the store ignores
+    /// EXIF, and register it under `id`
+    pub fn register_bytes(
+        &mut self,
+        id: &str,
+        bytes: &[u8],
+        sampling: (u32, SampleStat, ResizeFilter, Option<[u8; 3]>, WhiteBalance),
+        thumbs_db: &mut ThumbnailDb,
+    ) -> Result<(), Error> {
+        let (image, turned) =
+            decode_image(bytes, self.ignore_exif).map_err(|e| Error::Image(id.to_string(), e))?;
+        let exif = (turned, !self.ignore_exif);
+        thumbs_db
+            .thumbs
+            .insert(thumb_data(id, &image, sampling, exif, None));
+        self.images.insert(id.to_string(), image);
+
+        Ok(())
+    }
+
+    pub(crate) fn load(&self, id: &str) -> Result<DynamicImage, Error> {
+        match self.images.get(id) {
+            Some(image) => Ok(image.clone()),
+            None => load_image(id, self.ignore_exif).map(|(image, _)| image),
+        }
+    }
+}
+
+/// Load the thumbnail database and import any new or modified thumbnails matching the
+/// `thumbs` globs at `sampleres`, and from the same decode at each of `also_res`, so
+/// switching between those resolutions needs no new import. A file counts as imported
+/// once it has a band at `sampleres`. Entries whose files are gone are dropped. Thumbnails that
+/// can't be read or fall outside the `min_dimension` and `max_dimension` limits are
+/// reported and left out, as are near duplicates of another thumbnail with `dedupe`, the
+/// most bits their hashes may differ in. `force_reimport` ignores the cached entries and
+/// checks for duplicates again. With `structure`, entries imported before structure was
+/// recorded are imported again. Files are turned upright as their EXIF orientation says
+/// before sampling unless `ignore_exif`, and their colour cast evened out as `balance`
+/// says. The database lives at `db_path`, or `thumb_data_path()` if that's `None`
+pub fn index_thumbs(
+    thumbs: &[String],
+    (db_path, db_format): (Option<&Path>, Option<DbFormat>),
+    ((sampleres, stat, filter, background, balance), also_res, ignore_exif): (
+        Sampling,
+        &[u32],
+        bool,
+    ),
+    (min_dimension, max_dimension, dedupe, structure): (
+        Option<u32>,
+        Option<u32>,
+        Option<u32>,
+        bool,
+    ),
+    force_reimport: bool,
+    threads: Option<usize>,
+    profile: &mut Profile,
+) -> Result<ThumbnailDb, Error> {
+    let phase_start = Instant::now();
+    let thumb_data_path = match db_path {
+        Some(path) => path.to_path_buf(),
+        None => thumb_data_path()?,
+    };
+    let (mut thumbs_db, loaded_format) = read_thumbs_db(&thumb_data_path)?;
+    // Keep the format already on disk unless asked to convert
+    let db_format = db_format.unwrap_or(loaded_format);
+    profile.record("db load", phase_start);
+
+    log::info!(
+        "Loaded data for {} thumbs from {:?}!",
+        &thumbs_db.thumbs.len(),
+        &thumb_data_path
+    );
+
+    // Databases written before Oklab, CIELAB, HSV and average colours were cached get them
+    // filled in once
+    let stale = thumbs_db
+        .thumbs
+        .iter()
+        .filter(|thumb| {
+            thumb.oklab.len() != thumb.colors.len()
+                || thumb.cielab.len() != thumb.colors.len()
+                || thumb.hsv.len() != thumb.colors.len()
+                || thumb.mean_oklab.is_none()
+        })
+        .count();
+    if stale > 0 {
+        thumbs_db.thumbs = std::mem::take(&mut thumbs_db.thumbs)
+            .into_iter()
+            .map(|mut thumb| {
+                thumb.oklab = oklab_colors(&thumb.colors);
+                thumb.cielab = cielab_colors(&thumb.colors);
+                thumb.hsv = hsv_colors(&thumb.colors);
+                thumb.mean_oklab = Some(mean_lab(&thumb.oklab));
+                thumb
+            })
+            .collect();
+        log::info!(
+            "Cached Oklab, CIELAB, HSV and average colours for {} thumbs",
+            stale
+        );
+    }
+
+    // Entries are per band, so count files rather than entries
+    let mut removed = HashSet::new();
+    thumbs_db.thumbs.retain(|thumb| {
+        let exists = Path::new(&thumb.path).exists();
+        if !exists {
+            removed.insert(thumb.path.clone());
+        }
+        exists
+    });
+    let pruned = removed.len();
+    if pruned > 0 {
+        log::info!("Removed {} thumbnails no longer present", pruned);
+    }
+
+    // Duplicates are looked for again once either side is gone, or on request
+    let duplicates = thumbs_db.duplicates.len();
+    if force_reimport {
+        thumbs_db.duplicates.clear();
+    }
+    thumbs_db
+        .duplicates
+        .retain(|duplicate, kept| Path::new(duplicate).exists() && Path::new(kept).exists());
+    let mut dedupe_changes = duplicates - thumbs_db.duplicates.len();
+
+    let phase_start = Instant::now();
+    let mut dirty_thumbs_db = 0u32;
+    // Skipped files stay out of the database, so they're retried on the next run
+    let mut skipped = 0u32;
+    let mut changed = 0u32;
+    // Files outside the size limits, and how many of them had been indexed before
+    let mut sized_out = 0u32;
+    let mut unindexed = 0usize;
+    // Duplicates left out on an earlier run
+    let mut known_duplicates = 0u32;
+    let mut pending = Vec::new();
+
+    // Overlapping patterns can reach the same file
+    let mut seen = HashSet::new();
+    let thumb_entries = thumbs
+        .iter()
+        .map(|pattern| glob::glob(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Only the paths are collected, sorted so thumbnails are checked and imported in the
+    // same order whatever order the filesystem lists them in
+    let mut thumb_paths = Vec::new();
+    for thumb_entry in thumb_entries.into_iter().flatten() {
+        match thumb_entry {
+            Ok(thumb_entry) => thumb_paths.push(thumb_entry),
+            Err(e) => {
+                log::warn!("skipping {:?}: {}", e.path(), e.error());
+                skipped += 1;
+            }
+        }
+    }
+    thumb_paths.sort();
+
+    for thumb_entry in thumb_paths {
+        // Paths are stored as strings, so non UTF-8 names can't be indexed
+        let Some(entry_path) = thumb_entry.to_str().map(String::from) else {
+            log::warn!("skipping {:?}: path isn't valid UTF-8", thumb_entry);
+            skipped += 1;
+            continue;
+        };
+
+        if !seen.insert(fs::canonicalize(&thumb_entry).unwrap_or_else(|_| thumb_entry.clone())) {
+            continue;
+        }
+
+        if thumbs_db.duplicates.contains_key(&entry_path) {
+            if dedupe.is_some() {
+                known_duplicates += 1;
+                continue;
+            }
+            // Not indexed, so it's imported below like any new file
+            thumbs_db.duplicates.remove(&entry_path);
+            dedupe_changes += 1;
+        }
+
+        // Only the header is read, so this is cheap enough to check indexed files too
+        if (min_dimension.is_some() || max_dimension.is_some())
+            && let Ok((width, height)) = image::image_dimensions(&thumb_entry)
+            && (min_dimension.is_some_and(|min| width.min(height) < min)
+                || max_dimension.is_some_and(|max| width.max(height) > max))
+        {
+            log::info!(
+                "Skipping {}: {}x{} is outside the size limits",
+                entry_path,
+                width,
+                height
+            );
+            let before = thumbs_db.thumbs.len();
+            thumbs_db.thumbs.retain(|a| a.path != entry_path);
+            unindexed += before - thumbs_db.thumbs.len();
+            sized_out += 1;
+            continue;
+        }
+
+        let mtime = file_mtime(&thumb_entry);
+        let cached = thumbs_db.thumbs.iter().filter(|a| a.path == entry_path);
+        // Every band of a file is dropped once it changes, not just the one being imported
+        let modified =
+            force_reimport || cached.clone().any(|a| a.mtime.is_none() || a.mtime < mtime);
+        // Hashes need the whole image, so entries from before they were recorded are
+        // imported again
+        let unhashed = dedupe.is_some() && cached.clone().any(|a| a.dhash.is_none());
+        // Likewise for whether they have any transparency to composite
+        let unflattened = background.is_some() && cached.clone().any(|a| a.alpha.is_none());
+        let unstructured = structure && cached.clone().any(|a| a.structure.is_none());
+        // And for whether their EXIF orientation turns them
+        let unoriented = !ignore_exif && cached.clone().any(|a| a.turned.is_none());
+
+        if modified
+            || unhashed
+            || unflattened
+            || unstructured
+            || unoriented
+            || cached.clone().all(|a| {
+                (a.res != sampleres)
+                    || (a.stat != stat)
+                    || (a.filter != filter)
+                    || (a.white_balance != balance)
+                    || !a.fits_background(background)
+                    || !a.fits_orientation(ignore_exif)
+            })
+        {
+            if (modified || unhashed || unflattened || unstructured || unoriented)
+                && cached.clone().next().is_some()
+            {
+                thumbs_db.thumbs.retain(|a| a.path != entry_path);
+                changed += modified as u32;
+            }
+            pending.push((entry_path, mtime));
+        }
+    }
+
+    if changed > 0 && !force_reimport {
+        log::info!("Re-importing {} thumbnails changed since indexing", changed);
+    }
+
+    let mut imported = HashSet::new();
+    let mut progress = Progress::new("Importing", pending.len());
+    let interrupted = import_thumbs(
+        pending,
+        (
+            (sampleres, stat, filter, background, balance),
+            also_res,
+            ignore_exif,
+        ),
+        threads,
+        |result| {
+            match result {
+                Ok(bands) => {
+                    for thumb in bands {
+                        imported.insert(thumb.path.clone());
+                        thumbs_db.thumbs.insert(thumb);
+                    }
+                    dirty_thumbs_db += 1;
+                }
+                Err(e) => {
+                    log::warn!("skipping unreadable thumb: {}", e);
+                    skipped += 1;
+                }
+            }
+
+            progress.tick();
+        },
+    );
+    progress.finish();
+
+    let mut deduped = 0usize;
+    if let Some(threshold) = dedupe {
+        let duplicates = near_duplicates(&thumbs_db.thumbs, &imported, threshold);
+        for (duplicate, kept) in &duplicates {
+            log::info!("Skipping {}: near duplicate of {}", duplicate, kept);
+        }
+        let dropped: HashSet<&str> = duplicates.iter().map(|(d, _)| d.as_str()).collect();
+        thumbs_db
+            .thumbs
+            .retain(|thumb| !dropped.contains(thumb.path.as_str()));
+
+        deduped = duplicates.len();
+        thumbs_db.duplicates.extend(duplicates);
+    }
+
+    if dirty_thumbs_db > 0
+        || stale > 0
+        || pruned > 0
+        || unindexed > 0
+        || deduped > 0
+        || dedupe_changes > 0
+        || db_format != loaded_format
+    {
+        write_thumbs_db(&thumb_data_path, &thumbs_db, db_format)?;
+    }
+    if dirty_thumbs_db > 0 || skipped > 0 {
+        log::info!(
+            "Imported {} thumbnails, skipped {} unreadable files",
+            dirty_thumbs_db,
+            skipped
+        );
+    }
+    if sized_out > 0 {
+        log::info!("Left out {} thumbnails outside the size limits", sized_out);
+    }
+    if deduped > 0 || known_duplicates > 0 {
+        log::info!(
+            "Left out {} near-duplicate thumbnails",
+            deduped + known_duplicates as usize
+        );
+    }
+    profile.record("import", phase_start);
+
+    if interrupted {
+        log::warn!(
+            "Interrupted, saved {} imported thumbnails to {:?}",
+            dirty_thumbs_db,
+            thumb_data_path
+        );
+        return Err(Error::Interrupted);
+    }
+
+    Ok(thumbs_db)
+}
+
+/// Drop the entries of files that no longer exist, along with duplicate records either
+/// side of which is gone. Of each remaining file, only its highest resolution band (the
+/// most recently imported among several) and any bands at `keep_res` are kept. Returns
+/// how many files were gone and how many bands of the others were dropped
+pub fn compact_thumbs_db(thumbs_db: &mut ThumbnailDb, keep_res: &[u32]) -> (usize, usize) {
+    let mut missing = HashSet::new();
+    thumbs_db.thumbs.retain(|thumb| {
+        let exists = Path::new(&thumb.path).exists();
+        if !exists {
+            missing.insert(thumb.path.clone());
+        }
+        exists
+    });
+    thumbs_db
+        .duplicates
+        .retain(|duplicate, kept| Path::new(duplicate).exists() && Path::new(kept).exists());
+
+    // Bands of the same file are next to each other
+    let mut best: HashMap<&str, &ThumbnailData> = HashMap::new();
+    for thumb in &thumbs_db.thumbs {
+        let entry = best.entry(&thumb.path).or_insert(thumb);
+        if (thumb.res, thumb.mtime) > (entry.res, entry.mtime) {
+            *entry = thumb;
+        }
+    }
+    let best: BTreeSet<ThumbnailData> = best.into_values().cloned().collect();
+
+    let before = thumbs_db.thumbs.len();
+    thumbs_db
+        .thumbs
+        .retain(|thumb| keep_res.contains(&thumb.res) || best.contains(thumb));
+
+    (missing.len(), before - thumbs_db.thumbs.len())
+}
+
+pub fn thumb_data_path() -> Result<PathBuf, Error> {
+    let dir = std::env::current_dir().map_err(|e| Error::Io(PathBuf::from("."), e))?;
+
+    Ok(dir.join("thumbdata"))
+}
+
+/// Load thumbnail data from cache, or an empty database if there is none yet
+pub fn load_thumbs_db(path: &Path) -> Result<ThumbnailDb, Error> {
+    read_thumbs_db(path).map(|(thumbs_db, _)| thumbs_db)
+}
+
+/// Load thumbnail data and the format it was stored in. A missing database is empty
+/// and counts as RON
+pub fn read_thumbs_db(path: &Path) -> Result<(ThumbnailDb, DbFormat), Error> {
+    let thumb_data = match fs::read(path) {
+        Ok(thumb_data) => thumb_data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok((ThumbnailDb::default(), DbFormat::Ron));
+        }
+        Err(e) => return Err(Error::Io(path.to_path_buf(), e)),
+    };
+
+    let decode_error = |e| Error::Bincode(path.to_path_buf(), Box::new(e));
+    match thumb_data.strip_prefix(BINCODE_MAGIC) {
+        Some(encoded) => {
+            let (thumbs_db, _) =
+                bincode::serde::decode_from_slice(encoded, bincode::config::standard())
+                    .map_err(decode_error)?;
+            Ok((thumbs_db, DbFormat::Bincode))
+        }
+        None => {
+            let thumbs_db = ron::de::from_bytes(&thumb_data)
+                .map_err(|e| Error::Ron(path.to_path_buf(), Box::new(e)))?;
+            Ok((thumbs_db, DbFormat::Ron))
+        }
+    }
+}
+
+/// Store thumbnail data at `path` in `format`, creating its directory if needed
+pub fn write_thumbs_db(
+    path: &Path,
+    thumbs_db: &ThumbnailDb,
+    format: DbFormat,
+) -> Result<(), Error> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| Error::Io(dir.to_path_buf(), e))?;
+    }
+
+    let thumb_data = match format {
+        DbFormat::Ron => ron::ser::to_string(thumbs_db)?.into_bytes(),
+        DbFormat::Bincode => {
+            let mut thumb_data = BINCODE_MAGIC.to_vec();
+            thumb_data.extend(
+                bincode::serde::encode_to_vec(thumbs_db, bincode::config::standard())
+                    .map_err(Error::BincodeEncode)?,
+            );
+            thumb_data
+        }
+    };
+
+    fs::write(path, thumb_data).map_err(|e| Error::Io(path.to_path_buf(), e))
+}
+
+/// Import `(path, mtime)` thumbnails on the tokio blocking pool, each sampled as
+/// `sampling` says plus at the extra resolutions, handing each file's bands to
+/// `on_result` as it completes. Returns whether Ctrl-C stopped it early, in which case
+/// imports already running still finish, and a second one exits
+#[cfg(feature = "tokio")]
+fn import_thumbs<R>(
+    pending: Vec<(String, Option<u64>)>,
+    (sampling, also_res, ignore_exif): (Sampling, &[u32], bool),
+    threads: Option<usize>,
+    mut on_result: R,
+) -> bool
+where
+    R: FnMut(Result<Vec<ThumbnailData>, Error>),
+{
+    if pending.is_empty() {
+        return false;
+    }
+
+    let runtime = crate::matching::runtime(threads);
+    let also_res: std::sync::Arc<[u32]> = also_res.into();
+
+    runtime.block_on(async move {
+        let mut tasks = tokio::task::JoinSet::new();
+        let mut pending = pending.into_iter();
+
+        // Keep one import in flight per core, starting another as each finishes, so huge
+        // collections don't have thousands of files open at once
+        let concurrency =
+            threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()));
+        for (path, mtime) in pending.by_ref().take(concurrency) {
+            let also_res = also_res.clone();
+            tasks.spawn_blocking(move || import_thumb(&path, (sampling, &also_res, ignore_exif), mtime));
+        }
+
+        let mut interrupted = false;
+        let ctrl_c = crate::interrupt::interrupted();
+        tokio::pin!(ctrl_c);
+
+        loop {
+            let res_thumb = tokio::select! {
+                res_thumb = tasks.join_next() => match res_thumb {
+                    Some(res_thumb) => res_thumb,
+                    None => break,
+                },
+                _ = &mut ctrl_c, if !interrupted => {
+                    log::warn!("Interrupted, saving the thumbnails imported so far (Ctrl-C again to exit now)...");
+                    interrupted = true;
+                    continue;
+                }
+            };
+            on_result(res_thumb.expect("thread failed :("));
+
+            if !interrupted && let Some((path, mtime)) = pending.next() {
+                let also_res = also_res.clone();
+            tasks.spawn_blocking(move || import_thumb(&path, (sampling, &also_res, ignore_exif), mtime));
+            }
+        }
+
+        interrupted
+    })
+}
+
+/// Import `(path, mtime)` thumbnails on the rayon thread pool like the tokio version,
+/// one per pool thread at a time, handing each file's bands to `on_result` as it
+/// completes. Ctrl-C isn't handled
+/// without tokio, so this is never interrupted
+#[cfg(all(feature = "rayon", not(feature = "tokio")))]
+fn import_thumbs<R>(
+    pending: Vec<(String, Option<u64>)>,
+    (sampling, also_res, ignore_exif): (Sampling, &[u32], bool),
+    threads: Option<usize>,
+    mut on_result: R,
+) -> bool
+where
+    R: FnMut(Result<Vec<ThumbnailData>, Error>),
+{
+    use crate::matching::in_pool;
+    use rayon::prelude::*;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            in_pool(threads, || {
+                pending
+                    .into_par_iter()
+                    .for_each_with(sender, |sender, (path, mtime)| {
+                        sender
+                            .send(import_thumb(
+                                &path,
+                                (sampling, also_res, ignore_exif),
+                                mtime,
+                            ))
+                            .ok();
+                    });
+            })
+        });
+
+        for thumb in receiver {
+            on_result(thumb);
+        }
+    });
+
+    false
+}
+
+/// Decode the thumbnail at `p`, turned upright unless `ignore_exif`, and extract its
+/// features, as `thumb_bands` does
+fn import_thumb<P>(
+    p: P,
+    (sampling, also_res, ignore_exif): (Sampling, &[u32], bool),
+    mtime: Option<u64>,
+) -> Result<Vec<ThumbnailData>, Error>
+where
+    P: AsRef<std::path::Path> + Into<String>,
+{
+    let (image, turned) = load_image(&p, ignore_exif)?;
+    let exif = (turned, !ignore_exif);
+    let bands = thumb_bands(&p.into(), &image, sampling, also_res, exif, mtime);
+
+    let resolutions: Vec<String> = bands.iter().map(|band| band.res.to_string()).collect();
+    log::info!(
+        "Imported {}: {}x{}, sampled at {}",
+        bands[0].path,
+        image.width(),
+        image.height(),
+        resolutions.join(", ")
+    );
+
+    Ok(bands)
+}
+
+/// When `path` was last modified, if the platform records it
+fn file_mtime(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+/// Extract the features of a decoded thumbnail, to be stored under `id`. Its transparency
+/// is composited over `background` first, if it has any. `turned` is whether the file's
+/// EXIF orientation turns it, and `applied` whether `image` has been turned upright
+fn thumb_data(
+    id: &str,
+    image: &DynamicImage,
+    sampling: Sampling,
+    (turned, applied): (bool, bool),
+    mtime: Option<u64>,
+) -> ThumbnailData {
+    thumb_bands(id, image, sampling, &[], (turned, applied), mtime).remove(0)
+}
+
+/// Like `thumb_data`, followed by a band at each of `also_res` other than the sampled
+/// resolution. The whole image features are only worked out once for all of them
+fn thumb_bands(
+    id: &str,
+    image: &DynamicImage,
+    (res, stat, filter, background, balance): Sampling,
+    also_res: &[u32],
+    (turned, applied): (bool, bool),
+    mtime: Option<u64>,
+) -> Vec<ThumbnailData> {
+    let alpha = image.color().has_alpha();
+    let flattened;
+    let image = match background {
+        Some(background) if alpha => {
+            flattened = flatten_alpha(image.clone(), background);
+            &flattened
+        }
+        _ => image,
+    };
+    let balanced;
+    let image = match balance {
+        WhiteBalance::None => image,
+        balance => {
+            balanced = white_balance(image.clone(), balance);
+            &balanced
+        }
+    };
+    let (hash, structure) = (dhash(image), structure(image));
+
+    let mut resolutions = vec![res];
+    for &band_res in also_res {
+        if !resolutions.contains(&band_res) {
+            resolutions.push(band_res);
+        }
+    }
+
+    resolutions
+        .into_iter()
+        .map(|res| {
+            let colors = sample_image(image, res, stat, filter);
+            let oklab = oklab_colors(&colors);
+            ThumbnailData {
+                path: id.to_string(),
+                res,
+                stat,
+                filter,
+                mean_oklab: Some(mean_lab(&oklab)),
+                oklab,
+                cielab: cielab_colors(&colors),
+                hsv: hsv_colors(&colors),
+                colors,
+                mtime,
+                dhash: Some(hash),
+                alpha: Some(alpha),
+                background: background.filter(|_| alpha),
+                structure: Some(structure),
+                turned: Some(turned),
+                exif_applied: turned && applied,
+                white_balance: balance,
+            }
+        })
+        .collect()
+}
+
+/// Difference hash of `image`: each bit is whether a pixel of a 9x8 greyscale copy is
+/// brighter than its right-hand neighbour, so resized or recompressed copies and burst
+/// shots hash alike
+fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+
+    hash
+}
+
+/// Pairs of `(duplicate, kept)` paths among `thumbs` whose hashes differ in at most
+/// `threshold` bits. Thumbnails indexed before this run are kept over ones in `imported`,
+/// then earlier paths over later ones. Entries without a hash are never duplicates
+fn near_duplicates(
+    thumbs: &BTreeSet<ThumbnailData>,
+    imported: &HashSet<String>,
+    threshold: u32,
+) -> Vec<(String, String)> {
+    // One hash per file, bands of the same file are next to each other
+    let mut hashes: Vec<(&str, u64)> = Vec::new();
+    for thumb in thumbs {
+        if let Some(hash) = thumb.dhash
+            && hashes.last().is_none_or(|(path, _)| *path != thumb.path)
+        {
+            hashes.push((&thumb.path, hash));
+        }
+    }
+    hashes.sort_by_key(|(path, _)| imported.contains(*path));
+
+    // Two hashes within `threshold` bits agree exactly on at least one of `threshold + 1`
+    // parts, so only kept hashes sharing a part need comparing
+    let parts = (threshold + 1).min(64);
+    let part = |hash: u64, i: u32| {
+        let (start, end) = (i * 64 / parts, (i + 1) * 64 / parts);
+        (hash >> start) & (u64::MAX >> (64 - (end - start)))
+    };
+    let mut buckets: Vec<HashMap<u64, Vec<usize>>> = vec![HashMap::new(); parts as usize];
+    let mut kept: Vec<(&str, u64)> = Vec::new();
+    let mut duplicates = Vec::new();
+
+    for (path, hash) in hashes {
+        let original = (0..parts)
+            .filter_map(|i| buckets[i as usize].get(&part(hash, i)))
+            .flatten()
+            .filter(|&&k| (kept[k].1 ^ hash).count_ones() <= threshold)
+            .min();
+
+        match original {
+            Some(&k) => duplicates.push((path.to_string(), kept[k].0.to_string())),
+            None => {
+                for i in 0..parts {
+                    buckets[i as usize]
+                        .entry(part(hash, i))
+                        .or_default()
+                        .push(kept.len());
+                }
+                kept.push((path, hash));
+            }
+        }
+    }
+
+    duplicates
+}
+
+/// Keep a single band per thumbnail at `matchres`, preferring a band stored at
+/// exactly that resolution and otherwise resampling the `sampleres` band down. Fails if
+/// any thumbnail would need it resampled up instead
+pub(crate) fn select_bands(
+    thumbs: BTreeSet<ThumbnailData>,
+    sampleres: u32,
+    matchres: u32,
+    stat: SampleStat,
+    filter: ResizeFilter,
+    (background, balance): (Option<[u8; 3]>, WhiteBalance),
+    ignore_exif: bool,
+) -> Result<BTreeSet<ThumbnailData>, Error> {
+    let mut bands: HashMap<String, ThumbnailData> = HashMap::new();
+    // Thumbnails a coarser band would have to be made up into, and what else is stored
+    let mut unresolved: HashSet<String> = HashSet::new();
+    let mut stored: BTreeSet<u32> = BTreeSet::new();
+
+    for thumb in thumbs.into_iter().filter(|thumb| {
+        thumb.stat == stat
+            && thumb.filter == filter
+            && thumb.white_balance == balance
+            && thumb.fits_background(background)
+            && thumb.fits_orientation(ignore_exif)
+    }) {
+        stored.insert(thumb.res);
+        if thumb.res == matchres {
+            bands.insert(thumb.path.clone(), thumb);
+        } else if thumb.res == sampleres && matchres > sampleres {
+            unresolved.insert(thumb.path);
+        } else if thumb.res == sampleres && !bands.contains_key(&thumb.path) {
+            let colors = resample_colors(&thumb.colors, sampleres, matchres, stat, filter);
+            let oklab = oklab_colors(&colors);
+            bands.insert(
+                thumb.path.clone(),
+                ThumbnailData {
+                    mean_oklab: Some(mean_lab(&oklab)),
+                    oklab,
+                    cielab: cielab_colors(&colors),
+                    hsv: hsv_colors(&colors),
+                    colors,
+                    path: thumb.path,
+                    res: matchres,
+                    stat,
+                    filter,
+                    mtime: thumb.mtime,
+                    dhash: thumb.dhash,
+                    alpha: thumb.alpha,
+                    background: thumb.background,
+                    structure: thumb.structure,
+                    turned: thumb.turned,
+                    exif_applied: thumb.exif_applied,
+                    white_balance: balance,
+                },
+            );
+        }
+    }
+
+    unresolved.retain(|path| !bands.contains_key(path));
+    if !unresolved.is_empty() {
+        return Err(Error::MissingBand {
+            res: matchres,
+            thumbs: unresolved.len(),
+            stored: stored.into_iter().collect(),
+        });
+    }
+
+    Ok(bands.into_values().collect())
+}
+
+/// Take the first `limit` thumbnails by path, or a seeded random sample of them
+pub(crate) fn limit_thumbs(
+    thumbs: BTreeSet<ThumbnailData>,
+    limit: usize,
+    seed: Option<u64>,
+) -> BTreeSet<ThumbnailData> {
+    let mut thumbs = Vec::from_iter(thumbs);
+    thumbs.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if let Some(seed) = seed {
+        // Partial Fisher-Yates shuffle so the sample only depends on the seed
+        let mut rng = SplitMix64(seed);
+        for i in 0..limit.min(thumbs.len()) {
+            let j = i + rng.below((thumbs.len() - i) as u64) as usize;
+            thumbs.swap(i, j);
+        }
+    }
+
+    thumbs.into_iter().take(limit).collect()
+}
+
+/// Small deterministic PRNG, so seeded runs reproduce across platforms and versions
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// Uniform in `[0, 1)`
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path unique to this test run under the system temporary directory
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("imagegrid-{name}-{}", std::process::id()))
+    }
+
+    /// A band with every field set, so a field read from the wrong offset shows
+    fn thumb() -> ThumbnailData {
+        let colors = vec![[255, 0, 0], [0, 255, 0], [0, 0, 255], [9, 9, 9]];
+        let oklab = oklab_colors(&colors);
+        ThumbnailData {
+            path: "thumbs/a.png".to_string(),
+            res: 2,
+            stat: SampleStat::default(),
+            filter: ResizeFilter::default(),
+            cielab: cielab_colors(&colors),
+            hsv: hsv_colors(&colors),
+            mean_oklab: Some(mean_lab(&oklab)),
+            oklab,
+            colors,
+            mtime: Some(42),
+            dhash: Some(0xdead_beef),
+            alpha: Some(true),
+            background: Some([1, 2, 3]),
+            structure: Some([0.1, 0.2, 0.3]),
+            turned: Some(true),
+            exif_applied: true,
+            white_balance: WhiteBalance::GrayWorld,
+        }
+    }
+
+    /// Store `thumbs_db` in `format` and read it back
+    fn round_trip(thumbs_db: &ThumbnailDb, format: DbFormat) -> ThumbnailDb {
+        let path = temp_path(&format!("{format:?}"));
+        write_thumbs_db(&path, thumbs_db, format).unwrap();
+        let (read, read_format) = read_thumbs_db(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read_format, format);
+        read
+    }
+
+    #[test]
+    fn both_formats_round_trip() {
+        let thumbs_db = ThumbnailDb {
+            thumbs: BTreeSet::from([thumb()]),
+            duplicates: BTreeMap::from([("thumbs/b.png".to_string(), "thumbs/a.png".to_string())]),
+        };
+        // Every field, not just the ones `ThumbnailData`'s equality looks at
+        let written = ron::ser::to_string(&thumbs_db).unwrap();
+
+        for format in [DbFormat::Ron, DbFormat::Bincode] {
+            let read = round_trip(&thumbs_db, format);
+            assert_eq!(ron::ser::to_string(&read).unwrap(), written, "{format:?}");
+        }
+    }
+}
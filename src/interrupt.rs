@@ -0,0 +1,54 @@
+use std::{
+    process::exit,
+    sync::{
+        Once,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use tokio::sync::Notify;
+
+static WATCH: Once = Once::new();
+static INTERRUPTS: AtomicU32 = AtomicU32::new(0);
+static INTERRUPTED: Notify = Notify::const_new();
+
+/// Start listening for Ctrl-C on a thread of its own, for the rest of the process. The
+/// first one is left to `interrupted` to act on, a second one exits straight away.
+/// Listening replaces the default of exiting on the first one, so the thread outlives
+/// any runtime to keep a second Ctrl-C working in every phase
+pub(crate) fn watch() {
+    WATCH.call_once(|| {
+        std::thread::spawn(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("to start the Ctrl-C listener");
+
+            runtime.block_on(async {
+                loop {
+                    if tokio::signal::ctrl_c().await.is_err() {
+                        return;
+                    }
+                    if INTERRUPTS.fetch_add(1, Ordering::SeqCst) > 0 {
+                        eprintln!("\nInterrupted again, exiting without saving");
+                        exit(130i32);
+                    }
+                    INTERRUPTED.notify_waiters();
+                }
+            });
+        });
+    });
+}
+
+/// Resolves once Ctrl-C has been pressed, straight away if it already was
+pub(crate) async fn interrupted() {
+    watch();
+
+    let notified = INTERRUPTED.notified();
+    tokio::pin!(notified);
+    // Registered before checking, so a Ctrl-C in between isn't missed
+    notified.as_mut().enable();
+    if INTERRUPTS.load(Ordering::SeqCst) == 0 {
+        notified.await;
+    }
+}
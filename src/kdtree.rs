@@ -0,0 +1,114 @@
+/// An exact nearest neighbour index over fixed length colour vectors, built once the
+/// pool is locked. Points are compared by summed squared difference, accumulated in the
+/// same order as `compare_thumbs_f32` so the nearest point is the one a full scan picks
+pub(crate) struct KdTree {
+    /// Components per point
+    dims: usize,
+    /// Every point's components back to back, in pool order
+    points: Vec<f32>,
+    /// Pool indices laid out as an implicit tree: each range's middle entry splits it,
+    /// with the lower half on the left and the upper half on the right
+    order: Vec<usize>,
+    /// Component each entry of `order` splits its range on
+    axes: Vec<usize>,
+}
+
+impl KdTree {
+    /// Index `points`, which must all have the same number of colours
+    pub(crate) fn new(points: &[Vec<[f32; 3]>]) -> Self {
+        let mut tree = KdTree {
+            dims: points.first().map_or(0, |p| p.len() * 3),
+            points: points.iter().flatten().flatten().copied().collect(),
+            order: Vec::new(),
+            axes: vec![0; points.len()],
+        };
+
+        let mut order: Vec<usize> = (0..points.len()).collect();
+        tree.build(&mut order, 0);
+        tree.order = order;
+
+        tree
+    }
+
+    fn point(&self, i: usize) -> &[f32] {
+        &self.points[i * self.dims..(i + 1) * self.dims]
+    }
+
+    /// Split `order` on the component it's most spread along, then build both halves.
+    /// `offset` is where `order` starts in the full layout
+    fn build(&mut self, order: &mut [usize], offset: usize) {
+        if order.is_empty() {
+            return;
+        }
+
+        let axis = (0..self.dims)
+            .max_by(|&a, &b| self.spread(order, a).total_cmp(&self.spread(order, b)))
+            .unwrap_or(0);
+
+        let mid = order.len() / 2;
+        order.select_nth_unstable_by(mid, |&a, &b| {
+            self.point(a)[axis].total_cmp(&self.point(b)[axis])
+        });
+        self.axes[offset + mid] = axis;
+
+        let (left, right) = order.split_at_mut(mid);
+        self.build(left, offset);
+        self.build(&mut right[1..], offset + mid + 1);
+    }
+
+    fn spread(&self, order: &[usize], axis: usize) -> f32 {
+        let values = order.iter().map(|&i| self.point(i)[axis]);
+        let (min, max) = values.fold((f32::MAX, f32::MIN), |(min, max), v| {
+            (min.min(v), max.max(v))
+        });
+
+        max - min
+    }
+
+    /// Pool index of the point closest to `query`, the earliest wins ties
+    pub(crate) fn nearest(&self, query: &[[f32; 3]]) -> Option<usize> {
+        if query.len() * 3 != self.dims {
+            return None;
+        }
+
+        let query: Vec<f32> = query.iter().flatten().copied().collect();
+        let mut best = None;
+        self.search(0, self.order.len(), &query, &mut best);
+
+        best.map(|(i, _)| i)
+    }
+
+    fn search(&self, lo: usize, hi: usize, query: &[f32], best: &mut Option<(usize, f32)>) {
+        if lo >= hi {
+            return;
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let i = self.order[mid];
+        let point = self.point(i);
+
+        let mut dist = 0f32;
+        for (x, y) in query.chunks_exact(3).zip(point.chunks_exact(3)) {
+            dist += (x[0] - y[0]).powi(2i32) + (x[1] - y[1]).powi(2i32) + (x[2] - y[2]).powi(2i32);
+        }
+        if best
+            .is_none_or(|(best_i, best_dist)| dist < best_dist || (dist == best_dist && i < best_i))
+        {
+            *best = Some((i, dist));
+        }
+
+        let axis = self.axes[mid];
+        let diff = query[axis] - point[axis];
+        let (near, far) = if diff < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.search(near.0, near.1, query, best);
+        // The split plane bounds everything past it, and ties still need visiting
+        if best.is_none_or(|(_, best_dist)| diff.powi(2i32) <= best_dist) {
+            self.search(far.0, far.1, query, best);
+        }
+    }
+}
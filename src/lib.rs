@@ -0,0 +1,2015 @@
+//! Build image mosaics out of a collection of thumbnails.
+//!
+//! Thumbnails are indexed into a [`ThumbnailDb`] of sampled colours, then every cell of
+//! the input image is matched against that pool and replaced by its best thumbnail.
+//! [`generate_mosaic`] runs the whole pipeline from a [`Config`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::Read,
+    iter::zip,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use image::{
+    AnimationDecoder, Delay, DynamicImage, GenericImageView, RgbImage, codecs::gif::GifDecoder,
+};
+use oklab::Oklab;
+use serde::{Deserialize, Serialize};
+
+mod assign;
+mod batch;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod bucket;
+mod canvas;
+mod db;
+#[cfg(feature = "tokio")]
+mod interrupt;
+mod kdtree;
+mod matching;
+mod output;
+mod progress;
+mod report;
+mod sample;
+
+pub use assign::AssignMode;
+use assign::assign_global;
+use batch::PoolColumns;
+use bucket::ColorBuckets;
+pub use canvas::SvgLinks;
+use canvas::{
+    Canvas, GREYSCALE, TileCache, flat_image, grey, luminance_stats, mean_linear, mosaic_svg,
+    tone_image, tone_linear,
+};
+pub use db::{
+    DbFormat, ThumbnailData, ThumbnailDb, ThumbnailStore, compact_thumbs_db, index_thumbs,
+    load_thumbs_db, read_thumbs_db, thumb_data_path, write_thumbs_db,
+};
+use db::{SplitMix64, limit_thumbs, select_bands};
+use kdtree::KdTree;
+pub use matching::{DifferenceFunction, Flip, FlipMode, SampleWeights};
+use matching::{
+    Matcher, Metric, Search, best_index, cielab_colors, dither_cells, hsv_colors, lab_to_f32,
+    match_cells, mean_lab, oklab_colors, placed_nearby, ranked,
+};
+pub use output::{OutputFormat, StripWriter, save_image};
+use progress::{Progress, heading};
+pub use progress::{init_logger, set_quiet};
+pub use report::{PoorCell, Report};
+pub use sample::{ResizeFilter, SampleStat, WhiteBalance};
+use sample::{adjust_input, decode_image, resample_colors};
+
+/// What chunks and thumbnails are reduced to before comparing them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum MatchMode {
+    /// A sampleres x sampleres grid of colours
+    Sampled,
+    /// The whole cell at thumbsize resolution, which also compares texture. Indexes a
+    /// thumbsize band per thumbnail, so it is much slower and takes far more storage
+    Full,
+}
+
+/// How the input is fitted to a fixed `Config::grid` when the aspect ratios differ
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum GridFit {
+    /// Resize to exactly the grid, distorting the input
+    Stretch,
+    /// Resize to fit inside the grid and pad the rest with black bars
+    Letterbox,
+}
+
+/// What fills the margin when `Config::pad` pads the input up to a multiple of the
+/// cell size
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Padding {
+    /// A solid colour
+    Color([u8; 3]),
+    /// The nearest edge pixel of the input, repeated outwards
+    Edge,
+}
+
+/// Which part of the input is kept when it's cropped to a multiple of the cell size
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Gravity {
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+    #[value(alias = "topleft")]
+    TopLeft,
+    #[value(alias = "topright")]
+    TopRight,
+    #[value(alias = "bottomleft")]
+    BottomLeft,
+    #[value(alias = "bottomright")]
+    BottomRight,
+}
+
+impl Gravity {
+    /// Where the kept region starts when `excess` pixels are trimmed from each axis
+    fn origin(self, (excess_x, excess_y): (u32, u32)) -> (u32, u32) {
+        let x = match self {
+            Gravity::Left | Gravity::TopLeft | Gravity::BottomLeft => 0,
+            Gravity::Right | Gravity::TopRight | Gravity::BottomRight => excess_x,
+            Gravity::Center | Gravity::Top | Gravity::Bottom => excess_x / 2,
+        };
+        let y = match self {
+            Gravity::Top | Gravity::TopLeft | Gravity::TopRight => 0,
+            Gravity::Bottom | Gravity::BottomLeft | Gravity::BottomRight => excess_y,
+            Gravity::Center | Gravity::Left | Gravity::Right => excess_y / 2,
+        };
+
+        (x, y)
+    }
+}
+
+/// Settings for a single mosaic. `Config::default()` matches the CLI defaults
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The input image, or `-` to read it from stdin
+    pub input: PathBuf,
+    /// Globs of thumbnails to index before matching, all merged into one pool
+    pub thumbs: Vec<String>,
+    /// Sampling resolution of image thumbnails
+    pub sampleres: u32,
+    /// Resolutions thumbnails are sampled at as well when they're imported, so
+    /// switching `sampleres` or `matchres` between them needs no new import
+    pub import_res: Vec<u32>,
+    /// How each sample is reduced from the pixels it covers
+    pub sample_stat: SampleStat,
+    /// Filter used for every resize: sampling thumbnails and chunks, and sizing tiles
+    pub filter: ResizeFilter,
+    /// Re-import every thumbnail, ignoring what's cached in the database
+    pub force_reimport: bool,
+    /// Threads to import and match on, one per logical CPU when `None`
+    pub threads: Option<usize>,
+    /// Rewrite the thumbnail database in this format, keeping its current one if `None`
+    pub db_format: Option<DbFormat>,
+    /// Where the thumbnail database is kept, `thumb_data_path()` if `None`
+    pub db_path: Option<PathBuf>,
+    /// Leave out thumbnails whose shorter side is below this many pixels
+    pub min_dimension: Option<u32>,
+    /// Leave out thumbnails whose longer side is above this many pixels
+    pub max_dimension: Option<u32>,
+    /// Leave out thumbnails whose perceptual hash differs from an already indexed one's
+    /// in at most this many bits, keeping every near duplicate if `None`
+    pub dedupe: Option<u32>,
+    /// Colour transparent thumbnails are composited over when sampling and placing them,
+    /// dropping their alpha if `None`
+    pub alpha_background: Option<[u8; 3]>,
+    /// Decode the input and thumbnails as stored, rather than turned upright as their EXIF
+    /// orientation says
+    pub ignore_exif: bool,
+    /// How thumbnails' colour casts are evened out before they're sampled and placed
+    pub normalize: WhiteBalance,
+    /// Size of the thumbnail grid in pixels
+    pub thumbsize: u32,
+    /// Cell width, `thumbsize` if unset
+    pub thumb_width: Option<u32>,
+    /// Cell height, `thumbsize` if unset
+    pub thumb_height: Option<u32>,
+    /// Pick the thumbsize so there are about this many cells per available thumbnail
+    pub auto_thumbsize: Option<f64>,
+    /// Resize the input to exactly this many columns and rows of cells instead of
+    /// cropping it to a multiple of the cell size
+    pub grid: Option<(u32, u32)>,
+    pub grid_fit: GridFit,
+    /// Which part of the input the crop keeps
+    pub gravity: Gravity,
+    /// Sampling resolution chunks are matched at, `sampleres` if unset. Thumbnails are
+    /// matched by their band at this resolution, resampled down from the `sampleres`
+    /// band where there isn't one. Above `sampleres` every thumbnail needs a band
+    pub matchres: Option<u32>,
+    pub match_mode: MatchMode,
+    /// Resolution and candidate count of a fast first matching pass
+    pub coarse: Option<(u32, usize)>,
+    /// Find each cell's best match through a nearest neighbour index over the pool
+    /// instead of scoring every thumbnail. Only that match gets a score, so this can't be
+    /// combined with `coarse` or anything that falls back to the next best match.
+    /// Ignored for `DifferenceFunction::Ciede2000`
+    pub spatial_index: bool,
+    /// Score every thumbnail even in pools of `BUCKET_MIN_POOL` or more, which are
+    /// otherwise grouped by average colour so each cell is only scored against the
+    /// thumbnails near its own. Bucketing is much faster but can miss the best match
+    pub exact: bool,
+    /// Resolution multiplier for the output image
+    pub dpr: u32,
+    pub algorithm: DifferenceFunction,
+    /// Scale factors for the Oklab L, a and b channels before comparison
+    pub lab_weights: [f32; 3],
+    /// How much each sample position in a cell counts towards its difference. Applied
+    /// when comparing, so thumbnails don't need indexing again when it changes
+    pub sample_weights: SampleWeights,
+    /// How much differences in detail count next to differences in colour, so busy cells
+    /// get busy thumbnails. 0 compares colour alone
+    pub structure_weight: f32,
+    /// Extend the grid over partial cells at the edges instead of cropping
+    pub edge_cells: bool,
+    /// Pad the input up to the next multiple of the cell size instead of cropping it,
+    /// with `gravity` placing it in the padded frame. Ignored with `edge_cells`
+    pub pad: Option<Padding>,
+    /// Resize placed thumbnails from full resolution, cropping rather than stretching
+    pub native_tiles: bool,
+    /// Only match, leaving `Mosaic::image` empty, so the output can be composited a band
+    /// at a time with `render_assignment_strips`. `blend` needs the full canvas and is
+    /// ignored
+    pub match_only: bool,
+    /// Place tiles cheaply for a rough look at the composition: at a dpr of 1 and
+    /// resized with nearest neighbour, overriding `dpr` and `native_tiles`. Matching
+    /// runs as usual, so the tile choices are the real ones
+    pub preview: bool,
+    /// Most resized tiles kept in memory while placing, evicting the least recently
+    /// placed. Unbounded when `None`
+    pub cache_size: Option<usize>,
+    /// Pull each placed tile this far (0 to 1) toward its cell's average colour, mixed
+    /// in linear light, so the source image stays legible
+    pub blend: f32,
+    /// Also paint `Mosaic::flat`, the mosaic's counterpart with every cell filled with the
+    /// average colour of the input it was matched against
+    pub flat: bool,
+    /// Shadow and highlight colours placed tiles are mapped to by their luminance, black
+    /// and white for a greyscale mosaic. Tiles keep their colours if `None`
+    pub tone: Option<[[u8; 3]; 2]>,
+    /// Match on luminance alone, comparing the greys of the input and thumbnails
+    pub tone_match: bool,
+    /// Scale each placed tile's brightness to its cell's, by at most this factor (1 or
+    /// more) either way. Tiles are placed as they are if `None`
+    pub match_brightness: Option<f32>,
+    /// With `match_brightness`, scale each tile's contrast to its cell's too
+    pub match_contrast: bool,
+    /// Pixels of the output each tile extends past its cell on every side, fading into
+    /// its neighbours to soften the grid. Capped at half the tile's shorter side, and
+    /// ignored with `match_only`
+    pub overlap: u32,
+    /// Width in output pixels of the line drawn round the inside of every placed tile, 0
+    /// draws none
+    pub tile_border: u32,
+    /// Colour of the `tile_border` lines
+    pub tile_border_color: [u8; 3],
+    /// Gaussian blur sigma applied to the input before matching
+    pub pre_blur: Option<f32>,
+    /// Factor the input's Oklab chroma is scaled by before matching, 1 leaves it as it is
+    pub pre_saturation: f32,
+    /// Factor the input's Oklab lightness is spread from its average by before matching, 1
+    /// leaves it as it is
+    pub pre_contrast: f32,
+    /// Factor (0 to 1) the input is scaled by as soon as it's decoded, so the grid and
+    /// output are sized from the smaller image
+    pub input_scale: Option<f32>,
+    /// Longest side the input is scaled down to right after decoding, if it's larger
+    pub max_input_dimension: Option<u32>,
+    /// Refuse to match grids of more cells than this
+    pub max_cells: Option<u32>,
+    /// Refuse to composite outputs needing more bytes than this
+    pub max_output_bytes: Option<u64>,
+    /// Drop the input image itself from the thumbnail pool
+    pub exclude_self: bool,
+    /// Score added per earlier placement of a thumbnail
+    pub usage_penalty: Option<f64>,
+    /// Minimum distance in cells between copies of a thumbnail
+    pub no_repeat_radius: Option<u32>,
+    /// Most times a single thumbnail may be placed
+    pub max_uses: Option<u32>,
+    /// How cells are given their thumbnails once scored
+    pub assign: AssignMode,
+    /// Diffuse each cell's colour error onto its neighbours (Floyd-Steinberg)
+    pub dither: bool,
+    /// Only match against this many thumbnails
+    pub limit_thumbs: Option<usize>,
+    /// Seed picking a random sample for `limit_thumbs` instead of the first by path, and
+    /// driving the `top_n` picks
+    pub seed: Option<u64>,
+    /// Place one of this many closest matches at random instead of always the closest
+    pub top_n: Option<usize>,
+    /// Fill a cell with the input itself, scaled up, when even its best match scores
+    /// above this, rather than placing a tile that looks nothing like it
+    pub fallback_threshold: Option<f64>,
+    /// Also try every thumbnail mirrored, placing it in whichever orientation fits best
+    pub allow_flip: Option<FlipMode>,
+    /// Also try every thumbnail turned a quarter, half and three quarters of the way
+    /// round, and with `allow_flip` every mirror of those too. Needs square cells
+    pub allow_rotate: bool,
+    /// Cells already assigned by an earlier run, placed without matching
+    pub resume: Option<AssignmentMap>,
+    /// While matching, replace the assignment map at this path with the cells assigned so
+    /// far at most this often, so a crashed render can be resumed from it. Only greedy
+    /// matching assigns cells before they're all scored, so nothing is written otherwise
+    pub checkpoint: Option<(PathBuf, Duration)>,
+    /// Only mosaic every this many frames of an animated input
+    pub frame_skip: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            input: PathBuf::new(),
+            thumbs: vec![String::from("./thumbnails/**/*.jpg")],
+            sampleres: 4,
+            import_res: vec![2, 4, 8],
+            sample_stat: SampleStat::Mean,
+            filter: ResizeFilter::CatmullRom,
+            force_reimport: false,
+            threads: None,
+            db_format: None,
+            db_path: None,
+            min_dimension: None,
+            max_dimension: None,
+            dedupe: None,
+            alpha_background: None,
+            ignore_exif: false,
+            normalize: WhiteBalance::None,
+            thumbsize: 32,
+            thumb_width: None,
+            thumb_height: None,
+            auto_thumbsize: None,
+            grid: None,
+            grid_fit: GridFit::Stretch,
+            gravity: Gravity::Center,
+            matchres: None,
+            match_mode: MatchMode::Sampled,
+            coarse: None,
+            spatial_index: false,
+            exact: false,
+            dpr: 1,
+            algorithm: DifferenceFunction::Oklab,
+            lab_weights: [1.0, 2.0, 2.0],
+            sample_weights: SampleWeights::Flat,
+            structure_weight: 0.0,
+            edge_cells: false,
+            pad: None,
+            native_tiles: false,
+            match_only: false,
+            preview: false,
+            cache_size: None,
+            blend: 0.0,
+            flat: false,
+            tone: None,
+            tone_match: false,
+            match_brightness: None,
+            match_contrast: false,
+            overlap: 0,
+            tile_border: 0,
+            tile_border_color: [0, 0, 0],
+            pre_blur: None,
+            pre_saturation: 1.0,
+            pre_contrast: 1.0,
+            input_scale: None,
+            max_input_dimension: None,
+            max_cells: None,
+            max_output_bytes: None,
+            exclude_self: false,
+            usage_penalty: None,
+            no_repeat_radius: None,
+            max_uses: None,
+            assign: AssignMode::Greedy,
+            dither: false,
+            fallback_threshold: None,
+            limit_thumbs: None,
+            seed: None,
+            top_n: None,
+            allow_flip: None,
+            allow_rotate: false,
+            resume: None,
+            checkpoint: None,
+            frame_skip: 1,
+        }
+    }
+}
+
+impl Config {
+    /// Resolution thumbnails are indexed at. Full matching samples both sides at the
+    /// cell's own resolution
+    pub fn index_res(&self) -> u32 {
+        match self.match_mode {
+            MatchMode::Sampled => self.sampleres,
+            MatchMode::Full => {
+                let (width, height) = self.cell_size();
+                width.max(height)
+            }
+        }
+    }
+
+    /// Resolutions new thumbnails are sampled at besides `index_res`: `import_res`, and
+    /// the ones matching and the coarse pass compare, so they're stored rather than
+    /// resampled
+    fn import_resolutions(&self) -> Vec<u32> {
+        let mut resolutions = self.import_res.clone();
+        resolutions.extend(self.matchres);
+        resolutions.extend(self.coarse.map(|(coarse_res, _)| coarse_res));
+
+        resolutions
+    }
+
+    /// Output resolution multiplier, tile resize filter and whether tiles are resized
+    /// from full resolution, once `preview` has had its say
+    fn tile_settings(&self) -> (u32, ResizeFilter, bool) {
+        match self.preview {
+            true => (1, ResizeFilter::Nearest, false),
+            false => (self.dpr, self.filter, self.native_tiles),
+        }
+    }
+
+    /// Width and height of a grid cell in the input image
+    pub fn cell_size(&self) -> (u32, u32) {
+        (
+            self.thumb_width.unwrap_or(self.thumbsize),
+            self.thumb_height.unwrap_or(self.thumbsize),
+        )
+    }
+}
+
+/// Why a mosaic, or one of the steps leading up to it, failed
+#[derive(Debug)]
+pub enum Error {
+    /// A file couldn't be read or written
+    Io(PathBuf, std::io::Error),
+    /// An image couldn't be decoded or encoded
+    Image(String, image::ImageError),
+    /// The thumbnail glob isn't a valid pattern
+    Glob(glob::PatternError),
+    /// The thumbnail database or an assignment map couldn't be parsed
+    Ron(PathBuf, Box<ron::error::SpannedError>),
+    /// A JSON file couldn't be parsed
+    Json(PathBuf, serde_json::Error),
+    /// Data couldn't be serialized to RON
+    RonEncode(ron::Error),
+    /// A binary thumbnail database couldn't be decoded
+    Bincode(PathBuf, Box<bincode::error::DecodeError>),
+    /// The thumbnail database couldn't be encoded as bincode
+    BincodeEncode(bincode::error::EncodeError),
+    /// Fewer than two thumbnails matched the globs, listed comma separated
+    NotEnoughThumbnails(String),
+    /// `max_uses` copies of every thumbnail can't fill the grid
+    MaxUsesTooLow {
+        thumbs: usize,
+        max_uses: u32,
+        cells: u32,
+    },
+    /// The assignment map to resume from was made with `setting` at `map` rather than `now`
+    ResumeMismatch {
+        setting: &'static str,
+        map: String,
+        now: String,
+    },
+    /// `allow_rotate` was asked for with cells of this width and height
+    NonSquareRotation(u32, u32),
+    /// A cell of the first width and height doesn't fit even once in an input of the
+    /// second, so cropping to whole cells would leave nothing
+    CellLargerThanInput((u32, u32), (u32, u32)),
+    /// Some thumbnails have no band stored at a resolution above `sampleres` to match at,
+    /// and one can't be resampled up from a coarser band
+    MissingBand {
+        res: u32,
+        thumbs: usize,
+        /// Every resolution stored for them
+        stored: Vec<u32>,
+    },
+    /// The grid has more cells than `max_cells`, or the output needs more bytes than
+    /// `max_output_bytes`
+    TooLarge {
+        grid: (u64, u64),
+        output: (u64, u64),
+        bytes: u64,
+        /// Smallest square thumbsize that keeps the grid within `max_cells`, if it's over
+        thumbsize: Option<u32>,
+    },
+    /// Ctrl-C stopped indexing, after the thumbnails imported so far were saved
+    Interrupted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "Error accessing '{}': {}", path.display(), e),
+            Error::Image(path, e) => write!(f, "Error processing image '{}': {}", path, e),
+            Error::Glob(e) => write!(f, "Invalid thumbnail glob: {}", e),
+            Error::Ron(path, e) => write!(f, "Error parsing '{}': {}", path.display(), e),
+            Error::Json(path, e) => write!(f, "Error parsing '{}': {}", path.display(), e),
+            Error::RonEncode(e) => write!(f, "Error serializing data: {}", e),
+            Error::Bincode(path, e) => write!(f, "Error decoding '{}': {}", path.display(), e),
+            Error::BincodeEncode(e) => write!(f, "Error serializing data: {}", e),
+            Error::NotEnoughThumbnails(glob) => {
+                write!(f, "Not enough thumbnails found in {}", glob)
+            }
+            Error::MaxUsesTooLow {
+                thumbs,
+                max_uses,
+                cells,
+            } => write!(
+                f,
+                "{} thumbs used at most {} times each can't fill {} cells, raise --max-uses or use a larger thumbsize",
+                thumbs, max_uses, cells
+            ),
+            Error::ResumeMismatch { setting, map, now } => write!(
+                f,
+                "Assignment map was made with {} {}, but this render has {}",
+                setting, map, now
+            ),
+            Error::NonSquareRotation(width, height) => write!(
+                f,
+                "--allow-rotate only works with square cells, not {}x{}",
+                width, height
+            ),
+            Error::CellLargerThanInput(cell, input) => write!(
+                f,
+                "A --thumbsize of {}x{} is larger than the {}x{} input, so no whole cells fit. Use a smaller --thumbsize, or --pad or --edge-cells to keep partial cells",
+                cell.0, cell.1, input.0, input.1
+            ),
+            Error::MissingBand {
+                res,
+                thumbs,
+                stored,
+            } => write!(
+                f,
+                "{} thumbs have no colours stored at resolution {}, only at {}. Match at one of those, or pass --force-reimport to sample them at {} too",
+                thumbs,
+                res,
+                stored
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                res
+            ),
+            Error::TooLarge {
+                grid,
+                output,
+                bytes,
+                thumbsize,
+            } => {
+                let (size, unit) = match *bytes >= 1 << 30 {
+                    true => (*bytes as f64 / (1u64 << 30) as f64, "GiB"),
+                    false => (*bytes as f64 / (1u64 << 20) as f64, "MiB"),
+                };
+                write!(
+                    f,
+                    "A {}x{} grid of {} cells with a {}x{} output ({:.1} {}) is too large to render safely. ",
+                    grid.0,
+                    grid.1,
+                    grid.0 * grid.1,
+                    output.0,
+                    output.1,
+                    size,
+                    unit
+                )?;
+                match thumbsize {
+                    Some(thumbsize) => write!(f, "Try --thumbsize {} or more, lower", thumbsize)?,
+                    None => write!(f, "Lower")?,
+                }
+                write!(
+                    f,
+                    " --dpr, --input-scale or --max-input-dimension, or pass --yes to go ahead anyway"
+                )
+            }
+            Error::Interrupted => write!(f, "Interrupted by Ctrl-C"),
+        }
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(e: glob::PatternError) -> Self {
+        Error::Glob(e)
+    }
+}
+
+impl From<ron::Error> for Error {
+    fn from(e: ron::Error) -> Self {
+        Error::RonEncode(e)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, e) => Some(e),
+            Error::Image(_, e) => Some(e),
+            Error::Glob(e) => Some(e),
+            Error::Ron(_, e) => Some(e),
+            Error::Json(_, e) => Some(e),
+            Error::RonEncode(e) => Some(e),
+            Error::Bincode(_, e) => Some(e),
+            Error::BincodeEncode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Which thumbnail was placed in each cell of a (possibly partial) render, in raster
+/// order. Also written as the `--manifest` sidecar
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssignmentMap {
+    /// Width and height of the area the grid covers in the cropped input, which is the
+    /// rendered image's size at a dpr of 1. Zero in maps written before it was recorded
+    #[serde(default)]
+    pub dimensions: (u32, u32),
+    /// Cell width
+    pub thumbsize: u32,
+    /// Cell height, if the cells weren't square
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumb_height: Option<u32>,
+    pub matchres: u32,
+    pub algorithm: DifferenceFunction,
+    pub grid: (u32, u32),
+    pub cells: Vec<Assignment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Assignment {
+    pub x: u32,
+    pub y: u32,
+    pub path: String,
+    /// How well the thumbnail matched the cell under the chosen algorithm (lower is
+    /// better). Unknown for cells carried over from a resumed map
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// How the thumbnail is mirrored or turned, with `Config::allow_flip` and
+    /// `Config::allow_rotate`
+    #[serde(default, skip_serializing_if = "Flip::is_none")]
+    pub flip: Flip,
+    /// The cell shows the input itself, since even `path` scored above
+    /// `Config::fallback_threshold`. Anything rendered without the input, like the SVG or
+    /// a regenerated map, shows `path` instead
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fallback: bool,
+}
+
+/// Wall-clock time spent in each phase of a run
+#[derive(Default)]
+pub struct Profile {
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl Profile {
+    pub fn record(&mut self, phase: &'static str, since: Instant) {
+        self.phases.push((phase, since.elapsed()));
+    }
+
+    /// Add the times in `other`, summing any phase both have
+    pub fn add(&mut self, other: Profile) {
+        for (phase, time) in other.phases {
+            match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+                Some((_, total)) => *total += time,
+                None => self.phases.push((phase, time)),
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        println!("{:<12} {:>10}", "Phase", "Time");
+        for (phase, time) in &self.phases {
+            println!("{:<12} {:>7.1} ms", phase, time.as_secs_f64() * 1000.0);
+        }
+        let total: Duration = self.phases.iter().map(|(_, time)| *time).sum();
+        println!("{:<12} {:>7.1} ms", "total", total.as_secs_f64() * 1000.0);
+    }
+}
+
+/// A rendered mosaic and what went into it
+pub struct Mosaic {
+    /// Empty with `Config::match_only`
+    pub image: RgbImage,
+    /// Each cell filled with the average colour of the input it was matched against, the
+    /// same size as the output. Empty without `Config::flat`
+    pub flat: RgbImage,
+    /// Width and height of the output, whether or not `image` holds it
+    pub size: (u32, u32),
+    /// The thumbnail placed in each cell, in raster order
+    pub assignments: Vec<Assignment>,
+    pub grid: (u32, u32),
+    /// Cell width and height in the input image, which `Config::auto_thumbsize` may
+    /// have picked
+    pub thumbsize: (u32, u32),
+    /// Cell width and height in `image`
+    pub tile_size: (u32, u32),
+    pub matchres: u32,
+    pub algorithm: DifferenceFunction,
+    pub native_tiles: bool,
+    /// Number of thumbnails matched against
+    pub pool_size: usize,
+    /// Ctrl-C stopped matching early, so some cells are still empty
+    pub interrupted: bool,
+    pub profile: Profile,
+}
+
+impl Mosaic {
+    /// The assignments in raster order, for resuming or re-rendering later
+    pub fn assignment_map(&self) -> AssignmentMap {
+        let mut cells = self.assignments.clone();
+        cells.sort_by_key(|a| (a.y, a.x));
+
+        let (width, height) = self.thumbsize;
+        let dpr = self.tile_size.0 / width;
+        AssignmentMap {
+            dimensions: (self.size.0 / dpr, self.size.1 / dpr),
+            thumbsize: width,
+            thumb_height: (height != width).then_some(height),
+            matchres: self.matchres,
+            algorithm: self.algorithm,
+            grid: self.grid,
+            cells,
+        }
+    }
+
+    /// An SVG document placing each thumbnail file at its cell, referred to as `links`
+    /// says. Works just as well with `Config::match_only`, since nothing is composited
+    pub fn svg(&self, links: &SvgLinks) -> Result<String, Error> {
+        mosaic_svg(
+            self.size,
+            self.tile_size,
+            self.native_tiles,
+            &self.assignments,
+            links,
+        )
+    }
+}
+
+/// Index `config.thumbs` into the database at `config.db_path`, or `thumb_data_path()`
+/// without one, and render a mosaic against it. Matching
+/// runs on its own thread pool, so this is a plain blocking call
+pub fn generate_mosaic(config: &Config) -> Result<Mosaic, Error> {
+    let mut profile = Profile::default();
+    let thumbs_db = index_config_thumbs(config, &mut profile)?;
+
+    let mut mosaic = generate_mosaic_from(config, thumbs_db, ThumbnailStore::default())?;
+    profile.phases.append(&mut mosaic.profile.phases);
+    mosaic.profile = profile;
+
+    Ok(mosaic)
+}
+
+/// Index `config.thumbs` with the sampling and filters `config` asks for
+fn index_config_thumbs(config: &Config, profile: &mut Profile) -> Result<ThumbnailDb, Error> {
+    index_thumbs(
+        &config.thumbs,
+        (config.db_path.as_deref(), config.db_format),
+        (
+            (
+                config.index_res(),
+                config.sample_stat,
+                config.filter,
+                config.alpha_background,
+                config.normalize,
+            ),
+            &config.import_resolutions(),
+            config.ignore_exif,
+        ),
+        (
+            config.min_dimension,
+            config.max_dimension,
+            config.dedupe,
+            config.structure_weight > 0.0,
+        ),
+        config.force_reimport,
+        config.threads,
+        profile,
+    )
+}
+
+/// Composite the thumbnails recorded in `map` at their cells without matching anything,
+/// using the `dpr`, `filter`, `native_tiles` and `preview` of `config`. Fails on the first
+/// thumbnail that can't be loaded
+pub fn render_assignment_map(map: &AssignmentMap, config: &Config) -> Result<Mosaic, Error> {
+    let mut image = RgbImage::new(0, 0);
+    let profile = render_assignment_strips(map, config, map.grid.1, |strip| {
+        image = strip;
+        Ok(())
+    })?;
+
+    let thumbsize = (map.thumbsize, map.thumb_height.unwrap_or(map.thumbsize));
+    let (dpr, _, native_tiles) = config.tile_settings();
+    Ok(Mosaic {
+        image,
+        flat: RgbImage::new(0, 0),
+        size: rendered_size(map, config),
+        assignments: map.cells.clone(),
+        grid: map.grid,
+        thumbsize,
+        tile_size: (thumbsize.0 * dpr, thumbsize.1 * dpr),
+        matchres: map.matchres,
+        algorithm: map.algorithm,
+        native_tiles,
+        pool_size: HashSet::<&str>::from_iter(map.cells.iter().map(|c| c.path.as_str())).len(),
+        interrupted: false,
+        profile,
+    })
+}
+
+/// An SVG document of `map` at `config.dpr`, like `Mosaic::svg`, without loading or
+/// compositing any tiles
+pub fn assignment_map_svg(
+    map: &AssignmentMap,
+    config: &Config,
+    links: &SvgLinks,
+) -> Result<String, Error> {
+    let thumbsize = (map.thumbsize, map.thumb_height.unwrap_or(map.thumbsize));
+    let (dpr, _, native_tiles) = config.tile_settings();
+
+    mosaic_svg(
+        rendered_size(map, config),
+        (thumbsize.0 * dpr, thumbsize.1 * dpr),
+        native_tiles,
+        &map.cells,
+        links,
+    )
+}
+
+/// Whether an assignment map at `path` is RON, which it is for a .ron path, rather than
+/// JSON. Maps are written and read back by this one rule
+fn ron_map_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "ron")
+}
+
+/// Read an assignment map written by `write_assignment_map`, as RON for a .ron path and
+/// JSON otherwise
+pub fn read_assignment_map(path: &Path) -> Result<AssignmentMap, Error> {
+    let map = fs::read(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    match ron_map_path(path) {
+        true => ron::de::from_bytes(&map).map_err(|e| Error::Ron(path.to_path_buf(), Box::new(e))),
+        false => serde_json::from_slice(&map).map_err(|e| Error::Json(path.to_path_buf(), e)),
+    }
+}
+
+/// Write `map` to `path` as RON for a .ron path and JSON otherwise. It goes to a
+/// temporary file next to `path` first and is renamed over it, so a crash mid-write
+/// leaves whatever map was there before intact
+pub fn write_assignment_map(map: &AssignmentMap, path: &Path) -> Result<(), Error> {
+    let contents = if ron_map_path(path) {
+        ron::ser::to_string(map)?
+    } else {
+        serde_json::to_string_pretty(map).expect("assignment maps always serialize")
+    };
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    fs::write(&temp_path, contents).map_err(|e| Error::Io(temp_path.clone(), e))?;
+    fs::rename(&temp_path, path).map_err(|e| Error::Io(path.to_path_buf(), e))
+}
+
+/// Width and height of the image `render_assignment_strips` composites `map` into
+pub fn rendered_size(map: &AssignmentMap, config: &Config) -> (u32, u32) {
+    let thumbsize = (map.thumbsize, map.thumb_height.unwrap_or(map.thumbsize));
+    let (width, height) = match map.dimensions {
+        (0, 0) => (map.grid.0 * thumbsize.0, map.grid.1 * thumbsize.1),
+        dimensions => dimensions,
+    };
+    let (dpr, _, _) = config.tile_settings();
+
+    (width * dpr, height * dpr)
+}
+
+/// Composite `map` like `render_assignment_map`, but a band of `rows` grid rows at a
+/// time, handing each band to `on_strip` from top to bottom. Only one band of the
+/// output is held in memory, plus whatever `config.cache_size` lets the tile cache keep.
+/// Returns the time spent placing, including in `on_strip`
+pub fn render_assignment_strips(
+    map: &AssignmentMap,
+    config: &Config,
+    rows: u32,
+    mut on_strip: impl FnMut(RgbImage) -> Result<(), Error>,
+) -> Result<Profile, Error> {
+    let mut profile = Profile::default();
+    let phase_start = Instant::now();
+
+    let thumbsize = (map.thumbsize, map.thumb_height.unwrap_or(map.thumbsize));
+    let (width, height) = rendered_size(map, config);
+    let (dpr, filter, native_tiles) = config.tile_settings();
+    let mut canvas = Canvas {
+        image: RgbImage::new(0, 0),
+        first_row: 0,
+        tile_size: (thumbsize.0 * dpr, thumbsize.1 * dpr),
+        native_tiles,
+        filter,
+        blend: 0.0,
+        cell_colors: Vec::new(),
+        columns: map.grid.0,
+        max_adjust: None,
+        match_contrast: false,
+        cell_luminance: Vec::new(),
+        alpha_background: config.alpha_background,
+        white_balance: config.normalize,
+        tone: config.tone,
+        original: None,
+        cell_size: thumbsize,
+        overlap: 0,
+        border: (config.tile_border, config.tile_border_color),
+        feathered: Vec::new(),
+        thumbs_cache: TileCache::new(config.cache_size),
+        thumbs_store: ThumbnailStore::new(config.ignore_exif),
+    };
+
+    let mut cells: Vec<&Assignment> = map.cells.iter().collect();
+    cells.sort_by_key(|cell| (cell.y, cell.x));
+    let mut cells = cells.into_iter().peekable();
+
+    let rows = rows.max(1);
+    let mut progress = Progress::new("Placing", map.cells.len());
+    for first_row in (0..map.grid.1).step_by(rows as usize) {
+        // Edge cells can overhang the bottom, so the last band may be short
+        let top = first_row * canvas.tile_size.1;
+        canvas.image = RgbImage::new(width, (rows * canvas.tile_size.1).min(height - top));
+        canvas.first_row = first_row;
+
+        while let Some(cell) = cells.next_if(|cell| cell.y < first_row + rows) {
+            canvas.place_cell(cell)?;
+            progress.tick();
+        }
+        on_strip(std::mem::take(&mut canvas.image))?;
+    }
+    progress.finish();
+    profile.record("placement", phase_start);
+
+    Ok(profile)
+}
+
+/// Render a mosaic against an already built database, loading thumbnails through
+/// `thumbs_store` so in-memory thumbnails can be used. `config.thumbs` is ignored
+pub fn generate_mosaic_from(
+    config: &Config,
+    thumbs_db: ThumbnailDb,
+    thumbs_store: ThumbnailStore,
+) -> Result<Mosaic, Error> {
+    let mut profile = Profile::default();
+    let inputs = [config.input.clone()];
+    let mut pool = prepare_pool(config, thumbs_db, thumbs_store, &inputs, &mut profile)?;
+
+    let mut mosaic = mosaic_from(config, &mut pool, None)?;
+    profile.phases.append(&mut mosaic.profile.phases);
+    mosaic.profile = profile;
+
+    Ok(mosaic)
+}
+
+/// Index `config.thumbs` once and mosaic each of `inputs` against it in turn, passing
+/// every result to `on_mosaic` with the input it was made from. The pool is locked and
+/// indexed once for the whole batch, and resized tiles stay cached from one image to the
+/// next as far as `config.cache_size` allows. `config.input` is ignored, and
+/// `config.exclude_self` leaves every input out of the pool. Stops after an interrupted
+/// mosaic. Returns the time spent indexing, and in each phase over all images
+pub fn generate_mosaics(
+    config: &Config,
+    inputs: &[PathBuf],
+    mut on_mosaic: impl FnMut(&Path, Mosaic) -> Result<(), Error>,
+) -> Result<Profile, Error> {
+    let mut profile = Profile::default();
+    let thumbs_db = index_config_thumbs(config, &mut profile)?;
+    let mut pool = prepare_pool(
+        config,
+        thumbs_db,
+        ThumbnailStore::default(),
+        inputs,
+        &mut profile,
+    )?;
+
+    for (i, input) in inputs.iter().enumerate() {
+        heading(&format!(
+            "Image {}/{}: {}",
+            i + 1,
+            inputs.len(),
+            input.display()
+        ));
+        let config = Config {
+            input: input.clone(),
+            ..config.clone()
+        };
+        let mut mosaic = mosaic_from(&config, &mut pool, None)?;
+        // Report each phase once, summed over the images
+        profile.add(std::mem::take(&mut mosaic.profile));
+
+        let interrupted = mosaic.interrupted;
+        on_mosaic(input, mosaic)?;
+        if interrupted {
+            break;
+        }
+    }
+
+    Ok(profile)
+}
+
+/// Mosaic each frame of the animated GIF `config.input`, passing every result to
+/// `on_frame` with how long it's shown for. Frames are decoded one at a time, and with
+/// `config.frame_skip` only every nth is kept, shown for as long as the frames it
+/// replaces. Returns the time spent indexing, and in each phase over all frames
+pub fn generate_animation(
+    config: &Config,
+    mut on_frame: impl FnMut(Mosaic, Delay) -> Result<(), Error>,
+) -> Result<Profile, Error> {
+    let mut profile = Profile::default();
+    let thumbs_db = index_config_thumbs(config, &mut profile)?;
+
+    let inputs = [config.input.clone()];
+    let mut pool = prepare_pool(
+        config,
+        thumbs_db,
+        ThumbnailStore::default(),
+        &inputs,
+        &mut profile,
+    )?;
+
+    let input_error = |e| Error::Image(config.input.display().to_string(), e);
+    let file = fs::File::open(&config.input).map_err(|e| Error::Io(config.input.clone(), e))?;
+    let decoder = GifDecoder::new(std::io::BufReader::new(file)).map_err(input_error)?;
+
+    let frame_skip = config.frame_skip.max(1) as usize;
+    let mut frames = decoder.into_frames().peekable();
+    let mut index = 0;
+    while let Some(frame) = frames.next() {
+        let frame = frame.map_err(input_error)?;
+        let mut duration = Duration::from(frame.delay());
+        // Skipped frames are still decoded, only to add their time to the kept frame
+        for _ in 1..frame_skip {
+            match frames.next() {
+                Some(skipped) => duration += Duration::from(skipped.map_err(input_error)?.delay()),
+                None => break,
+            }
+        }
+
+        index += 1;
+        heading(&format!("Frame {}", index));
+        let image = DynamicImage::ImageRgba8(frame.into_buffer());
+        let mut mosaic = mosaic_from(config, &mut pool, Some(image))?;
+        // Report each phase once, summed over the frames
+        profile.add(std::mem::take(&mut mosaic.profile));
+
+        let interrupted = mosaic.interrupted;
+        on_frame(mosaic, Delay::from_saturating_duration(duration))?;
+        if interrupted {
+            break;
+        }
+    }
+
+    Ok(profile)
+}
+
+/// The thumbnail pool locked and indexed for matching, which every image of a batch or
+/// frame of an animation is matched against in turn
+struct PreparedPool {
+    pool: Arc<Vec<ThumbnailData>>,
+    coarse_colors: Arc<Vec<Vec<[u8; 3]>>>,
+    search: Arc<Search>,
+    metric: Metric,
+    thumbs_store: ThumbnailStore,
+    /// Tiles resized for the last image, which can be placed again as long as the tile
+    /// size stays the same
+    thumbs_cache: TileCache,
+    tile_size: (u32, u32),
+}
+
+/// Pick the band of `thumbs_db` to match against, leave out `inputs` if
+/// `config.exclude_self` says so, and lock and index what's left
+fn prepare_pool(
+    config: &Config,
+    mut thumbs_db: ThumbnailDb,
+    mut thumbs_store: ThumbnailStore,
+    inputs: &[PathBuf],
+    profile: &mut Profile,
+) -> Result<PreparedPool, Error> {
+    if thumbs_db.thumbs.len() < 2 {
+        return Err(Error::NotEnoughThumbnails(config.thumbs.join(", ")));
+    }
+
+    // Pick one resolution band per thumbnail to match against
+    let phase_start = Instant::now();
+    let sampleres = config.index_res();
+    let matchres = config.matchres.unwrap_or(sampleres);
+    let mut coarse_bands: HashMap<String, Vec<[u8; 3]>> = HashMap::new();
+    if let Some((coarse_res, _)) = config.coarse {
+        coarse_bands.extend(
+            select_bands(
+                thumbs_db.thumbs.clone(),
+                sampleres,
+                coarse_res,
+                config.sample_stat,
+                config.filter,
+                (config.alpha_background, config.normalize),
+                config.ignore_exif,
+            )?
+            .into_iter()
+            .map(|thumb| match config.tone_match {
+                true => (thumb.path, thumb.colors.iter().map(|&c| grey(c)).collect()),
+                false => (thumb.path, thumb.colors),
+            }),
+        );
+    }
+    thumbs_db.thumbs = select_bands(
+        thumbs_db.thumbs,
+        sampleres,
+        matchres,
+        config.sample_stat,
+        config.filter,
+        (config.alpha_background, config.normalize),
+        config.ignore_exif,
+    )?;
+
+    if config.exclude_self {
+        // Compare canonical paths so relative and absolute spellings both match
+        let input_paths: HashSet<PathBuf> = inputs
+            .iter()
+            .filter_map(|input| fs::canonicalize(input).ok())
+            .collect();
+        if !input_paths.is_empty() {
+            thumbs_db.thumbs.retain(|thumb| {
+                fs::canonicalize(&thumb.path)
+                    .ok()
+                    .is_none_or(|path| !input_paths.contains(&path))
+            });
+        }
+    }
+
+    if let Some(limit) = config.limit_thumbs {
+        thumbs_db.thumbs = limit_thumbs(thumbs_db.thumbs, limit, config.seed);
+        log::info!("Limited matching to {} thumbs", thumbs_db.thumbs.len());
+    }
+
+    // Lock thumbs_db, the order here is the order chunk scores are reported in. It's
+    // sorted by path whatever order thumbnails were found or imported in, which is what
+    // makes ties between equal scores break the same way on every machine
+    let pool: Vec<ThumbnailData> = thumbs_db
+        .thumbs
+        .into_iter()
+        .map(|mut thumb| {
+            if config.tone_match {
+                thumb.colors = thumb.colors.iter().map(|&c| grey(c)).collect();
+                thumb.oklab = oklab_colors(&thumb.colors);
+                thumb.cielab = cielab_colors(&thumb.colors);
+                thumb.hsv = hsv_colors(&thumb.colors);
+                thumb.mean_oklab = Some(mean_lab(&thumb.oklab));
+            }
+            for lab in thumb.oklab.iter_mut().chain(&mut thumb.mean_oklab) {
+                let [l, a, b] = *lab;
+                *lab = lab_to_f32(Oklab { l, a, b }, &config.lab_weights);
+            }
+            thumb
+        })
+        .collect();
+
+    let coarse_colors = match config.coarse {
+        Some((coarse_res, _)) => pool
+            .iter()
+            .map(|thumb| match coarse_bands.remove(&thumb.path) {
+                Some(colors) => colors,
+                None => resample_colors(
+                    &thumb.colors,
+                    matchres,
+                    coarse_res,
+                    config.sample_stat,
+                    config.filter,
+                ),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let metric = Metric {
+        algorithm: config.algorithm,
+        lab_weights: config.lab_weights,
+        sample_weights: config.sample_weights,
+        structure_weight: config.structure_weight,
+    };
+    // CIEDE2000 and HSV aren't Euclidean distances, and the tree's points aren't weighted,
+    // so a k-d tree can't search any of them
+    let spatial_index = config.spatial_index
+        && matches!(
+            config.algorithm,
+            DifferenceFunction::Rgb
+                | DifferenceFunction::Oklab
+                | DifferenceFunction::Cielab
+                | DifferenceFunction::AverageColor
+        )
+        && config.sample_weights == SampleWeights::Flat
+        && config.structure_weight == 0.0;
+    if config.spatial_index && !spatial_index {
+        log::warn!(
+            "The spatial index doesn't support ciede2000, hsv, weighted samples or structure, scoring every thumbnail instead"
+        );
+    }
+    let search = if spatial_index {
+        let points: Vec<Vec<[f32; 3]>> = pool
+            .iter()
+            .map(|thumb| match config.algorithm {
+                DifferenceFunction::Cielab => metric.index_colors(&thumb.colors, &thumb.cielab),
+                DifferenceFunction::AverageColor => {
+                    metric.index_colors(&thumb.colors, thumb.mean_oklab.as_slice())
+                }
+                _ => metric.index_colors(&thumb.colors, &thumb.oklab),
+            })
+            .collect();
+        Search::Nearest(KdTree::new(&points))
+    } else if !config.exact
+        && config.coarse.is_none()
+        && config.assign == AssignMode::Greedy
+        && pool.len() >= BUCKET_MIN_POOL
+    {
+        log::info!(
+            "Bucketed {} thumbs by average colour, only scoring the nearest buckets",
+            pool.len()
+        );
+        Search::Buckets(ColorBuckets::new(&pool, config.algorithm))
+    } else {
+        PoolColumns::new(&pool, config.algorithm).map_or(Search::Scan, Search::Batched)
+    };
+
+    profile.record("index build", phase_start);
+
+    // Tiles are placed turned the same way they were sampled
+    thumbs_store.ignore_exif = config.ignore_exif;
+
+    Ok(PreparedPool {
+        pool: Arc::new(pool),
+        coarse_colors: Arc::new(coarse_colors),
+        search: Arc::new(search),
+        metric,
+        thumbs_store,
+        thumbs_cache: TileCache::new(config.cache_size),
+        tile_size: (0, 0),
+    })
+}
+
+/// Decode the image at `path`, or the one piped to stdin if `path` is -, turned upright
+/// unless `ignore_exif`
+fn load_input(path: &Path, ignore_exif: bool) -> Result<DynamicImage, Error> {
+    let io_error = |e| Error::Io(path.to_path_buf(), e);
+    let raw_image = if path.as_os_str() == "-" {
+        let mut raw_image = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut raw_image)
+            .map_err(io_error)?;
+        raw_image
+    } else {
+        fs::read(path).map_err(io_error)?
+    };
+
+    decode_image(&raw_image, ignore_exif)
+        .map(|(image, _)| image)
+        .map_err(|e| Error::Image(path.display().to_string(), e))
+}
+
+/// Render a mosaic of `input`, or of the image at `config.input` if there isn't one,
+/// against `prepared`
+fn mosaic_from(
+    config: &Config,
+    prepared: &mut PreparedPool,
+    input: Option<DynamicImage>,
+) -> Result<Mosaic, Error> {
+    let mut profile = Profile::default();
+    let matchres = config.matchres.unwrap_or(config.index_res());
+
+    // Load the target image
+    let phase_start = Instant::now();
+    let mut image = match input {
+        Some(image) => image,
+        None => load_input(&config.input, config.ignore_exif)?,
+    };
+
+    // Everything from here on is sized from the scaled input
+    let (mut width, mut height) = image.dimensions();
+    if let Some((scaled_width, scaled_height)) = scaled_input_size(
+        (width, height),
+        config.input_scale,
+        config.max_input_dimension,
+    ) {
+        image = image.resize_exact(scaled_width, scaled_height, config.filter.into());
+        log::info!(
+            "Scaled the input from {}x{} to {}x{}",
+            width,
+            height,
+            scaled_width,
+            scaled_height
+        );
+        (width, height) = (scaled_width, scaled_height);
+    }
+
+    let mut thumbsize = config.cell_size();
+    if let Some(ratio) = config.auto_thumbsize {
+        let target = ratio * prepared.pool.len() as f64;
+        // Padding covers partial cells just like edge cells do
+        let partial_cells = config.edge_cells || config.pad.is_some();
+        let size = suggest_thumbsize((width, height), target, partial_cells);
+        thumbsize = (size, size);
+
+        let (x_chunks, y_chunks) = grid_size((width, height), thumbsize, partial_cells);
+        log::info!(
+            "Picked thumbsize {} for a {}x{} grid ({} cells from {} thumbs)",
+            thumbsize.0,
+            x_chunks,
+            y_chunks,
+            x_chunks * y_chunks,
+            prepared.pool.len()
+        );
+    }
+
+    if config.allow_rotate && thumbsize.0 != thumbsize.1 {
+        return Err(Error::NonSquareRotation(thumbsize.0, thumbsize.1));
+    }
+
+    if let Some((columns, rows)) = config.grid {
+        image = fit_grid(
+            &image,
+            (columns * thumbsize.0, rows * thumbsize.1),
+            config.grid_fit,
+            config.filter,
+        );
+        (width, height) = image.dimensions();
+    }
+
+    // Crop the image to the nearest multiple of the cell size, or pad it up to the next
+    let (crop_width, crop_height) = if config.edge_cells {
+        (width, height)
+    } else if config.pad.is_some() {
+        (
+            width.next_multiple_of(thumbsize.0),
+            height.next_multiple_of(thumbsize.1),
+        )
+    } else {
+        (width - width % thumbsize.0, height - height % thumbsize.1)
+    };
+    if crop_width == 0 || crop_height == 0 {
+        return Err(Error::CellLargerThanInput(thumbsize, (width, height)));
+    }
+
+    image = match config.pad {
+        Some(padding) => pad_image(&image, (crop_width, crop_height), config.gravity, padding),
+        None => {
+            let (crop_x, crop_y) = config
+                .gravity
+                .origin((width - crop_width, height - crop_height));
+            image.crop(crop_x, crop_y, crop_width, crop_height)
+        }
+    };
+
+    // Fallback cells show the input as it is, not blurred or toned for matching
+    let resumed_fallbacks = config
+        .resume
+        .as_ref()
+        .is_some_and(|map| map.cells.iter().any(|cell| cell.fallback));
+    let original = (!config.match_only
+        && (config.fallback_threshold.is_some() || resumed_fallbacks))
+        .then(|| image.to_rgb8());
+
+    // Only what's matched, blended and brightness matched against is adjusted, the
+    // thumbnails and their cached colours are left alone
+    image = adjust_input(image, config.pre_saturation, config.pre_contrast);
+
+    // Only the sampled input is blurred, placed thumbnails stay sharp
+    if let Some(sigma) = config.pre_blur {
+        image = image.blur(sigma);
+    }
+
+    let input = match config.tone_match {
+        true => tone_image(&image, GREYSCALE),
+        false => image.into_rgb8(),
+    };
+    let matcher = Arc::new(Matcher {
+        input,
+        pool: prepared.pool.clone(),
+        coarse_colors: prepared.coarse_colors.clone(),
+        coarse: config.coarse,
+        search: prepared.search.clone(),
+        metric: prepared.metric,
+        thumbsize,
+        matchres,
+        stat: config.sample_stat,
+        filter: config.filter,
+        orientations: FlipMode::orientations(config.allow_flip, config.allow_rotate),
+    });
+    let pool = &matcher.pool;
+    profile.record("input decode", phase_start);
+
+    let x_chunks = crop_width.div_ceil(thumbsize.0);
+    let y_chunks = crop_height.div_ceil(thumbsize.1);
+    // Can't overflow once `check_size` has capped the cell count
+    check_size(config, (crop_width, crop_height), thumbsize)?;
+    let chunks = x_chunks * y_chunks;
+    if chunks > LARGE_GRID {
+        log::warn!(
+            "{} cells is a very large job, consider --input-scale or a larger thumbsize",
+            chunks
+        );
+    }
+
+    let blend = !config.match_only && config.blend > 0.0;
+    let match_brightness = !config.match_only && config.match_brightness.is_some();
+    let mut cell_colors = Vec::new();
+    let mut cell_luminance = Vec::new();
+    let mut flat_colors = Vec::new();
+    if blend || match_brightness || config.flat {
+        for y in 0..y_chunks {
+            for x in 0..x_chunks {
+                let chunk = matcher.chunk(x, y);
+                if blend || config.flat {
+                    let color = mean_linear(&chunk);
+                    if config.flat {
+                        flat_colors.push(color);
+                    }
+                    if blend {
+                        cell_colors.push(match config.tone {
+                            Some(tone) => tone_linear(color, tone),
+                            None => color,
+                        });
+                    }
+                }
+                if match_brightness {
+                    cell_luminance.push(luminance_stats(&chunk, config.tone));
+                }
+            }
+        }
+    }
+
+    let (dpr, filter, native_tiles) = config.tile_settings();
+    let composite = !config.match_only;
+    let tile_size = (thumbsize.0 * dpr, thumbsize.1 * dpr);
+    let flat = match config.flat {
+        true => flat_image(
+            &flat_colors,
+            x_chunks,
+            tile_size,
+            (crop_width * dpr, crop_height * dpr),
+        ),
+        false => RgbImage::new(0, 0),
+    };
+    let overlap = match composite {
+        true => config.overlap.min(tile_size.0.min(tile_size.1) / 2),
+        false => 0,
+    };
+    let mut canvas = Canvas {
+        image: match composite {
+            true => RgbImage::new(crop_width * dpr, crop_height * dpr),
+            false => RgbImage::new(0, 0),
+        },
+        first_row: 0,
+        tile_size,
+        native_tiles,
+        filter,
+        blend: config.blend,
+        cell_colors,
+        columns: x_chunks,
+        max_adjust: config.match_brightness,
+        match_contrast: config.match_contrast,
+        cell_luminance,
+        alpha_background: config.alpha_background,
+        white_balance: config.normalize,
+        tone: config.tone,
+        original,
+        cell_size: thumbsize,
+        overlap,
+        border: (config.tile_border, config.tile_border_color),
+        feathered: match overlap {
+            0 => Vec::new(),
+            _ => vec![[0; 4]; (crop_width * dpr * crop_height * dpr) as usize],
+        },
+        thumbs_cache: match prepared.tile_size == tile_size {
+            true => std::mem::take(&mut prepared.thumbs_cache),
+            false => TileCache::new(config.cache_size),
+        },
+        thumbs_store: std::mem::take(&mut prepared.thumbs_store),
+    };
+
+    if let Some(max_uses) = config.max_uses
+        && (max_uses as usize).saturating_mul(pool.len()) < chunks as usize
+    {
+        return Err(Error::MaxUsesTooLow {
+            thumbs: pool.len(),
+            max_uses,
+            cells: chunks,
+        });
+    }
+
+    // Cells already assigned by a previous run are placed without matching
+    let mut resumed: HashMap<(u32, u32), &Assignment> = HashMap::new();
+    if let Some(map) = config.resume.as_ref() {
+        let size = |(width, height): (u32, u32)| format!("{}x{}", width, height);
+        let map_thumbsize = (map.thumbsize, map.thumb_height.unwrap_or(map.thumbsize));
+        let mismatch = if map_thumbsize != thumbsize {
+            Some(("thumbsize", size(map_thumbsize), size(thumbsize)))
+        } else if map.grid != (x_chunks, y_chunks) {
+            Some(("grid", size(map.grid), size((x_chunks, y_chunks))))
+        } else if map.matchres != matchres {
+            Some(("matchres", map.matchres.to_string(), matchres.to_string()))
+        } else if map.algorithm != config.algorithm {
+            let (map, now) = (map.algorithm, config.algorithm);
+            Some(("algorithm", format!("{:?}", map), format!("{:?}", now)))
+        } else {
+            None
+        };
+        if let Some((setting, map, now)) = mismatch {
+            return Err(Error::ResumeMismatch { setting, map, now });
+        }
+        // A hand-edited or stale map can still list cells the grid doesn't have
+        if let Some(cell) = map
+            .cells
+            .iter()
+            .find(|a| a.x >= x_chunks || a.y >= y_chunks)
+        {
+            return Err(Error::ResumeMismatch {
+                setting: "a cell at",
+                map: format!("({}, {})", cell.x, cell.y),
+                now: format!("a {}x{} grid", x_chunks, y_chunks),
+            });
+        }
+
+        resumed.extend(map.cells.iter().map(|a| ((a.x, a.y), a)));
+        log::info!("Resuming {} assigned chunks", resumed.len());
+    }
+
+    let phase_start = Instant::now();
+    // Each cell's assignment at its raster index. Cells are matched in whatever order
+    // their tasks finish, so nothing is placed until they're all in, and then in raster
+    // order, which keeps the output and the order assignments are listed in the same on
+    // every run
+    let mut grid: Vec<Option<Assignment>> = vec![None; chunks as usize];
+    let raster = |x: u32, y: u32| (y * x_chunks + x) as usize;
+    let mut pending: Vec<(u32, u32, Vec<f64>)> = Vec::new();
+
+    for &cell in resumed.values() {
+        grid[raster(cell.x, cell.y)] = Some(Assignment {
+            score: None,
+            ..cell.clone()
+        });
+    }
+
+    let mut cells = Vec::new();
+    for x_chunk in 0..x_chunks {
+        for y_chunk in 0..y_chunks {
+            if !resumed.contains_key(&(x_chunk, y_chunk)) {
+                cells.push((x_chunk, y_chunk));
+            }
+        }
+    }
+
+    // Penalised or spaced out matches depend on earlier choices, random picks have to
+    // draw from the seed in a fixed cell order, and a global assignment needs every
+    // cell's scores, so they're assigned once all cells are scored
+    let assign_serially = config.usage_penalty.is_some()
+        || config.no_repeat_radius.is_some()
+        || config.max_uses.is_some()
+        || config.top_n.is_some()
+        || config.assign == AssignMode::Global;
+    let mut progress = Progress::new("Matching", cells.len());
+    let mut last_checkpoint = Instant::now();
+    let on_result = |x, y, scores: Vec<f64>| {
+        if assign_serially {
+            pending.push((x, y, scores));
+        } else {
+            let best_i = best_index(&scores).expect("To process image chunk");
+            let best = &pool[best_i];
+            let flip = matcher.orientation(x, y, best_i);
+            log::debug!(
+                "Cell ({}, {}): {} scored {:.4}",
+                x,
+                y,
+                best.path,
+                scores[best_i]
+            );
+
+            grid[raster(x, y)] = Some(Assignment {
+                x,
+                y,
+                path: best.path.clone(),
+                score: Some(scores[best_i]),
+                flip,
+                fallback: config
+                    .fallback_threshold
+                    .is_some_and(|t| scores[best_i] > t),
+            });
+
+            if let Some((path, every)) = &config.checkpoint
+                && last_checkpoint.elapsed() >= *every
+            {
+                let map = AssignmentMap {
+                    dimensions: (crop_width, crop_height),
+                    thumbsize: thumbsize.0,
+                    thumb_height: (thumbsize.1 != thumbsize.0).then_some(thumbsize.1),
+                    matchres,
+                    algorithm: config.algorithm,
+                    grid: (x_chunks, y_chunks),
+                    cells: grid.iter().flatten().cloned().collect(),
+                };
+                // A checkpoint is only a safety net, so failing to write one doesn't
+                // stop the render
+                if let Err(e) = write_assignment_map(&map, path) {
+                    log::warn!("Couldn't write checkpoint: {}", e);
+                }
+                last_checkpoint = Instant::now();
+            }
+        }
+
+        progress.tick();
+    };
+
+    let interrupted = if config.dither {
+        dither_cells(&matcher, cells, (x_chunks, y_chunks), on_result);
+        false
+    } else {
+        let matcher = matcher.clone();
+        match_cells(
+            cells,
+            config.threads,
+            move |x, y| matcher.score_cell(x, y),
+            on_result,
+        )
+    };
+
+    progress.finish();
+
+    if assign_serially {
+        let penalty = config.usage_penalty.unwrap_or(0.0);
+        let mut rng = SplitMix64(config.seed.unwrap_or_else(|| {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+            now.map_or(0, |d| d.as_nanos() as u64)
+        }));
+        let mut uses = vec![0u32; pool.len()];
+        // Pool index placed in each cell so far, including resumed cells
+        let mut placed: HashMap<(u32, u32), usize> = HashMap::new();
+        let pool_index: HashMap<&str, usize> = pool
+            .iter()
+            .enumerate()
+            .map(|(i, thumb)| (thumb.path.as_str(), i))
+            .collect();
+        for assignment in grid.iter().flatten() {
+            if let Some(&i) = pool_index.get(assignment.path.as_str()) {
+                uses[i] += 1;
+                placed.insert((assignment.x, assignment.y), i);
+            }
+        }
+
+        pending.sort_by_key(|(x, y, _)| (*y, *x));
+
+        // Each thumbnail gets an even share of the grid, or `max_uses` if that's set
+        let global = (config.assign == AssignMode::Global).then(|| {
+            let cap = config
+                .max_uses
+                .unwrap_or_else(|| chunks.div_ceil(pool.len() as u32));
+            let capacity = uses.iter().map(|&n| cap.saturating_sub(n)).collect();
+            let scores: Vec<&[f64]> = pending.iter().map(|(_, _, s)| s.as_slice()).collect();
+
+            let (chosen, greedy_total, total) = assign_global(&scores, capacity);
+            log::info!(
+                "Assigned {} cells at most {} times per thumb, swaps lowered the total score from {:.1} to {:.1}",
+                chosen.len(),
+                cap,
+                greedy_total,
+                total
+            );
+            chosen
+        });
+
+        for (cell, (x, y, scores)) in pending.into_iter().enumerate() {
+            let penalised: Vec<f64> = zip(&scores, &uses)
+                .map(|(score, uses)| score + penalty * *uses as f64)
+                .collect();
+
+            let best = if let Some(chosen) = &global {
+                Some(chosen[cell])
+            } else if config.no_repeat_radius.is_none()
+                && config.max_uses.is_none()
+                && config.top_n.is_none()
+            {
+                best_index(&penalised)
+            } else {
+                let cap = config.max_uses.unwrap_or(u32::MAX);
+                let order = ranked(&penalised);
+                let mut available = order.iter().copied().filter(|&i| uses[i] < cap);
+
+                let candidates: Vec<usize> = available
+                    .clone()
+                    .filter(|&i| {
+                        config
+                            .no_repeat_radius
+                            .is_none_or(|radius| !placed_nearby(&placed, (x, y), radius, i))
+                    })
+                    .take(config.top_n.unwrap_or(1))
+                    .collect();
+
+                match candidates.as_slice() {
+                    // Fall back to the best match left if everything is already nearby
+                    [] => available.next(),
+                    [best] => Some(*best),
+                    candidates => Some(pick_weighted(candidates, &penalised, &mut rng)),
+                }
+            }
+            .expect("To process image chunk");
+            uses[best] += 1;
+            placed.insert((x, y), best);
+
+            let flip = matcher.orientation(x, y, best);
+            log::debug!(
+                "Cell ({}, {}): {} scored {:.4}",
+                x,
+                y,
+                pool[best].path,
+                scores[best]
+            );
+            grid[raster(x, y)] = Some(Assignment {
+                x,
+                y,
+                path: pool[best].path.clone(),
+                score: Some(scores[best]),
+                flip,
+                fallback: config.fallback_threshold.is_some_and(|t| scores[best] > t),
+            });
+        }
+
+        let mut used: Vec<u32> = uses.into_iter().filter(|&n| n > 0).collect();
+        used.sort_unstable();
+        if let (Some(min), Some(max)) = (used.first(), used.last()) {
+            log::info!(
+                "Used {} of {} thumbs, {} to {} times each (median {})",
+                used.len(),
+                pool.len(),
+                min,
+                max,
+                used[used.len() / 2]
+            );
+        }
+    }
+
+    let assignments: Vec<Assignment> = grid.into_iter().flatten().collect();
+    if let Some(threshold) = config.fallback_threshold {
+        let fallbacks = assignments.iter().filter(|a| a.fallback).count();
+        log::info!(
+            "Kept the input in {} of {} cells, where nothing scored within {}",
+            fallbacks,
+            chunks,
+            threshold
+        );
+    }
+    profile.record("matching", phase_start);
+
+    let phase_start = Instant::now();
+    if composite {
+        for assignment in &assignments {
+            canvas.place_cell(assignment)?;
+        }
+    }
+    canvas.finish();
+    prepared.thumbs_store = canvas.thumbs_store;
+    prepared.thumbs_cache = canvas.thumbs_cache;
+    prepared.tile_size = canvas.tile_size;
+    profile.record("placement", phase_start);
+
+    Ok(Mosaic {
+        image: canvas.image,
+        flat,
+        size: (crop_width * dpr, crop_height * dpr),
+        assignments,
+        grid: (x_chunks, y_chunks),
+        thumbsize,
+        tile_size: canvas.tile_size,
+        matchres,
+        algorithm: config.algorithm,
+        native_tiles: canvas.native_tiles,
+        pool_size: pool.len(),
+        interrupted,
+        profile,
+    })
+}
+
+/// One of `candidates` picked at random, each as likely as the inverse of its score so
+/// closer matches still win more often
+fn pick_weighted(candidates: &[usize], scores: &[f64], rng: &mut SplitMix64) -> usize {
+    let weights: Vec<f64> = candidates
+        .iter()
+        .map(|&i| 1.0 / scores[i].max(1e-9))
+        .collect();
+
+    let mut target = rng.unit() * weights.iter().sum::<f64>();
+    for (&i, weight) in zip(candidates, &weights) {
+        if target < *weight {
+            return i;
+        }
+        target -= weight;
+    }
+
+    candidates[candidates.len() - 1]
+}
+
+/// Resize `image` to exactly `size`, stretching or letterboxing it as `fit` says
+fn fit_grid(
+    image: &DynamicImage,
+    (width, height): (u32, u32),
+    fit: GridFit,
+    filter: ResizeFilter,
+) -> DynamicImage {
+    match fit {
+        GridFit::Stretch => image.resize_exact(width, height, filter.into()),
+        GridFit::Letterbox => {
+            let scaled = image.resize(width, height, filter.into()).into_rgb8();
+            let mut boxed = RgbImage::new(width, height);
+            image::imageops::overlay(
+                &mut boxed,
+                &scaled,
+                ((width - scaled.width()) / 2) as i64,
+                ((height - scaled.height()) / 2) as i64,
+            );
+            DynamicImage::ImageRgb8(boxed)
+        }
+    }
+}
+
+/// `image` on a `width` x `height` frame at least as large, placed where `gravity` says
+/// and with the margin filled as `padding` says
+fn pad_image(
+    image: &DynamicImage,
+    (width, height): (u32, u32),
+    gravity: Gravity,
+    padding: Padding,
+) -> DynamicImage {
+    let source = image.to_rgb8();
+    let (x, y) = gravity.origin((width - source.width(), height - source.height()));
+
+    DynamicImage::ImageRgb8(match padding {
+        Padding::Color(color) => {
+            let mut padded = RgbImage::from_pixel(width, height, image::Rgb(color));
+            image::imageops::overlay(&mut padded, &source, x as i64, y as i64);
+            padded
+        }
+        Padding::Edge => RgbImage::from_fn(width, height, |px, py| {
+            *source.get_pixel(
+                px.saturating_sub(x).min(source.width() - 1),
+                py.saturating_sub(y).min(source.height() - 1),
+            )
+        }),
+    })
+}
+
+/// Pool size from which thumbnails are bucketed by colour unless `Config::exact` is set.
+/// Smaller pools are quick enough to score in full
+pub const BUCKET_MIN_POOL: usize = 4096;
+
+/// Cell count past which a grid is big enough to take hours to match
+const LARGE_GRID: u32 = 500_000;
+
+/// Fail before anything is matched or allocated if a grid of `thumbsize` cells over the
+/// cropped input of `size` is over `config.max_cells` or `u32::MAX`, or its output over
+/// `config.max_output_bytes`. Counted in 64 bits, so absurd sizes can't overflow
+fn check_size(config: &Config, size: (u32, u32), thumbsize: (u32, u32)) -> Result<(), Error> {
+    let cells = |(width, height): (u32, u32)| {
+        (
+            size.0.div_ceil(width) as u64,
+            size.1.div_ceil(height) as u64,
+        )
+    };
+    let grid = cells(thumbsize);
+
+    let (dpr, _, _) = config.tile_settings();
+    let output = (size.0 as u64 * dpr as u64, size.1 as u64 * dpr as u64);
+    // RGB, plus the feathering sums with `overlap`
+    let bytes = match (config.match_only, config.overlap) {
+        (true, _) => 0,
+        (false, 0) => output.0 * output.1 * 3,
+        (false, _) => output.0 * output.1 * (3 + 16),
+    };
+
+    // Cells are counted in a `u32`, so no grid may have more even without `max_cells`
+    let max_cells = config.max_cells.unwrap_or(u32::MAX);
+    let over_cells = Some(max_cells).filter(|&max| grid.0 * grid.1 > max as u64);
+    let over_bytes = config.max_output_bytes.is_some_and(|max| bytes > max);
+    if over_cells.is_none() && !over_bytes {
+        return Ok(());
+    }
+
+    // Start from the side that scales the cell count down to the limit, then settle on
+    // the smallest that fits, since rounding up each row and column throws it off
+    let thumbsize = over_cells.map(|max| {
+        let fits = |side: u32| {
+            let (columns, rows) = cells((side, side));
+            columns * rows <= max as u64
+        };
+        let ratio = (grid.0 * grid.1) as f64 / max as f64;
+        let mut side = (thumbsize.0.max(thumbsize.1) as f64 * ratio.sqrt()).ceil() as u32;
+        while side > 1 && fits(side - 1) {
+            side -= 1;
+        }
+        while !fits(side) {
+            side += 1;
+        }
+        side
+    });
+
+    Err(Error::TooLarge {
+        grid,
+        output,
+        bytes,
+        thumbsize,
+    })
+}
+
+/// Size of the input after `scale` and then `max_dimension` are applied, or `None` if it
+/// stays as it is. Neither side drops below a pixel
+fn scaled_input_size(
+    (width, height): (u32, u32),
+    scale: Option<f32>,
+    max_dimension: Option<u32>,
+) -> Option<(u32, u32)> {
+    let mut factor = scale.unwrap_or(1.0) as f64;
+    if let Some(max_dimension) = max_dimension {
+        factor = factor.min(max_dimension as f64 / width.max(height) as f64);
+    }
+    if factor >= 1.0 {
+        return None;
+    }
+
+    let scaled = |side: u32| ((side as f64 * factor).round() as u32).max(1);
+    Some((scaled(width), scaled(height)))
+}
+
+/// The thumbsize whose grid over an image of `dimensions` comes closest to `target` cells
+fn suggest_thumbsize(dimensions: (u32, u32), target: f64, edge_cells: bool) -> u32 {
+    let distance = |thumbsize: u32| {
+        let (x_chunks, y_chunks) = grid_size(dimensions, (thumbsize, thumbsize), edge_cells);
+        // Compare ratios so being twice too many is as bad as half too few
+        ((x_chunks * y_chunks) as f64 / target).ln().abs()
+    };
+
+    (1..=dimensions.0.min(dimensions.1))
+        .min_by(|&a, &b| distance(a).total_cmp(&distance(b)))
+        .unwrap_or(1)
+}
+
+/// Number of cells across and down an image of `dimensions`, including partial ones
+/// at the edges if `edge_cells` is set
+fn grid_size(
+    (width, height): (u32, u32),
+    (cell_width, cell_height): (u32, u32),
+    edge_cells: bool,
+) -> (u32, u32) {
+    if edge_cells {
+        (width.div_ceil(cell_width), height.div_ceil(cell_height))
+    } else {
+        (width / cell_width, height / cell_height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gravity_picks_the_kept_region() {
+        // A 16x12 image trimmed to 10x8 cells of 5x4 has 6 columns and 4 rows to spare
+        let excess = (16 - 10, 12 - 8);
+        let origins = [
+            (Gravity::Center, (3, 2)),
+            (Gravity::Top, (3, 0)),
+            (Gravity::Bottom, (3, 4)),
+            (Gravity::Left, (0, 2)),
+            (Gravity::Right, (6, 2)),
+            (Gravity::TopLeft, (0, 0)),
+            (Gravity::TopRight, (6, 0)),
+            (Gravity::BottomLeft, (0, 4)),
+            (Gravity::BottomRight, (6, 4)),
+        ];
+
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 12, |x, y| {
+            image::Rgb([x as u8, y as u8, 0])
+        }));
+        for (gravity, origin) in origins {
+            assert_eq!(gravity.origin(excess), origin, "{gravity:?}");
+
+            let (x, y) = origin;
+            let kept = image.crop_imm(x, y, 10, 8).to_rgb8();
+            assert_eq!(kept.get_pixel(0, 0).0, [x as u8, y as u8, 0]);
+            assert_eq!(kept.get_pixel(9, 7).0, [x as u8 + 9, y as u8 + 7, 0]);
+        }
+    }
+
+    #[test]
+    fn gravity_places_padded_images() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, image::Rgb([255; 3])));
+        let padding = Padding::Color([0; 3]);
+
+        for (gravity, (x, y)) in [
+            (Gravity::Center, (1, 1)),
+            (Gravity::TopLeft, (0, 0)),
+            (Gravity::BottomRight, (2, 2)),
+        ] {
+            let padded = pad_image(&image, (4, 4), gravity, padding).to_rgb8();
+            assert_eq!(padded.get_pixel(x, y).0, [255; 3], "{gravity:?}");
+            assert_eq!(padded.get_pixel(x + 1, y + 1).0, [255; 3], "{gravity:?}");
+            let white = padded.pixels().filter(|p| p.0 == [255; 3]).count();
+            assert_eq!(white, 4, "{gravity:?}");
+        }
+    }
+
+    #[test]
+    fn grids_past_u32_cells_are_too_large_even_without_a_limit() {
+        let config = Config {
+            max_cells: None,
+            max_output_bytes: None,
+            ..Config::default()
+        };
+
+        assert!(check_size(&config, (65_535, 65_535), (1, 1)).is_ok());
+        assert!(matches!(
+            check_size(&config, (65_536, 65_536), (1, 1)),
+            Err(Error::TooLarge {
+                grid: (65_536, 65_536),
+                ..
+            })
+        ));
+    }
+}
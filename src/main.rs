@@ -1,405 +1,1881 @@
 use std::{
     collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
     fs,
-    io::{Cursor, Write},
-    iter::zip,
-    path::Path,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
     process::exit,
-    sync::OnceLock,
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
-use image::{DynamicImage, GenericImageView, ImageReader, RgbImage};
-use oklab::{Oklab, srgb_to_oklab};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, parser::ValueSource};
+use image::{
+    DynamicImage, Frame, ImageFormat,
+    codecs::gif::{GifEncoder, Repeat},
+};
+use imagegrid::{
+    AssignMode, AssignmentMap, Config, DbFormat, DifferenceFunction, Error, FlipMode, Gravity,
+    GridFit, MatchMode, Mosaic, OutputFormat, Padding, Profile, Report, ResizeFilter, SampleStat,
+    SampleWeights, StripWriter, SvgLinks, WhiteBalance, assignment_map_svg, compact_thumbs_db,
+    generate_animation, generate_mosaic, generate_mosaics, index_thumbs, init_logger,
+    read_assignment_map, read_thumbs_db, render_assignment_map, render_assignment_strips,
+    rendered_size, save_image, set_quiet, thumb_data_path, write_assignment_map, write_thumbs_db,
+};
 use serde::{Deserialize, Serialize};
-use tokio::task::{self};
 
 #[derive(Parser, Debug)]
-#[command(version, about)]
-struct Args {
-    /// The input image
-    image: String,
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = "Examples:
+  imagegrid photo.jpg mosaic.png --thumbs \"/media/**/*.jpg\" -T 48
+  imagegrid index --thumbs \"/media/**/*.jpg\" --sampleres 6
+  imagegrid photo.jpg --thumbs \"/media/**/*.jpg\" --dump-config > settings.json
+  imagegrid --config settings.json other.jpg"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Render arguments, used when no subcommand is given
+    #[command(flatten)]
+    render: RenderArgs,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Build or update the thumbnail database and exit
+    Index(IndexArgs),
+    /// Render a mosaic (the default)
+    Render(Box<RenderArgs>),
+    /// Print statistics about the thumbnail database
+    Inspect(InspectArgs),
+    /// Rewrite the thumbnail database without entries for deleted files or bands that
+    /// aren't needed, and exit
+    Compact(CompactArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct CompactArgs {
+    /// Thumbnail database to compact [default: ./thumbdata]
+    #[arg(long, value_name = "PATH")]
+    db_path: Option<PathBuf>,
+
+    /// Keep the bands at these resolutions as well as each thumbnail's highest, e.g. the
+    /// --sampleres renders use, so they aren't imported again
+    #[arg(long, value_name = "RES", value_delimiter = ',')]
+    keep_res: Vec<u32>,
+
+    /// Write the compacted database as RON or bincode [default: its current format]
+    #[arg(long, value_enum)]
+    db_format: Option<DbFormat>,
+}
+
+#[derive(clap::Args, Debug)]
+struct InspectArgs {
+    /// Thumbnail database to inspect [default: ./thumbdata]
+    #[arg(long, value_name = "PATH")]
+    db_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IndexArgs {
+    /// Glob of thumbnails to index. Repeat to merge several folders or file types
+    #[arg(short, long, default_values_t = [String::from("./thumbnails/**/*.jpg")])]
+    #[serde(deserialize_with = "one_or_many")]
+    thumbs: Vec<String>,
+
+    /// Sampling resolution of image thumbnails
+    #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=MAX_SAMPLERES as i64))]
+    sampleres: u32,
+
+    /// Sample newly imported thumbnails at these resolutions too, from the same decode,
+    /// so switching --sampleres or --matchres between them needs no new import. Pass
+    /// just the --sampleres value to keep the database small
+    #[arg(long, value_name = "RES", value_delimiter = ',', value_parser = clap::value_parser!(u32).range(1..=MAX_SAMPLERES as i64), default_values_t = [2, 4, 8])]
+    import_res: Vec<u32>,
+
+    /// How each sample is reduced from the pixels it covers
+    #[arg(long, value_enum, default_value_t = SampleStat::Mean)]
+    sample_stat: SampleStat,
+
+    /// Filter used whenever images are resized. Nearest makes importing much faster at
+    /// low sampleres. Thumbnails are re-imported when it changes, since their cached
+    /// colours depend on it
+    #[arg(long, value_enum, default_value_t = ResizeFilter::CatmullRom)]
+    filter: ResizeFilter,
+
+    /// Re-import every thumbnail, ignoring what's cached in the database
+    #[arg(long)]
+    force_reimport: bool,
+
+    /// Import and match on at most this many threads [default: one per logical CPU]. 1
+    /// keeps the machine responsive and processes cells strictly in order
+    #[arg(short = 'j', long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    threads: Option<usize>,
+
+    /// Don't draw progress bars, for scripts. They're drawn on stderr, and only when it's
+    /// a terminal
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Log each imported thumbnail and skipped file, or with -vv each cell's match and
+    /// score too. Logs go to stderr, and RUST_LOG overrides this when it's set
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Store the thumbnail database as RON or as compact bincode, converting the existing
+    /// one [default: keep its current format, RON for a new database]
+    #[arg(long, value_enum)]
+    db_format: Option<DbFormat>,
+
+    /// Keep the thumbnail database in this file instead of ./thumbdata, e.g. one per
+    /// collection. Missing parent directories are created
+    #[arg(long, value_name = "PATH")]
+    db_path: Option<PathBuf>,
+
+    /// Leave out thumbnails whose shorter side is below this many pixels, such as icons
+    /// that would be blown up into blurry tiles. Checked on every run, so indexed
+    /// thumbnails that fall outside the limit are dropped from the database
+    #[arg(long, value_name = "PIXELS")]
+    min_dimension: Option<u32>,
+
+    /// Leave out thumbnails whose longer side is above this many pixels
+    #[arg(long, value_name = "PIXELS")]
+    max_dimension: Option<u32>,
+
+    /// Leave out near duplicates such as burst shots, keeping the thumbnail indexed first.
+    /// Each one left out is listed with the thumbnail it duplicates, and remembered in the
+    /// database. They're indexed again on a run without it
+    #[arg(long)]
+    dedupe: bool,
+
+    /// How many of the 64 bits of two thumbnails' perceptual hashes may differ for them to
+    /// count as duplicates with --dedupe. 0 only catches copies
+    #[arg(long, value_name = "BITS", default_value_t = 5, value_parser = clap::value_parser!(u32).range(0..=32))]
+    dedupe_threshold: u32,
+
+    /// Composite transparent thumbnails over this colour, given as #rrggbb or r,g,b, for
+    /// both matching and placing them. Without it their alpha is dropped, which shows
+    /// whatever colour their transparent pixels happen to hold
+    #[arg(long, value_name = "COLOR", value_parser = parse_color)]
+    alpha_background: Option<[u8; 3]>,
+
+    /// Read the input and thumbnails as stored, instead of turning photos upright as their
+    /// EXIF orientation says. Thumbnails it turns are imported again when this changes
+    #[arg(long)]
+    ignore_exif: bool,
+
+    /// Even out the colour casts of thumbnails shot under different light before
+    /// sampling and placing them, so matching compares like with like. `gray-world`
+    /// makes each thumbnail average out to grey, `white-patch` makes its brightest
+    /// pixels neutral. Each setting is sampled and cached separately
+    #[arg(long, value_enum, default_value_t = WhiteBalance::None)]
+    normalize: WhiteBalance,
+}
+
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RenderArgs {
+    /// The input image, or - to read it from stdin. A directory or quoted glob mosaics
+    /// every image it holds against the same index
+    #[arg(required_unless_present_any = ["config", "dump_config", "regenerate_from_manifest"])]
+    image: Option<String>,
 
     /// The output image
+    #[arg(conflicts_with = "output_path")]
     output: Option<String>,
 
-    /// Thumbnail directory
-    #[arg(short, long, default_value_t = String::from("./thumbnails/**/*.jpg"))]
-    thumbs: String,
+    /// Write the output image to exactly this path. The format follows the extension
+    /// unless --format is given. With several input images, the directory to write their
+    /// outputs to [default: <image>.output.<ext> in the current directory]
+    #[arg(short = 'o', long = "output", value_name = "PATH")]
+    output_path: Option<String>,
+
+    /// Replace the output if it already exists, instead of stopping before any work
+    #[arg(long, conflicts_with = "auto_increment")]
+    overwrite: bool,
 
-    /// Size of the thumbnail grid in pixels
-    #[arg(short = 'T', long, default_value_t = 32)]
+    /// Add a number to the output's name if it already exists, e.g.
+    /// photo.output-1.jpg, instead of stopping before any work
+    #[arg(long)]
+    auto_increment: bool,
+
+    /// Encode the output in this format whatever its file extension. The default output
+    /// name then ends in it too
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    #[command(flatten)]
+    index: IndexArgs,
+
+    /// Size of the thumbnail grid in pixels, sets both the width and height of cells
+    #[arg(short = 'T', long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..=MAX_THUMBSIZE as i64))]
     thumbsize: u32,
 
-    /// Sampling resolution of image thumbnails
-    #[arg(short, long, default_value_t = 4)]
-    sampleres: u32,
+    /// Width of grid cells, for rectangular cells [default: thumbsize]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=MAX_THUMBSIZE as i64))]
+    thumb_width: Option<u32>,
+
+    /// Height of grid cells, for rectangular cells [default: thumbsize]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=MAX_THUMBSIZE as i64))]
+    thumb_height: Option<u32>,
+
+    /// Pick the thumbsize for the input so there are about this many cells per
+    /// available thumbnail [default: 2]
+    #[arg(
+        long,
+        num_args = 0..=1,
+        default_missing_value = "2",
+        conflicts_with_all = ["thumbsize", "thumb_width", "thumb_height", "match_mode"]
+    )]
+    auto_thumbsize: Option<f64>,
+
+    /// Resize the input to exactly COLSxROWS cells instead of cropping it to a multiple
+    /// of the thumbsize, for a fixed tile count
+    #[arg(long, value_name = "COLSxROWS", value_parser = parse_grid, conflicts_with_all = ["auto_thumbsize", "edge_cells"])]
+    grid: Option<(u32, u32)>,
+
+    /// How --grid handles an input of a different aspect ratio
+    #[arg(long, value_enum, default_value_t = GridFit::Stretch, requires = "grid")]
+    grid_fit: GridFit,
+
+    /// Which part of the input to keep when cropping it to a multiple of the thumbsize, or
+    /// where to place it with --pad
+    #[arg(long, value_enum, default_value_t = Gravity::Center)]
+    gravity: Gravity,
+
+    /// Sampling resolution chunks are matched at, against each thumbnail's band stored at
+    /// that resolution. New thumbnails are sampled at it too. Older ones without it are
+    /// resampled down from the --sampleres band, or above --sampleres have to be imported
+    /// again [default: sampleres]
+    #[arg(short, long, visible_alias = "chunk-sampleres", value_parser = clap::value_parser!(u32).range(1..=MAX_SAMPLERES as i64))]
+    matchres: Option<u32>,
+
+    /// What chunks and thumbnails are reduced to before comparing them
+    #[arg(long, value_enum, default_value_t = MatchMode::Sampled, conflicts_with = "matchres")]
+    match_mode: MatchMode,
+
+    /// Sampling resolution of a fast first matching pass. Only the best --coarse-top
+    /// candidates from it are rescored at matchres
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=MAX_SAMPLERES as i64))]
+    coarse_res: Option<u32>,
+
+    /// How many candidates the coarse pass keeps for the fine pass
+    #[arg(long, default_value_t = 32, requires = "coarse_res")]
+    coarse_top: usize,
+
+    /// Find each chunk's best match through a k-d tree over the thumbnail colours instead
+    /// of scoring every thumbnail. Picks the same thumbnails, and can be faster on very
+    /// large collections (compare with --profile)
+    #[arg(long, conflicts_with_all = ["usage_penalty", "no_repeat_radius", "max_uses", "top_n", "coarse_res"])]
+    spatial_index: bool,
+
+    /// Score every thumbnail against every cell. Collections of 4096 or more thumbnails
+    /// are otherwise grouped by average colour, and each cell is only scored against
+    /// the thumbnails in and around its colour's group. That's much faster, but now and
+    /// then misses the best match
+    #[arg(long)]
+    exact: bool,
 
     /// Resolution multiplier for final image (warning: multiplies image resolution!)
-    #[arg(short, long, default_value_t = 1)]
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=MAX_DPR as i64))]
     dpr: u32,
 
     /// Which algorithm is used to assign thumbnails
     #[arg(short, long, value_enum, default_value_t = DifferenceFunction::Oklab)]
     algorithm: DifferenceFunction,
+
+    /// Keep the full frame by extending the grid over partial cells at the right and
+    /// bottom edges instead of cropping. Edge cells are matched on a mirrored copy of the
+    /// pixels they cover and their thumbnails are clipped, so they fit less faithfully
+    #[arg(long)]
+    edge_cells: bool,
+
+    /// Keep the full frame by padding the input up to the next multiple of the thumbsize
+    /// instead of cropping, placed as --gravity says. Cells over the padding match
+    /// thumbnails of the --pad-color
+    #[arg(long, conflicts_with_all = ["edge_cells", "grid"])]
+    pad: bool,
+
+    /// What --pad fills the margin with: a colour as #rrggbb or r,g,b, or "edge" to
+    /// repeat the input's edge pixels outwards
+    #[arg(long, value_name = "COLOR", value_parser = parse_padding, default_value = "#000000", requires = "pad")]
+    pad_color: Padding,
+
+    /// Resize each placed thumbnail straight from its full resolution decode with a
+    /// high quality filter, cropping rather than stretching it to the cell (for prints)
+    #[arg(long)]
+    native_tiles: bool,
+
+    /// Render a rough mosaic quickly to judge the composition: tiles are placed at a dpr
+    /// of 1 and resized with nearest neighbour, but matched as usual. Writes
+    /// <input>.preview.<ext> unless -o is given
+    #[arg(long, conflicts_with_all = ["dpr", "native_tiles"])]
+    preview: bool,
+
+    /// Keep at most this many resized tiles in memory while placing, dropping the least
+    /// recently placed. Unbounded by default, which can take gigabytes at a high --dpr
+    #[arg(long, value_name = "TILES")]
+    cache_size: Option<usize>,
+
+    /// Composite and write the output this many grid rows at a time, so only one band of
+    /// it is ever in memory (pair with --cache-size to bound the tiles too). PNG and PPM
+    /// are encoded band by band, other formats are still assembled before saving
+    #[arg(long, value_name = "ROWS", value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = ["blend", "match_brightness", "overlap"])]
+    strip_rows: Option<u32>,
+
+    /// Pull each placed tile this far toward the average colour of the input cell it
+    /// covers, from 0 (untouched tiles) to 1 (the flat downscaled input). Mixed in linear
+    /// light so the blend doesn't turn muddy
+    #[arg(long, value_parser = parse_blend, default_value_t = 0.0)]
+    blend: f32,
+
+    /// Scale each placed tile's brightness so its average matches the input cell it
+    /// covers. This changes how the thumbnails look, but follows the input much more
+    /// closely when the collection lacks the right tones
+    #[arg(long)]
+    match_brightness: bool,
+
+    /// With --match-brightness, also scale each tile's contrast to its cell's
+    #[arg(long, requires = "match_brightness")]
+    match_contrast: bool,
+
+    /// Largest factor --match-brightness and --match-contrast scale a tile by, either
+    /// way, so tiles aren't distorted beyond recognition
+    #[arg(long, value_name = "FACTOR", value_parser = parse_max_adjust, default_value_t = 2.0)]
+    max_adjust: f32,
+
+    /// Draw each tile this many output pixels past its cell on every side, fading it into
+    /// its neighbours so the grid lines soften. Tiles lose a little sharpness at their edges
+    /// in exchange. At most half the tile's shorter side, and 0 (edge to edge) by default
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    overlap: u32,
+
+    /// Draw a line this many output pixels wide round the inside of every tile, for a
+    /// deliberately gridded poster look. Lines between tiles come out twice as wide as
+    /// the frame round the edge. The width doesn't change with --dpr, so scale it along
+    /// to keep the same look. 0 (no lines) by default
+    #[arg(long, value_name = "WIDTH", default_value_t = 0)]
+    tile_border: u32,
+
+    /// Colour of the --tile-border lines, as #rrggbb or r,g,b
+    #[arg(long, value_name = "COLOR", value_parser = parse_color, default_value = "#000000", requires = "tile_border")]
+    tile_border_color: [u8; 3],
+
+    /// Turn placed tiles into greys of the same luminance, for a black and white mosaic.
+    /// Tiles are still matched by colour unless --grayscale-match is given too
+    #[arg(long, group = "tone")]
+    grayscale: bool,
+
+    /// Like --grayscale but map each tile's luminance onto the range from one colour in
+    /// the shadows to another in the highlights, each given as #rrggbb or r,g,b
+    #[arg(long, num_args = 2, value_names = ["SHADOWS", "HIGHLIGHTS"], value_parser = parse_color, group = "tone")]
+    duotone: Option<Vec<[u8; 3]>>,
+
+    /// With --grayscale or --duotone, match on luminance alone rather than colour, so
+    /// tiles are picked for their tones
+    #[arg(long, requires = "tone")]
+    grayscale_match: bool,
+
+    /// Gaussian blur sigma applied to the input before matching, to reduce noise
+    /// sensitivity. Above 0, up to 100
+    #[arg(long, value_parser = parse_pre_blur)]
+    pre_blur: Option<f32>,
+
+    /// Scale the input's saturation by this factor (0 to 4) before matching, so more vivid
+    /// or more muted thumbnails get picked. The input file and thumbnails are untouched
+    #[arg(long, value_name = "FACTOR", value_parser = parse_pre_adjust, default_value_t = 1.0)]
+    pre_saturation: f32,
+
+    /// Scale the input's contrast by this factor (0 to 4) before matching, spreading its
+    /// lightness away from the average, so tiles with stronger lights and darks get picked
+    #[arg(long, value_name = "FACTOR", value_parser = parse_pre_adjust, default_value_t = 1.0)]
+    pre_contrast: f32,
+
+    /// Scale the input by this factor (above 0, up to 1) right after decoding, so huge
+    /// photos don't turn into millions of cells. The grid and output follow the scaled size
+    #[arg(long, value_parser = parse_input_scale)]
+    input_scale: Option<f32>,
+
+    /// Scale the input down so its longer side is at most this many pixels, right after
+    /// decoding. Smaller inputs are left alone
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_input_dimension: Option<u32>,
+
+    /// Stop before matching if the grid has more cells than this, or the output would take
+    /// more than 4 GiB, and suggest a thumbsize that fits. A small thumbsize against a
+    /// large input, or a high --dpr, can otherwise run for hours or exhaust memory
+    #[arg(long, value_name = "N", default_value_t = 500_000)]
+    max_cells: u32,
+
+    /// Go ahead with grids and outputs over the --max-cells and output size limits
+    #[arg(long, short = 'y')]
+    yes: bool,
+
+    /// Drop the input image itself from the thumbnail pool. Useful when --thumbs covers
+    /// the folder the input lives in, which otherwise tiles the input with copies of itself
+    #[arg(long)]
+    exclude_self: bool,
+
+    /// Add this much to a thumbnail's score for every time it has already been placed,
+    /// spreading usage over the whole collection. The penalty is in units of the chosen
+    /// algorithm's score, and cells are assigned one at a time in raster order
+    #[arg(long)]
+    usage_penalty: Option<f64>,
+
+    /// Never place a thumbnail within this many cells (in any direction, diagonals
+    /// included) of another copy of itself, using the next best match instead. Larger
+    /// radii break up flat areas more but move more cells away from their best match
+    #[arg(long)]
+    no_repeat_radius: Option<u32>,
+
+    /// Place each thumbnail at most this many times, moving on to the next best match
+    /// once one is used up. Fails if the pool can't fill the grid under the cap
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_uses: Option<u32>,
+
+    /// How cells get their thumbnails once every cell is scored. `global` spreads the
+    /// whole pool over the grid for the lowest total score it finds, placing each
+    /// thumbnail at most once when there are as many thumbnails as cells, evenly often
+    /// when there are fewer, or up to --max-uses times. It keeps every cell's score for
+    /// every thumbnail in memory and takes time quadratic in the cell count
+    #[arg(long, value_enum, default_value_t = AssignMode::Greedy, conflicts_with_all = ["usage_penalty", "no_repeat_radius", "top_n", "dither", "spatial_index", "coarse_res"])]
+    assign: AssignMode,
+
+    /// Place one of the N closest matches at random instead of always the closest,
+    /// favouring closer ones, to break up repetition in flat areas. Picks are made in
+    /// raster order, so a fixed --seed reproduces the same mosaic
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    top_n: Option<usize>,
+
+    /// Keep the input itself, scaled up, in cells whose best match still scores above
+    /// this, so a face isn't broken up by a tile that looks nothing like it. Scores are
+    /// on the chosen algorithm's scale, as --report shows them
+    #[arg(long, value_name = "SCORE", conflicts_with = "strip_rows")]
+    fallback_threshold: Option<f64>,
+
+    /// Also try every thumbnail mirrored left to right (or with `both`, top to bottom and
+    /// both ways too) and place it in whichever orientation fits the cell best. Matching
+    /// takes twice (or four times) as long
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "horizontal", conflicts_with_all = ["spatial_index", "dither"])]
+    allow_flip: Option<FlipMode>,
+
+    /// Also try every thumbnail turned a quarter, half and three quarters of the way
+    /// round, which helps textured thumbnails fit. With --allow-flip all eight
+    /// orientations are tried. Only works with square cells
+    #[arg(long, conflicts_with_all = ["spatial_index", "dither"])]
+    allow_rotate: bool,
+
+    /// Match cells in raster order, diffusing each cell's colour error onto its
+    /// unmatched neighbours (Floyd-Steinberg) so groups of cells reproduce colours no
+    /// single thumbnail has. Cells are matched one at a time, so this is slower
+    #[arg(long, conflicts_with_all = ["usage_penalty", "no_repeat_radius", "max_uses", "top_n", "coarse_res"])]
+    dither: bool,
+
+    /// Only match against this many thumbnails from the database, the first by path
+    /// unless --seed picks a random sample. Useful for quick tests, or to see how a
+    /// smaller collection holds up. The database itself keeps every thumbnail
+    #[arg(long, visible_alias = "limit", value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    limit_thumbs: Option<usize>,
+
+    /// Seed used to pick a random sample for --limit-thumbs instead of the first N by
+    /// path, and for the --top-n picks [default: random for --top-n]
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Resume from an assignment map written by an earlier (e.g. interrupted) run
+    #[arg(long)]
+    resume: Option<String>,
+
+    /// While matching, write the cells assigned so far to <output>.partial.ron every SECS
+    /// seconds, to --resume from if the render dies. Only plain greedy matching assigns
+    /// cells as it goes, so with other assignment options nothing is written
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    checkpoint: Option<u64>,
+
+    /// Scale factors for the Oklab L, a and b channels before comparison. The defaults
+    /// roughly equalise the channel ranges over sRGB (L spans ~1.0, a and b ~0.5 each)
+    #[arg(long, value_parser = parse_weights, default_value = "1,2,2")]
+    lab_weights: [f32; 3],
+
+    /// Extra factor on the L weight, above 1 to favour matching brightness over hue when
+    /// the thumbnails' palette is limited, below 1 to favour colour
+    #[arg(long, value_parser = parse_weight, default_value_t = 1.0)]
+    luma_weight: f32,
+
+    /// How much each sample in a cell counts. Gaussian favours the centre of each cell,
+    /// for subjects whose detail sits there. Doesn't need thumbnails indexed again
+    #[arg(long, value_enum, default_value_t = SampleWeights::Flat, conflicts_with = "spatial_index")]
+    sample_weights: SampleWeights,
+
+    /// Experimental: how much differences in detail count next to differences in colour,
+    /// from 0 to 10, so busy cells like eyes and text get busy thumbnails and flat ones
+    /// get flat ones. At 1, the largest possible difference in detail counts as much as
+    /// white against black. Thumbnails indexed before detail was recorded are imported
+    /// again
+    #[arg(long, value_name = "W", value_parser = parse_weight, default_value_t = 0.0, conflicts_with = "spatial_index")]
+    structure_weight: f32,
+
+    /// Also write an SVG that references each placed thumbnail file at its grid position.
+    /// The thumbnails are linked by absolute path, so they must stay where they are. An
+    /// output path ending in .svg writes only the SVG, without compositing anything
+    #[arg(long)]
+    svg: Option<String>,
+
+    /// Embed each thumbnail in the SVG output as a data URI, once however often it's
+    /// placed, so the SVG works on its own
+    #[arg(long, conflicts_with = "svg_base")]
+    embed: bool,
+
+    /// Link thumbnails in the SVG output by their path relative to this directory, such as
+    /// the one the SVG is served from, instead of by absolute path
+    #[arg(long, value_name = "DIR")]
+    svg_base: Option<PathBuf>,
+
+    /// Match without compositing anything and print how well the thumbnails cover the
+    /// input: match distance statistics, how often thumbnails were reused, and the cells
+    /// that matched worst. No image is written
+    #[arg(long, conflicts_with_all = ["strip_rows", "preview", "regenerate_from_manifest"])]
+    report: bool,
+
+    /// Cells scoring above this count as poor matches in --report. Defaults to the score
+    /// of the worst 5% of cells
+    #[arg(long, value_name = "SCORE")]
+    report_threshold: Option<f64>,
+
+    /// Also write the --report as JSON to this path. Implies --report
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["strip_rows", "preview", "regenerate_from_manifest"])]
+    report_json: Option<String>,
+
+    /// Also write a manifest of the image size, cell size, grid and the thumbnail and
+    /// match score of every cell in raster order. Written as RON for a .ron path and JSON
+    /// otherwise, either of which --resume can read back
+    #[arg(long)]
+    manifest: Option<String>,
+
+    /// Also save the mosaic without its thumbnails to this path: every cell filled with
+    /// the average colour of the input it was matched against, at the same size and
+    /// --dpr, so the two line up pixel for pixel
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["strip_rows", "regenerate_from_manifest"])]
+    emit_flat: Option<String>,
+
+    /// Skip matching and composite the thumbnails recorded in a manifest again, e.g. at a
+    /// different --dpr or --filter. Writes <manifest>.output.png unless -o is given
+    #[arg(long, value_name = "MANIFEST", conflicts_with = "image")]
+    regenerate_from_manifest: Option<String>,
+
+    /// For an animated GIF input written to a GIF, only mosaic every Nth frame. Each kept
+    /// frame is shown for as long as the frames it replaces
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    frame_skip: u32,
+
+    /// Report how long each phase of the run took
+    #[arg(long)]
+    profile: bool,
+
+    /// JPEG or AVIF quality of the output image, from 1 to 100 [default: 75 for JPEG, 80 for
+    /// AVIF]. WebP output is always lossless, and other formats ignore it
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+
+    /// Don't record generation parameters in the output image metadata
+    #[arg(long)]
+    no_metadata: bool,
+
+    /// Read settings from a JSON file as written by --dump-config. Flags given on the
+    /// command line take precedence, and settings missing from the file keep their defaults
+    #[arg(long)]
+    #[serde(skip)]
+    config: Option<String>,
+
+    /// Print the effective settings as JSON and exit without rendering
+    #[arg(long)]
+    #[serde(skip)]
+    dump_config: bool,
 }
 
-#[derive(Debug, Clone, clap::ValueEnum)]
-enum DifferenceFunction {
-    /// Fast
-    Rgb,
-    /// Slower, More Accurate
-    Oklab,
+/// A single channel weight, from 0 (channel ignored) to 10
+fn parse_weight(s: &str) -> Result<f32, String> {
+    let weight: f32 = s
+        .trim()
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+
+    if (0.0..=10.0).contains(&weight) {
+        Ok(weight)
+    } else {
+        Err(String::from("expected a weight from 0 to 10"))
+    }
 }
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Hash)]
-struct ThumbnailData {
-    path: String,
-    res: u32,
-    colors: Vec<[u8; 3]>,
+fn parse_weights(s: &str) -> Result<[f32; 3], String> {
+    let weights: Vec<f32> = s.split(',').map(parse_weight).collect::<Result<_, _>>()?;
+
+    weights
+        .try_into()
+        .map_err(|_| String::from("expected three comma separated weights, e.g. 1,2,2"))
 }
 
-#[derive(Default, Serialize, Deserialize)]
-struct ThumbnailDb {
-    thumbs: HashSet<ThumbnailData>,
+fn parse_pre_blur(s: &str) -> Result<f32, String> {
+    let sigma: f32 = s
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+
+    if pre_blur_in_range(sigma) {
+        Ok(sigma)
+    } else {
+        Err(format!(
+            "expected a blur sigma above 0, up to {}",
+            MAX_PRE_BLUR
+        ))
+    }
 }
 
-static INPUT_IMAGE: OnceLock<RgbImage> = OnceLock::new();
-static THUMBS_DB: OnceLock<ThumbnailDb> = OnceLock::new();
-static COMPARISON_FN: OnceLock<DifferenceFunction> = OnceLock::new();
+/// Whether `sigma` is a blur --pre-blur takes, which NaN never is
+fn pre_blur_in_range(sigma: f32) -> bool {
+    sigma > 0.0 && sigma <= MAX_PRE_BLUR
+}
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
-    COMPARISON_FN.get_or_init(|| args.algorithm);
+fn parse_blend(s: &str) -> Result<f32, String> {
+    let blend: f32 = s
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
 
-    println!("Targeting {}!", args.image);
-    let mut thumbs_db = ThumbnailDb::default();
-    let mut thumbs_cache: HashMap<String, DynamicImage> = HashMap::new();
+    if (0.0..=1.0).contains(&blend) {
+        Ok(blend)
+    } else {
+        Err(String::from("expected a blend factor from 0 to 1"))
+    }
+}
 
-    let thumb_data_path = std::env::current_dir().unwrap().join("thumbdata");
+fn parse_max_adjust(s: &str) -> Result<f32, String> {
+    let factor: f32 = s
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
 
-    // Load thumbnail data from cache
-    if let Ok(thumb_data) = fs::read(&thumb_data_path) {
-        thumbs_db = ron::de::from_bytes(&thumb_data).expect("to deserialize thumbdata");
+    if (1.0..=16.0).contains(&factor) {
+        Ok(factor)
+    } else {
+        Err(String::from("expected a factor from 1 to 16"))
     }
+}
 
-    println!(
-        "Loaded data for {} thumbs from {:?}!",
-        &thumbs_db.thumbs.len(),
-        &thumb_data_path
-    );
+/// Accept a single string where a list is expected, as config files written before
+/// a field took several values have it
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
 
-    let mut dirty_thumbs_db = 0u32;
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
 
-    let mut dir = glob::glob(&args.thumbs).expect("to glob directory");
-    while let Some(Ok(thumb_entry)) = dir.next() {
-        let entry_path = String::from(
-            thumb_entry
-                .to_str()
-                .expect("to convert thumbnail path into a string"),
-        );
+fn parse_pre_adjust(s: &str) -> Result<f32, String> {
+    let factor: f32 = s
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
 
-        if thumbs_db
-            .thumbs
-            .iter()
-            .find(|&a| (a.path == entry_path) && (a.res == args.sampleres))
-            .is_none()
-        {
-            print!("\rProcessing new thumb {:?}", thumb_entry);
-            std::io::stdout().flush().unwrap(); // Ensure stdout is flushed
+    if (0.0..=4.0).contains(&factor) {
+        Ok(factor)
+    } else {
+        Err(String::from("expected a factor from 0 to 4"))
+    }
+}
+
+fn parse_input_scale(s: &str) -> Result<f32, String> {
+    let scale: f32 = s
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+
+    if scale > 0.0 && scale <= 1.0 {
+        Ok(scale)
+    } else {
+        Err(String::from("expected a scale above 0 and up to 1"))
+    }
+}
+
+fn parse_grid(s: &str) -> Result<(u32, u32), String> {
+    let invalid = || String::from("expected a grid like 40x30");
+    let (columns, rows) = s.split_once('x').ok_or_else(invalid)?;
+    let columns: u32 = columns.parse().map_err(|_| invalid())?;
+    let rows: u32 = rows.parse().map_err(|_| invalid())?;
+
+    if columns == 0 || rows == 0 {
+        return Err(String::from("the grid needs at least one column and row"));
+    }
+
+    Ok((columns, rows))
+}
+
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let invalid = || String::from("expected a colour like #ffffff or 255,255,255");
+    let s = s.trim();
+
+    let channels: Vec<u8> = if s.contains(',') {
+        s.split(',')
+            .map(|c| c.trim().parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?
+    } else {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        (0..6)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?
+    };
+
+    channels.try_into().map_err(|_| invalid())
+}
 
-            import_thumb(&entry_path, args.sampleres, &mut thumbs_db).expect("to import thumbnail");
-            dirty_thumbs_db += 1;
+fn parse_padding(s: &str) -> Result<Padding, String> {
+    match s.trim() {
+        "edge" => Ok(Padding::Edge),
+        color => parse_color(color)
+            .map(Padding::Color)
+            .map_err(|e| format!("{}, or edge", e)),
+    }
+}
+
+fn main() {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    let result = match cli.command {
+        Some(Command::Index(args)) => {
+            set_quiet(args.quiet);
+            init_logger(args.verbose);
+            index_thumbs(
+                &args.thumbs,
+                (args.db_path.as_deref(), args.db_format),
+                (
+                    (
+                        args.sampleres,
+                        args.sample_stat,
+                        args.filter,
+                        args.alpha_background,
+                        args.normalize,
+                    ),
+                    &args.import_res,
+                    args.ignore_exif,
+                ),
+                (
+                    args.min_dimension,
+                    args.max_dimension,
+                    args.dedupe.then_some(args.dedupe_threshold),
+                    false,
+                ),
+                args.force_reimport,
+                args.threads,
+                &mut Profile::default(),
+            )
+            .map(|thumbs_db| {
+                // Each thumbnail is stored once per resolution it was sampled at
+                let thumbs: HashSet<&str> = thumbs_db
+                    .thumbs
+                    .iter()
+                    .map(|thumb| thumb.path.as_str())
+                    .collect();
+                println!(
+                    "Thumbnail database holds {} thumbs ({} entries)",
+                    thumbs.len(),
+                    thumbs_db.thumbs.len()
+                );
+            })
+        }
+        Some(Command::Render(args)) => {
+            let matches = matches.subcommand_matches("render").unwrap();
+            resolve_config(*args, matches).and_then(render)
         }
+        Some(Command::Inspect(args)) => inspect_thumbs(args),
+        Some(Command::Compact(args)) => compact_thumbs(args),
+        None => resolve_config(cli.render, &matches).and_then(render),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(match e {
+            Error::Io(..) | Error::Image(..) => 2,
+            Error::ResumeMismatch { .. } => 3,
+            Error::Interrupted => 130,
+            _ => 1,
+        });
     }
+}
 
-    if dirty_thumbs_db > 0 {
-        fs::write(thumb_data_path, ron::ser::to_string(&thumbs_db).unwrap())
-            .expect("to write thumbdata");
-        println!(
-            "Processed {} new thumbs!                                                  ",
-            dirty_thumbs_db
+/// Layer the flags explicitly given on the command line over `args.config`, if any
+fn resolve_config(args: RenderArgs, matches: &ArgMatches) -> Result<RenderArgs, Error> {
+    let Some(config_path) = args.config.as_deref() else {
+        return Ok(args);
+    };
+
+    let path = Path::new(config_path);
+    let config = fs::read_to_string(path).map_err(|e| Error::Io(path.into(), e))?;
+    let mut config: serde_json::Value =
+        serde_json::from_str(&config).map_err(|e| Error::Json(path.into(), e))?;
+    let explicit = serde_json::to_value(&args).expect("render args always serialize");
+    merge_explicit_args(&mut config, explicit, matches);
+
+    let mut resolved: RenderArgs =
+        serde_json::from_value(config).map_err(|e| Error::Json(path.into(), e))?;
+    resolved.dump_config = args.dump_config;
+    check_config_ranges(&resolved, config_path);
+    Ok(resolved)
+}
+
+/// Exit naming the first size or blur setting `config_path` gave out of the range its
+/// flag accepts, since values read from a config file don't go through clap's checks
+fn check_config_ranges(args: &RenderArgs, config_path: &str) {
+    let index = &args.index;
+    let settings = [
+        ("thumbsize", Some(args.thumbsize), MAX_THUMBSIZE),
+        ("thumb-width", args.thumb_width, MAX_THUMBSIZE),
+        ("thumb-height", args.thumb_height, MAX_THUMBSIZE),
+        ("sampleres", Some(index.sampleres), MAX_SAMPLERES),
+        ("matchres", args.matchres, MAX_SAMPLERES),
+        ("coarse-res", args.coarse_res, MAX_SAMPLERES),
+        ("dpr", Some(args.dpr), MAX_DPR),
+    ];
+    let import_res = index
+        .import_res
+        .iter()
+        .map(|&res| ("import-res", Some(res), MAX_SAMPLERES));
+
+    for (flag, value, max) in settings.into_iter().chain(import_res) {
+        if let Some(value) = value
+            && !(1..=max).contains(&value)
+        {
+            eprintln!(
+                "--{} in '{}' must be from 1 to {}, not {}",
+                flag, config_path, max, value
+            );
+            exit(2);
+        }
+    }
+
+    if let Some(sigma) = args.pre_blur
+        && !pre_blur_in_range(sigma)
+    {
+        eprintln!(
+            "--pre-blur in '{}' must be above 0, up to {}, not {}",
+            config_path, MAX_PRE_BLUR, sigma
         );
+        exit(2);
     }
+}
 
-    if thumbs_db.thumbs.len() < 2 {
-        eprintln!("Not enough thumbnails found in {}", &args.thumbs);
-        exit(1i32);
+/// Copy over command line values into `config`, keeping config values for any
+/// argument that was only defaulted
+fn merge_explicit_args(
+    config: &mut serde_json::Value,
+    cli: serde_json::Value,
+    matches: &ArgMatches,
+) {
+    let (serde_json::Value::Object(config), serde_json::Value::Object(cli)) = (config, cli) else {
+        return;
+    };
+
+    for (key, value) in cli {
+        match config.get_mut(&key) {
+            // Flattened argument groups such as `index`
+            Some(nested @ serde_json::Value::Object(_)) => {
+                merge_explicit_args(nested, value, matches)
+            }
+            Some(existing) => {
+                if matches.value_source(&key) == Some(ValueSource::CommandLine) {
+                    *existing = value;
+                }
+            }
+            None => {
+                config.insert(key, value);
+            }
+        }
     }
+}
 
-    // Filter thumbs to current sample resolution
-    thumbs_db.thumbs = thumbs_db
-        .thumbs
-        .extract_if(|ThumbnailData { res, .. }| args.sampleres.eq(res))
-        .collect();
+/// Outputs larger than this stop before matching unless --yes is given
+const MAX_OUTPUT_BYTES: u64 = 4 << 30;
+
+/// Largest cell width or height --thumbsize, --thumb-width and --thumb-height take
+const MAX_THUMBSIZE: u32 = 4096;
+
+/// Largest sampling resolution, which stores its square in colours per thumbnail
+const MAX_SAMPLERES: u32 = 64;
 
-    // Lock thumbs_db
-    THUMBS_DB.get_or_init(|| thumbs_db);
+/// Largest --dpr, already far past the detail of any thumbnail a tile is drawn from
+const MAX_DPR: u32 = 64;
 
-    // Load the target image
-    let raw_image = fs::read(&args.image);
+/// Largest --pre-blur sigma, already enough to wash out anything a cell could match on
+const MAX_PRE_BLUR: f32 = 100.0;
 
-    if let Err(e) = raw_image {
-        eprintln!("Error loading image '{}': {}", &args.image, e);
-        exit(2i32);
+const PREVIEW_BANNER: &str =
+    "PREVIEW: tiles placed at dpr 1 with nearest neighbour resizing, not a final render";
+
+fn render(args: RenderArgs) -> Result<(), Error> {
+    if args.dump_config {
+        println!("{}", serde_json::to_string_pretty(&args).unwrap());
+        return Ok(());
     }
-    let raw_image = raw_image.unwrap();
+    set_quiet(args.index.quiet);
+    init_logger(args.index.verbose);
 
-    let reader = ImageReader::new(Cursor::new(raw_image))
-        .with_guessed_format()
-        .expect("Cursor io never fails");
+    if args.preview {
+        eprintln!("{}", PREVIEW_BANNER);
+    }
 
-    // Figure out where we want to write the output image
-    let original_path = Path::new(&args.image);
+    if let Some(manifest_path) = args.regenerate_from_manifest.as_deref() {
+        return regenerate(&args, manifest_path);
+    }
 
-    let mut working_path: std::path::PathBuf;
-    let output_path = match args.output.as_ref() {
-        Some(p) => Path::new(p),
+    let Some(input) = args.image.as_deref() else {
+        eprintln!("No input image given on the command line or in the config file");
+        exit(2);
+    };
 
-        None => {
-            let output_dir = std::env::current_dir().unwrap();
-            let output_name = original_path.file_prefix().unwrap().to_str().unwrap();
-            let output_ext = original_path.extension().unwrap();
-
-            working_path = output_dir
-                .join(output_name)
-                .with_extension("output")
-                .with_added_extension(output_ext);
-
-            // If the filename already exists try adding a number until it works
-            let mut dup_num = 1u32;
-            while working_path.exists() {
-                working_path = output_dir
-                    .join(output_name)
-                    .with_extension(format!("output-{}", dup_num))
-                    .with_added_extension(output_ext);
-                dup_num += 1;
-            }
+    if let Some(inputs) = batch_inputs(input)? {
+        return render_batch(&args, input, &inputs);
+    }
 
-            working_path.as_path()
-        }
+    log::info!("Targeting {}!", input);
+
+    let original_path = Path::new(input);
+    let input_ext = original_path.extension().unwrap_or(OsStr::new("png"));
+    let output_path = output_path(&args, original_path, input_ext)?;
+    let format = output_format(&args, &output_path);
+    warn_unused_quality(&args, format, &output_path);
+    warn_unused_svg_links(&args);
+
+    let mut config = mosaic_config(&args, input)?;
+
+    if args.report || args.report_json.is_some() {
+        return report(&args, &config);
+    }
+    config.checkpoint = args
+        .checkpoint
+        .map(|secs| (partial_map_path(&output_path), Duration::from_secs(secs)));
+    if svg_output(&args) {
+        return render_svg(&args, &config, &output_path);
+    }
+
+    let is_gif = |path: &Path| {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"))
     };
+    if is_gif(original_path) && format == Some(ImageFormat::Gif) {
+        return render_animation(&args, &config, &output_path);
+    }
 
-    let mut image = reader.decode().expect("to decode image");
+    let mut mosaic = generate_mosaic(&config)?;
 
-    // Crop the image with centre gravity to nearest multiple of thumbsize
-    let (width, height) = image.dimensions();
+    if let Some(svg_path) = args.svg.as_ref() {
+        write_svg(&mosaic.svg(&svg_links(&args))?, Path::new(svg_path))?;
+    }
+    // Written first since an interrupted mosaic exits once it's saved, and the flat
+    // image is whole either way
+    write_flat(&args, &mosaic)?;
 
-    let crop_width = width - width % args.thumbsize;
-    let crop_height = height - height % args.thumbsize;
+    write_mosaic(&args, &config, original_path, &output_path, &mut mosaic)?;
+    if args.preview {
+        eprintln!("{}", PREVIEW_BANNER);
+    }
 
-    image = image.crop(
-        (width - crop_width) / 2,
-        (height - crop_height) / 2,
-        crop_width,
-        crop_height,
-    );
+    if let Some(manifest_path) = args.manifest.as_ref() {
+        write_manifest(&mosaic, Path::new(manifest_path))?;
+    }
 
-    let image = image.into_rgb8();
-    INPUT_IMAGE.get_or_init(|| image);
-
-    let mut target_image = image::RgbImage::new(crop_width * args.dpr, crop_height * args.dpr);
-
-    let x_chunks = crop_width / args.thumbsize;
-    let y_chunks = crop_height / args.thumbsize;
-    let chunks = x_chunks * y_chunks;
-    let mut seen_chunks = 0u32;
-
-    // Create a set of tasks to process chunks async
-    let mut tasks = task::JoinSet::new();
-
-    for x_chunk in 0..x_chunks {
-        for y_chunk in 0..y_chunks {
-            tasks.spawn(async move {
-                // Do async work
-                let chunk: &RgbImage = &INPUT_IMAGE
-                    .get()
-                    .unwrap()
-                    .view(
-                        x_chunk * args.thumbsize,
-                        y_chunk * args.thumbsize,
-                        args.thumbsize,
-                        args.thumbsize,
-                    )
-                    .to_image();
-
-                return (
-                    x_chunk,
-                    y_chunk,
-                    process_chunk(chunk, args.sampleres, THUMBS_DB.get().unwrap())
-                        .await
-                        .expect("To process image chunk"),
-                );
-            });
+    if args.profile {
+        mosaic.profile.print();
+    }
+
+    Ok(())
+}
+
+/// Every image a directory or glob pattern given as the input stands for, sorted by
+/// path, or `None` if the input is a single image
+fn batch_inputs(input: &str) -> Result<Option<Vec<PathBuf>>, Error> {
+    let path = Path::new(input);
+    let candidates: Vec<PathBuf> = if path.is_dir() {
+        fs::read_dir(path)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect()
+            })
+            .map_err(|e| Error::Io(path.into(), e))?
+    } else if !path.exists() && input.contains(['*', '?', '[']) {
+        match glob::glob(input) {
+            Ok(paths) => paths.filter_map(Result::ok).collect(),
+            Err(e) => {
+                eprintln!("Invalid input glob {}: {}", input, e);
+                exit(2);
+            }
         }
+    } else {
+        return Ok(None);
+    };
+
+    let mut inputs: Vec<PathBuf> = candidates
+        .into_iter()
+        .filter(|path| path.is_file() && ImageFormat::from_path(path).is_ok())
+        .collect();
+    inputs.sort();
+    if inputs.is_empty() {
+        eprintln!("No images found in {}", input);
+        exit(2);
     }
 
-    while let Some(res) = tasks.join_next().await {
-        let (x, y, best) = res.expect("thread failed :(");
+    Ok(Some(inputs))
+}
+
+/// Mosaic each of `inputs` against one index, saving every output under its default
+/// name in the directory -o gives, or the current directory
+fn render_batch(args: &RenderArgs, input: &str, inputs: &[PathBuf]) -> Result<(), Error> {
+    if args.svg.is_some()
+        || args.manifest.is_some()
+        || args.resume.is_some()
+        || args.checkpoint.is_some()
+        || args.emit_flat.is_some()
+        || args.report
+        || args.report_json.is_some()
+    {
+        eprintln!(
+            "--svg, --manifest, --resume, --checkpoint, --emit-flat and --report only work with a single input image"
+        );
+        exit(2);
+    }
 
-        if !thumbs_cache.contains_key(&best.path) {
-            let image = load_image(&best.path).resize_exact(
-                args.thumbsize * args.dpr,
-                args.thumbsize * args.dpr,
-                image::imageops::FilterType::CatmullRom,
+    let output_dir = match args.output_path.as_ref().or(args.output.as_ref()) {
+        Some(dir) if Path::new(dir).is_dir() => PathBuf::from(dir),
+        Some(dir) => {
+            eprintln!(
+                "The output has to be an existing directory when the input is several images, {} isn't one",
+                dir
             );
-            thumbs_cache.insert(best.path.clone(), image);
+            exit(2);
+        }
+        None => std::env::current_dir().map_err(|e| Error::Io(PathBuf::from("."), e))?,
+    };
+    let output_ext = |path: &Path| path.extension().unwrap_or(OsStr::new("png")).to_owned();
+
+    // Outputs are only claimed once each image is done, so check every name and format
+    // up front
+    let mut formats = HashSet::new();
+    let mut outputs = HashSet::new();
+    for input in inputs {
+        let path = default_output_path(args, &output_dir, input, &output_ext(input));
+        let format = output_format(args, &path);
+        if formats.insert(format) {
+            check_writable(&path, format);
+            warn_unused_quality(args, format, &path);
         }
+        if !args.auto_increment {
+            claim_output(args, path.clone());
+            if !outputs.insert(path.clone()) {
+                eprintln!(
+                    "Two inputs would both be written to {}, pass --auto-increment to number them",
+                    path.display()
+                );
+                exit(2);
+            }
+        }
+    }
+
+    let config = mosaic_config(args, input)?;
+
+    log::info!("Targeting {} images in {}!", inputs.len(), input);
+    let profile = generate_mosaics(&config, inputs, |original_path, mut mosaic| {
+        let output_path = claim_output(
+            args,
+            default_output_path(args, &output_dir, original_path, &output_ext(original_path)),
+        );
+        write_mosaic(args, &config, original_path, &output_path, &mut mosaic)
+    })?;
 
-        let best_image = thumbs_cache.get(&best.path).unwrap().to_rgb8();
+    if args.preview {
+        eprintln!("{}", PREVIEW_BANNER);
+    }
 
-        let x = (x * args.thumbsize * args.dpr) as i64;
-        let y = (y * args.thumbsize * args.dpr) as i64;
+    if args.profile {
+        profile.print();
+    }
 
-        image::imageops::overlay(&mut target_image, &best_image, x, y);
+    Ok(())
+}
 
-        seen_chunks += 1;
-        print!("\rProcessing {}/{}", seen_chunks, chunks);
-        std::io::stdout().flush().unwrap(); // Ensure stdout is flushed
+/// Save `mosaic` of the input at `original_path` to `output_path`. An interrupted mosaic
+/// is saved as a partial result next to it with the assignment map to resume from, and
+/// then the process exits
+fn write_mosaic(
+    args: &RenderArgs,
+    config: &Config,
+    original_path: &Path,
+    output_path: &Path,
+    mosaic: &mut Mosaic,
+) -> Result<(), Error> {
+    if !args.index.quiet {
+        eprint!("Saving image...\r");
     }
 
-    println!("\rProcessing ............ Done!");
-    print!("Saving Image...\r");
+    let metadata = (!args.no_metadata).then(|| {
+        format!(
+            "imagegrid {}; input={:016x}; thumbsize={}; sampleres={}; matchres={}; algorithm={:?}; dpr={}; thumbs={}",
+            env!("CARGO_PKG_VERSION"),
+            fnv1a(original_path.file_name().map_or(&[][..], |name| name.as_encoded_bytes())),
+            match mosaic.thumbsize {
+                (width, height) if width == height => width.to_string(),
+                (width, height) => format!("{}x{}", width, height),
+            },
+            config.index_res(),
+            mosaic.matchres,
+            mosaic.algorithm,
+            mosaic.tile_size.0 / mosaic.thumbsize.0,
+            mosaic.pool_size,
+        )
+    });
 
-    target_image
-        .save(&output_path)
-        .expect("to save output image");
+    if mosaic.interrupted {
+        // An output named without an extension gets the one of the format it's saved in
+        let mut output_ext = OsString::from("partial.");
+        match output_path.extension() {
+            Some(ext) => output_ext.push(ext),
+            None => output_ext
+                .push(output_format(args, output_path).map_or("png", |f| f.extensions_str()[0])),
+        }
+        let partial_path = output_path.with_extension(output_ext);
+        let map_path = partial_map_path(output_path);
 
-    println!("Saved image to {}", &output_path.display());
-}
+        save_mosaic(args, config, mosaic, &partial_path, metadata.as_deref())?;
 
-fn import_thumb<P>(p: P, res: u32, thumbs_db: &mut ThumbnailDb) -> Result<(), ()>
-where
-    P: AsRef<std::path::Path> + Into<String>,
-{
-    let image = load_image(&p);
-    let thumb_image = get_thumb(&image, res);
+        let map = mosaic.assignment_map();
+        write_assignment_map(&map, &map_path)?;
 
-    let path = String::from(p.into());
+        let (x_chunks, y_chunks) = mosaic.grid;
+        println!(
+            "Wrote partial result ({}/{} chunks) to {} and assignment map to {}",
+            map.cells.len(),
+            x_chunks * y_chunks,
+            &partial_path.display(),
+            &map_path.display()
+        );
+        exit(130i32);
+    }
 
-    thumbs_db.thumbs.insert(ThumbnailData {
-        path: path.clone(),
-        res,
-        colors: rgb_thumb_to_pixels(&thumb_image),
-    });
+    save_mosaic(args, config, mosaic, output_path, metadata.as_deref())?;
+
+    println!("Saved image to {}", output_path.display());
+    remove_checkpoint(config);
+
+    Ok(())
+}
+
+/// Save `Mosaic::flat` to the --emit-flat path, if there is one
+fn write_flat(args: &RenderArgs, mosaic: &Mosaic) -> Result<(), Error> {
+    let Some(flat_path) = args.emit_flat.as_ref().map(Path::new) else {
+        return Ok(());
+    };
+
+    save_image(&mosaic.flat, flat_path, None, None, args.quality)
+        .map_err(|e| Error::Image(flat_path.display().to_string(), e))?;
+    log::info!("Wrote flat image to {}", flat_path.display());
 
     Ok(())
 }
 
-fn get_thumb(image: &DynamicImage, res: u32) -> image::ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-    image
-        .clone()
-        .resize_exact(res, res, image::imageops::FilterType::CatmullRom)
-        .to_rgb8()
+/// Where the assignment map of an interrupted or checkpointed render to `output_path`
+/// is written
+fn partial_map_path(output_path: &Path) -> PathBuf {
+    output_path.with_extension("partial.ron")
 }
 
-fn load_image<P>(p: P) -> DynamicImage
-where
-    P: AsRef<std::path::Path>,
-{
-    let raw_image = fs::read(p).expect("to read the provided thumb file");
+/// Delete the --checkpoint map once the whole render is saved, since there's nothing
+/// left to resume
+fn remove_checkpoint(config: &Config) {
+    if let Some((path, _)) = &config.checkpoint
+        && let Err(e) = fs::remove_file(path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        log::warn!("Couldn't remove checkpoint {}: {}", path.display(), e);
+    }
+}
 
-    let reader = ImageReader::new(Cursor::new(raw_image))
-        .with_guessed_format()
-        .expect("Cursor io never fails");
+/// Write the assignment map as RON for a .ron path and JSON otherwise
+fn write_manifest(mosaic: &Mosaic, manifest_path: &Path) -> Result<(), Error> {
+    write_assignment_map(&mosaic.assignment_map(), manifest_path)?;
+    log::info!("Wrote manifest to {}", manifest_path.display());
 
-    let image = reader.decode().expect("to decode image");
+    Ok(())
+}
 
-    image
+/// Whether the output path names an SVG, which selects the SVG backend instead of
+/// compositing a raster
+fn svg_output(args: &RenderArgs) -> bool {
+    let output = args.output_path.as_ref().or(args.output.as_ref());
+    args.format.is_none()
+        && output.is_some_and(|path| {
+            Path::new(path)
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+        })
 }
 
-fn rgb_thumb_to_pixels(thumb: &RgbImage) -> Vec<[u8; 3]> {
-    Vec::from_iter(thumb.enumerate_pixels().map(|(_x, _y, pixel)| pixel.0))
+/// How the SVG output refers to the thumbnails, as --embed and --svg-base say
+fn svg_links(args: &RenderArgs) -> SvgLinks {
+    match (&args.svg_base, args.embed) {
+        (_, true) => SvgLinks::Embedded,
+        (Some(base), false) => SvgLinks::RelativeTo(base.clone()),
+        (None, false) => SvgLinks::Absolute,
+    }
 }
 
-fn compare_thumbs_u8(a: &Vec<[u8; 3]>, b: &Vec<[u8; 3]>) -> i32 {
-    if !a.len() == b.len() {
-        return i32::max_value();
+fn write_svg(svg: &str, path: &Path) -> Result<(), Error> {
+    fs::write(path, svg).map_err(|e| Error::Io(path.into(), e))?;
+    println!("Wrote SVG mosaic to {}", path.display());
+
+    Ok(())
+}
+
+/// Match the input and write it as an SVG of thumbnail links to `output_path`, with
+/// `Config::match_only` set so no tiles are loaded or placed
+fn render_svg(args: &RenderArgs, config: &Config, output_path: &Path) -> Result<(), Error> {
+    let mosaic = generate_mosaic(config)?;
+    write_svg(&mosaic.svg(&svg_links(args))?, output_path)?;
+
+    if let Some(svg_path) = args.svg.as_ref() {
+        write_svg(&mosaic.svg(&svg_links(args))?, Path::new(svg_path))?;
+    }
+    write_flat(args, &mosaic)?;
+    if let Some(manifest_path) = args.manifest.as_ref() {
+        write_manifest(&mosaic, Path::new(manifest_path))?;
     }
 
-    let mut diff = 0i32;
+    if args.profile {
+        mosaic.profile.print();
+    }
 
-    for (x, y) in zip(a, b) {
-        diff += (x[0] as i32 - y[0] as i32).pow(2u32)
-            + (x[1] as i32 - y[1] as i32).pow(2u32)
-            + (x[2] as i32 - y[2] as i32).pow(2u32);
+    if mosaic.interrupted {
+        log::warn!("Matching was interrupted, so the SVG only places the cells matched so far");
+        exit(130i32);
     }
+    remove_checkpoint(config);
 
-    diff
+    Ok(())
 }
 
-fn compare_thumbs_f32(a: &Vec<[f32; 3]>, b: &Vec<[f32; 3]>) -> f32 {
-    if !a.len() == b.len() {
-        return f32::MAX;
+/// Match the input and print how well it went, with `Config::match_only` set so no
+/// tiles are loaded or placed
+fn report(args: &RenderArgs, config: &Config) -> Result<(), Error> {
+    let mosaic = generate_mosaic(config)?;
+    let report = Report::new(&mosaic, args.report_threshold);
+
+    println!();
+    report.print();
+
+    if let Some(json_path) = args.report_json.as_ref() {
+        let json = serde_json::to_string_pretty(&report).expect("reports always serialize");
+        fs::write(json_path, json).map_err(|e| Error::Io(PathBuf::from(json_path), e))?;
+        println!("\nWrote report to {}", json_path);
     }
+    write_flat(args, &mosaic)?;
 
-    let mut diff = 0f32;
+    if let Some(manifest_path) = args.manifest.as_ref() {
+        write_manifest(&mosaic, Path::new(manifest_path))?;
+    }
 
-    for (x, y) in zip(a, b) {
-        diff += (x[0] - y[0]).powi(2i32) + (x[1] - y[1]).powi(2i32) + (x[2] - y[2]).powi(2i32);
+    if args.profile {
+        mosaic.profile.print();
     }
 
-    diff
+    if mosaic.interrupted {
+        log::warn!("Matching was interrupted, so the report only covers the cells matched so far");
+        exit(130i32);
+    }
+
+    Ok(())
 }
 
-fn compare_thumbs_oklab(a: &Vec<[u8; 3]>, b: &Vec<[u8; 3]>) -> f32 {
-    let a_rgb = a
-        .into_iter()
-        .map(|v| lab_to_f32(srgb_to_oklab(oklab::Rgb::from(*v))));
-    let b_rgb = b
-        .into_iter()
-        .map(|v| lab_to_f32(srgb_to_oklab(oklab::Rgb::from(*v))));
+/// Mosaic every frame of a GIF into an animated GIF, encoding each as soon as it's done
+fn render_animation(args: &RenderArgs, config: &Config, output_path: &Path) -> Result<(), Error> {
+    if args.svg.is_some()
+        || args.manifest.is_some()
+        || args.resume.is_some()
+        || args.checkpoint.is_some()
+        || args.emit_flat.is_some()
+        || args.strip_rows.is_some()
+    {
+        eprintln!(
+            "--svg, --manifest, --resume, --checkpoint, --emit-flat and --strip-rows only work with still images"
+        );
+        exit(2);
+    }
+
+    let image_error = |e| Error::Image(output_path.display().to_string(), e);
+    let file = fs::File::create(output_path).map_err(|e| Error::Io(output_path.into(), e))?;
+    // The default speed spends far longer quantizing each frame than mosaicking it
+    let mut encoder = GifEncoder::new_with_speed(BufWriter::new(file), 10);
+    encoder.set_repeat(Repeat::Infinite).map_err(image_error)?;
+
+    let mut frames = 0;
+    let mut interrupted = false;
+    let profile = generate_animation(config, |mosaic, delay| {
+        let image = DynamicImage::ImageRgb8(mosaic.image).into_rgba8();
+        encoder
+            .encode_frame(Frame::from_parts(image, 0, 0, delay))
+            .map_err(image_error)?;
+        frames += 1;
+        interrupted = mosaic.interrupted;
+        Ok(())
+    })?;
+    drop(encoder);
+
+    if interrupted {
+        println!(
+            "Wrote {} frames, the last one partial, to {}",
+            frames,
+            output_path.display()
+        );
+        exit(130i32);
+    }
 
-    compare_thumbs_f32(&a_rgb.collect(), &b_rgb.collect())
+    println!("Saved {} frames to {}", frames, output_path.display());
+    if args.preview {
+        eprintln!("{}", PREVIEW_BANNER);
+    }
+
+    if args.profile {
+        profile.print();
+    }
+
+    Ok(())
 }
 
-fn lab_to_f32(lab: Oklab) -> [f32; 3] {
-    [lab.l, lab.a, lab.b]
+/// --quality only does anything for JPEG and AVIF output
+fn warn_unused_quality(args: &RenderArgs, format: Option<ImageFormat>, output_path: &Path) {
+    let lossy = matches!(format, Some(ImageFormat::Jpeg | ImageFormat::Avif));
+
+    if args.quality.is_some() && !lossy {
+        log::warn!(
+            "--quality only applies to JPEG and AVIF output, ignoring it for {}",
+            output_path.display()
+        );
+    }
 }
 
-async fn process_chunk<'a>(
-    chunk: &RgbImage,
-    sampleres: u32,
-    thumbs_db: &'a ThumbnailDb,
-) -> Option<&'a ThumbnailData> {
-    let mut thumb = DynamicImage::from(chunk.clone())
-        .resize_exact(
-            sampleres,
-            sampleres,
-            image::imageops::FilterType::CatmullRom,
-        )
-        .to_rgb8();
+/// --embed and --svg-base only do anything when an SVG is written
+fn warn_unused_svg_links(args: &RenderArgs) {
+    if (args.embed || args.svg_base.is_some()) && args.svg.is_none() && !svg_output(args) {
+        log::warn!("--embed and --svg-base only apply to SVG output, ignoring them");
+    }
+}
 
-    let pixels = rgb_thumb_to_pixels(&mut thumb);
-    let mut best_match: Option<&ThumbnailData> = None;
+/// The format the output is written in: --format if given, otherwise the one its
+/// extension names
+fn output_format(args: &RenderArgs, output_path: &Path) -> Option<ImageFormat> {
+    match args.format {
+        Some(format) => Some(format.into()),
+        None => ImageFormat::from_path(output_path).ok(),
+    }
+}
 
-    match COMPARISON_FN.get().expect("to get comparison function") {
-        DifferenceFunction::Oklab => {
-            let mut best_score = f32::MAX;
+/// Exit before any work is done if this build can't encode the output format
+fn check_writable(path: &Path, format: Option<ImageFormat>) {
+    let problem = match format {
+        None => return,
+        Some(format) if !format.can_write() => format!("{:?} can't be written", format),
+        Some(format) if !format.writing_enabled() => {
+            let feature = match format {
+                ImageFormat::Jpeg => "jpeg",
+                ImageFormat::Pnm => "pnm",
+                format => format.extensions_str()[0],
+            };
+            format!(
+                "{:?} output needs the `{}` feature of the image crate, which this build leaves out",
+                format, feature
+            )
+        }
+        Some(_) => return,
+    };
 
-            for ref_thumb in &thumbs_db.thumbs {
-                let score = compare_thumbs_oklab(&pixels, &ref_thumb.colors);
+    eprintln!("Can't write {}: {}", path.display(), problem);
+    exit(2);
+}
 
-                if score < best_score {
-                    best_score = score;
-                    best_match = Some(ref_thumb);
-                }
+/// Where to write the output image: the path given on the command line, or
+/// `<original>.output.<output_ext>` in the current directory, claimed as `claim_output`
+/// says
+fn output_path(
+    args: &RenderArgs,
+    original_path: &Path,
+    output_ext: &OsStr,
+) -> Result<PathBuf, Error> {
+    let path = match args.output_path.as_ref().or(args.output.as_ref()) {
+        Some(p) => {
+            let path = PathBuf::from(p);
+            if let Some(parent) = path.parent()
+                && !parent.as_os_str().is_empty()
+                && !parent.is_dir()
+            {
+                eprintln!("Output directory {} doesn't exist", parent.display());
+                exit(2);
+            }
+            if args.format.is_none()
+                && !svg_output(args)
+                && let Err(e) = ImageFormat::from_path(&path)
+            {
+                eprintln!("Can't write {}: {}", path.display(), e);
+                exit(2);
             }
+            path
         }
-        DifferenceFunction::Rgb => {
-            let mut best_score = i32::MAX;
 
-            for ref_thumb in &thumbs_db.thumbs {
-                let score = compare_thumbs_u8(&pixels, &ref_thumb.colors);
+        None => {
+            let output_dir =
+                std::env::current_dir().map_err(|e| Error::Io(PathBuf::from("."), e))?;
+            default_output_path(args, &output_dir, original_path, output_ext)
+        }
+    };
+    check_writable(&path, output_format(args, &path));
 
-                if score < best_score {
-                    best_score = score;
-                    best_match = Some(ref_thumb);
-                }
+    Ok(claim_output(args, path))
+}
+
+/// `path` if it's free or --overwrite was given, otherwise with --auto-increment the
+/// first free `<stem>-<n>.<ext>` next to it. Exits if it's taken and neither was given
+fn claim_output(args: &RenderArgs, path: PathBuf) -> PathBuf {
+    if args.overwrite || !path.exists() {
+        return path;
+    }
+    if !args.auto_increment {
+        eprintln!(
+            "{} already exists, pass --overwrite to replace it or --auto-increment to number the new one",
+            path.display()
+        );
+        exit(2);
+    }
+
+    let stem = path.file_stem().unwrap_or_default();
+    (1u32..)
+        .map(|n| {
+            let mut name = stem.to_os_string();
+            name.push(format!("-{}", n));
+            if let Some(ext) = path.extension() {
+                name.push(".");
+                name.push(ext);
             }
+            path.with_file_name(name)
+        })
+        .find(|numbered| !numbered.exists())
+        .expect("some number is free")
+}
+
+/// `<original>.output.<output_ext>` in `output_dir`, or `.preview.` for a preview.
+/// --format replaces the extension
+fn default_output_path(
+    args: &RenderArgs,
+    output_dir: &Path,
+    original_path: &Path,
+    output_ext: &OsStr,
+) -> PathBuf {
+    let output_ext = match args.format {
+        Some(format) => OsStr::new(ImageFormat::from(format).extensions_str()[0]),
+        None => output_ext,
+    };
+
+    // Nothing to name the output after when the input is piped in
+    let output_name = match original_path.as_os_str() == "-" {
+        true => {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+            format!("stdin-{}", now.map_or(0, |d| d.as_secs()))
+        }
+        false => original_path
+            .file_prefix()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string(),
+    };
+    let suffix = if args.preview { "preview" } else { "output" };
+
+    output_dir
+        .join(&output_name)
+        .with_extension(suffix)
+        .with_added_extension(output_ext)
+}
+
+/// Save a rendered mosaic to `path`, or composite it there in bands with --strip-rows,
+/// where encoding counts towards placement
+fn save_mosaic(
+    args: &RenderArgs,
+    config: &Config,
+    mosaic: &mut Mosaic,
+    path: &Path,
+    metadata: Option<&str>,
+) -> Result<(), Error> {
+    let Some(rows) = args.strip_rows else {
+        let phase_start = Instant::now();
+        let format = args.format.map(ImageFormat::from);
+        save_image(&mosaic.image, path, format, metadata, args.quality)
+            .map_err(|e| Error::Image(path.display().to_string(), e))?;
+        mosaic.profile.record("save", phase_start);
+        return Ok(());
+    };
+
+    let profile = write_strips(
+        &mosaic.assignment_map(),
+        config,
+        rows,
+        (path, args.format),
+        metadata,
+        args.quality,
+    )?;
+    mosaic.profile.add(profile);
+
+    Ok(())
+}
+
+/// Composite `map` into `path` a band of `rows` grid rows at a time, encoding each band
+/// as it's done where the format allows
+fn write_strips(
+    map: &AssignmentMap,
+    config: &Config,
+    rows: u32,
+    (path, format): (&Path, Option<OutputFormat>),
+    metadata: Option<&str>,
+    quality: Option<u8>,
+) -> Result<Profile, Error> {
+    let image_error = |e| Error::Image(path.display().to_string(), e);
+    let format = format.map(ImageFormat::from);
+    let size = rendered_size(map, config);
+    let mut writer =
+        StripWriter::create(path, format, size, metadata, quality).map_err(image_error)?;
+    if !writer.is_streaming() {
+        log::warn!("Only PNG and PPM are written in strips, assembling the whole image first");
+    }
+
+    let profile = render_assignment_strips(map, config, rows, |strip| {
+        writer.write_strip(&strip).map_err(image_error)
+    })?;
+    writer.finish().map_err(image_error)?;
+
+    Ok(profile)
+}
+
+/// Composite the mosaic recorded in a manifest at the current --dpr, --filter and tile
+/// settings, without matching again
+fn regenerate(args: &RenderArgs, manifest_path: &str) -> Result<(), Error> {
+    log::info!("Regenerating {}!", manifest_path);
+
+    let original_path = Path::new(manifest_path);
+    let map = read_assignment_map(original_path)?;
+
+    let output_path = output_path(args, original_path, OsStr::new("png"))?;
+    warn_unused_quality(args, output_format(args, &output_path), &output_path);
+    warn_unused_svg_links(args);
+    let config = Config {
+        filter: args.index.filter,
+        dpr: args.dpr,
+        native_tiles: args.native_tiles,
+        preview: args.preview,
+        cache_size: args.cache_size,
+        tile_border: args.tile_border,
+        tile_border_color: args.tile_border_color,
+        alpha_background: args.index.alpha_background,
+        normalize: args.index.normalize,
+        tone: tile_tone(args),
+        ignore_exif: args.index.ignore_exif,
+        ..Default::default()
+    };
+    if svg_output(args) {
+        return write_svg(
+            &assignment_map_svg(&map, &config, &svg_links(args))?,
+            &output_path,
+        );
+    }
+    if let Some(rows) = args.strip_rows {
+        if args.svg.is_some() {
+            eprintln!("--svg can't be combined with --strip-rows when regenerating");
+            exit(2);
+        }
+        let target = (output_path.as_path(), args.format);
+        let profile = write_strips(&map, &config, rows, target, None, args.quality)?;
+        println!("Saved image to {}", &output_path.display());
+        if args.profile {
+            profile.print();
         }
+        return Ok(());
+    }
+
+    let mut mosaic = render_assignment_map(&map, &config)?;
+
+    if let Some(svg_path) = args.svg.as_ref() {
+        write_svg(&mosaic.svg(&svg_links(args))?, Path::new(svg_path))?;
+    }
+
+    let phase_start = Instant::now();
+    let format = args.format.map(ImageFormat::from);
+    save_image(&mosaic.image, &output_path, format, None, args.quality)
+        .map_err(|e| Error::Image(output_path.display().to_string(), e))?;
+    mosaic.profile.record("save", phase_start);
+
+    println!("Saved image to {}", &output_path.display());
+    if args.preview {
+        eprintln!("{}", PREVIEW_BANNER);
+    }
+
+    if args.profile {
+        mosaic.profile.print();
     }
 
-    best_match
+    Ok(())
+}
+
+/// Shadow and highlight colours --duotone or --grayscale tone tiles to
+fn tile_tone(args: &RenderArgs) -> Option<[[u8; 3]; 2]> {
+    match &args.duotone {
+        Some(colors) => Some([colors[0], colors[1]]),
+        None => args.grayscale.then_some([[0; 3], [255; 3]]),
+    }
+}
+
+/// Library settings for a render of `input`, reading the assignment map to resume
+/// from if there is one
+fn mosaic_config(args: &RenderArgs, input: &str) -> Result<Config, Error> {
+    let resume = match args.resume.as_ref() {
+        Some(map_path) => Some(read_assignment_map(Path::new(map_path))?),
+        None => None,
+    };
+
+    Ok(Config {
+        input: PathBuf::from(input),
+        thumbs: args.index.thumbs.clone(),
+        sampleres: args.index.sampleres,
+        import_res: args.index.import_res.clone(),
+        sample_stat: args.index.sample_stat,
+        filter: args.index.filter,
+        force_reimport: args.index.force_reimport,
+        threads: args.index.threads,
+        db_format: args.index.db_format,
+        db_path: args.index.db_path.clone(),
+        min_dimension: args.index.min_dimension,
+        max_dimension: args.index.max_dimension,
+        dedupe: args.index.dedupe.then_some(args.index.dedupe_threshold),
+        alpha_background: args.index.alpha_background,
+        ignore_exif: args.index.ignore_exif,
+        normalize: args.index.normalize,
+        thumbsize: args.thumbsize,
+        thumb_width: args.thumb_width,
+        thumb_height: args.thumb_height,
+        auto_thumbsize: args.auto_thumbsize,
+        grid: args.grid,
+        grid_fit: args.grid_fit,
+        gravity: args.gravity,
+        matchres: args.matchres,
+        match_mode: args.match_mode,
+        coarse: args.coarse_res.map(|res| (res, args.coarse_top)),
+        spatial_index: args.spatial_index,
+        exact: args.exact,
+        dpr: args.dpr,
+        algorithm: args.algorithm,
+        lab_weights: [
+            args.lab_weights[0] * args.luma_weight,
+            args.lab_weights[1],
+            args.lab_weights[2],
+        ],
+        sample_weights: args.sample_weights,
+        structure_weight: args.structure_weight,
+        edge_cells: args.edge_cells,
+        pad: args.pad.then_some(args.pad_color),
+        native_tiles: args.native_tiles,
+        match_only: args.strip_rows.is_some()
+            || args.report
+            || args.report_json.is_some()
+            || svg_output(args),
+        preview: args.preview,
+        cache_size: args.cache_size,
+        blend: args.blend,
+        flat: args.emit_flat.is_some(),
+        tone: tile_tone(args),
+        tone_match: args.grayscale_match,
+        match_brightness: args.match_brightness.then_some(args.max_adjust),
+        match_contrast: args.match_contrast,
+        overlap: args.overlap,
+        tile_border: args.tile_border,
+        tile_border_color: args.tile_border_color,
+        pre_blur: args.pre_blur,
+        pre_saturation: args.pre_saturation,
+        pre_contrast: args.pre_contrast,
+        input_scale: args.input_scale,
+        max_input_dimension: args.max_input_dimension,
+        max_cells: (!args.yes).then_some(args.max_cells),
+        max_output_bytes: (!args.yes).then_some(MAX_OUTPUT_BYTES),
+        exclude_self: args.exclude_self,
+        usage_penalty: args.usage_penalty,
+        no_repeat_radius: args.no_repeat_radius,
+        max_uses: args.max_uses,
+        assign: args.assign,
+        dither: args.dither,
+        fallback_threshold: args.fallback_threshold,
+        limit_thumbs: args.limit_thumbs,
+        seed: args.seed,
+        top_n: args.top_n,
+        allow_flip: args.allow_flip,
+        allow_rotate: args.allow_rotate,
+        resume,
+        checkpoint: None,
+        frame_skip: args.frame_skip,
+    })
+}
+
+/// Rewrite the thumbnail database without the entries of missing files and superseded
+/// bands, in its own format unless --db-format asks for another, and print how much it
+/// shrank
+fn compact_thumbs(args: CompactArgs) -> Result<(), Error> {
+    let thumb_data_path = match args.db_path {
+        Some(path) => path,
+        None => thumb_data_path()?,
+    };
+    let Ok(metadata) = fs::metadata(&thumb_data_path) else {
+        eprintln!("No thumbnail database found at {:?}", &thumb_data_path);
+        exit(1i32);
+    };
+    let (mut thumbs_db, format) = read_thumbs_db(&thumb_data_path)?;
+    let entries = thumbs_db.thumbs.len();
+
+    let (missing, superseded) = compact_thumbs_db(&mut thumbs_db, &args.keep_res);
+    write_thumbs_db(
+        &thumb_data_path,
+        &thumbs_db,
+        args.db_format.unwrap_or(format),
+    )?;
+    let compacted = fs::metadata(&thumb_data_path)
+        .map_err(|e| Error::Io(thumb_data_path.clone(), e))?
+        .len();
+
+    println!("Removed {} thumbnails no longer present", missing);
+    println!("Dropped {} superseded bands of the others", superseded);
+    println!(
+        "Database {:?}: {} entries in {} KiB, now {} in {} KiB",
+        &thumb_data_path,
+        entries,
+        metadata.len().div_ceil(1024),
+        thumbs_db.thumbs.len(),
+        compacted.div_ceil(1024)
+    );
+
+    Ok(())
+}
+
+fn inspect_thumbs(args: InspectArgs) -> Result<(), Error> {
+    let thumb_data_path = match args.db_path {
+        Some(path) => path,
+        None => thumb_data_path()?,
+    };
+    let Ok(metadata) = fs::metadata(&thumb_data_path) else {
+        eprintln!("No thumbnail database found at {:?}", &thumb_data_path);
+        exit(1i32);
+    };
+    let (thumbs_db, format) = read_thumbs_db(&thumb_data_path)?;
+
+    println!(
+        "Database {:?}: {} KiB ({:?})",
+        &thumb_data_path,
+        metadata.len().div_ceil(1024),
+        format
+    );
+
+    let mut resolutions: Vec<(u32, usize)> = Vec::new();
+    let mut averages: HashMap<&str, [f32; 3]> = HashMap::new();
+    for thumb in &thumbs_db.thumbs {
+        match resolutions.iter_mut().find(|(res, _)| *res == thumb.res) {
+            Some((_, count)) => *count += 1,
+            None => resolutions.push((thumb.res, 1)),
+        }
+        averages
+            .entry(&thumb.path)
+            .or_insert_with(|| average_color(&thumb.colors));
+    }
+    resolutions.sort();
+
+    println!(
+        "Entries: {} ({} distinct thumbnails)",
+        thumbs_db.thumbs.len(),
+        averages.len()
+    );
+    println!(
+        "Resolutions: {}",
+        resolutions
+            .iter()
+            .map(|(res, count)| format!("{}x{} ({})", res, res, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let missing = averages.keys().filter(|p| !Path::new(p).exists()).count();
+    println!("Missing on disk: {}", missing);
+
+    if averages.is_empty() {
+        return Ok(());
+    }
+
+    // Spread of per-thumbnail average colours, plus a rough brightness histogram
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    let mut sum = [0f32; 3];
+    let mut brightness = [0usize; 4];
+    for avg in averages.values() {
+        for c in 0..3 {
+            min[c] = min[c].min(avg[c]);
+            max[c] = max[c].max(avg[c]);
+            sum[c] += avg[c];
+        }
+        let luma = 0.2126 * avg[0] + 0.7152 * avg[1] + 0.0722 * avg[2];
+        brightness[((luma / 64.0) as usize).min(3)] += 1;
+    }
+    let n = averages.len() as f32;
+
+    println!(
+        "Average color (RGB): mean [{:.0}, {:.0}, {:.0}], min [{:.0}, {:.0}, {:.0}], max [{:.0}, {:.0}, {:.0}]",
+        sum[0] / n,
+        sum[1] / n,
+        sum[2] / n,
+        min[0],
+        min[1],
+        min[2],
+        max[0],
+        max[1],
+        max[2]
+    );
+    println!(
+        "Brightness: dark {} | shadows {} | highlights {} | bright {}",
+        brightness[0], brightness[1], brightness[2], brightness[3]
+    );
+
+    Ok(())
+}
+
+fn average_color(colors: &[[u8; 3]]) -> [f32; 3] {
+    let mut sum = [0f32; 3];
+    for color in colors {
+        for c in 0..3 {
+            sum[c] += color[c] as f32;
+        }
+    }
+
+    sum.map(|c| c / colors.len().max(1) as f32)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
 }
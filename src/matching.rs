@@ -0,0 +1,1095 @@
+use std::{collections::HashMap, iter::zip, sync::Arc};
+
+use image::{DynamicImage, RgbImage};
+use oklab::{Oklab, srgb_to_oklab};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "tokio")]
+use tokio::task::{self};
+
+use crate::{
+    batch::PoolColumns,
+    bucket::ColorBuckets,
+    canvas::srgb_to_linear,
+    db::ThumbnailData,
+    kdtree::KdTree,
+    sample::{ResizeFilter, SampleStat, extract_chunk, sample_image, structure},
+};
+
+/// How the colours of a chunk and a thumbnail are compared
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum DifferenceFunction {
+    /// Fast
+    Rgb,
+    /// Slower, More Accurate
+    Oklab,
+    /// Euclidean distance in CIELAB (ΔE76), the Lab of Photoshop and most colour tools.
+    /// About as fast as Oklab, but less even across hues
+    Cielab,
+    /// Slowest, most perceptually accurate (CIEDE2000 over CIELAB)
+    Ciede2000,
+    /// Hue first, so cells get thumbnails of the same colour family even at a different
+    /// brightness. A stylised look rather than an accurate one
+    Hsv,
+    /// Fastest, least detailed: each cell and thumbnail is reduced to its average Oklab
+    /// colour, so nothing of the layout within a cell is matched. Ignores sample weights
+    AverageColor,
+}
+
+/// How much each sample position in a cell counts towards its difference
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum SampleWeights {
+    /// Every sample counts the same
+    Flat,
+    /// Samples count less the further they are from the centre of the cell, for
+    /// subjects whose detail sits in the middle of each cell
+    Gaussian,
+}
+
+impl SampleWeights {
+    /// Weight of each sample in a square grid of `len` samples, in raster order, averaging
+    /// 1 so scores stay on the same scale. Empty when every sample counts the same
+    fn grid(self, len: usize) -> Vec<f32> {
+        match self {
+            SampleWeights::Flat => Vec::new(),
+            SampleWeights::Gaussian => {
+                let res = (len as f64).sqrt().round() as usize;
+                // Standard deviation as a fraction of the cell size
+                let sigma = 0.25;
+                let offset = |i: usize| (i as f64 + 0.5) / res as f64 - 0.5;
+                let weights: Vec<f64> = (0..len)
+                    .map(|i| {
+                        let distance = offset(i % res).powi(2) + offset(i / res).powi(2);
+                        (-distance / (2.0 * sigma * sigma)).exp()
+                    })
+                    .collect();
+                let mean = weights.iter().sum::<f64>() / len as f64;
+
+                weights.iter().map(|w| (w / mean) as f32).collect()
+            }
+        }
+    }
+}
+
+/// How a placed thumbnail is mirrored or turned. The quarter turns and diagonal
+/// mirrors only fit square cells
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Flip {
+    #[default]
+    None,
+    Horizontal,
+    Vertical,
+    /// Mirrored both ways, which is a half turn
+    Both,
+    /// A quarter turn clockwise
+    Rotate90,
+    /// A quarter turn anticlockwise
+    Rotate270,
+    /// Mirrored across the diagonal from the top left corner
+    Transpose,
+    /// Mirrored across the diagonal from the top right corner
+    Transverse,
+}
+
+impl Flip {
+    pub fn is_none(&self) -> bool {
+        *self == Flip::None
+    }
+
+    pub(crate) fn apply(self, image: &RgbImage) -> RgbImage {
+        use image::imageops::{flip_horizontal, flip_vertical, rotate90, rotate180, rotate270};
+
+        match self {
+            Flip::None => image.clone(),
+            Flip::Horizontal => flip_horizontal(image),
+            Flip::Vertical => flip_vertical(image),
+            Flip::Both => rotate180(image),
+            Flip::Rotate90 => rotate90(image),
+            Flip::Rotate270 => rotate270(image),
+            Flip::Transpose => flip_horizontal(&rotate90(image)),
+            Flip::Transverse => flip_horizontal(&rotate270(image)),
+        }
+    }
+
+    /// The orientation that undoes this one. Only the quarter turns aren't their own
+    fn inverse(self) -> Flip {
+        match self {
+            Flip::Rotate90 => Flip::Rotate270,
+            Flip::Rotate270 => Flip::Rotate90,
+            flip => flip,
+        }
+    }
+}
+
+/// Which mirrored orientations thumbnails may be placed in, besides the original
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum FlipMode {
+    /// Mirrored left to right
+    Horizontal,
+    /// Mirrored left to right, top to bottom, or both
+    Both,
+}
+
+impl FlipMode {
+    /// Orientations to try with `mode`, and every quarter turn of them with `rotate`.
+    /// Turning a mirrored thumbnail reaches both mirrors and both diagonals, so any
+    /// mode with `rotate` gives all eight
+    pub(crate) fn orientations(mode: Option<FlipMode>, rotate: bool) -> &'static [Flip] {
+        match (mode, rotate) {
+            (None, false) => &[Flip::None],
+            (Some(FlipMode::Horizontal), false) => &[Flip::None, Flip::Horizontal],
+            (Some(FlipMode::Both), false) => {
+                &[Flip::None, Flip::Horizontal, Flip::Vertical, Flip::Both]
+            }
+            (None, true) => &[Flip::None, Flip::Rotate90, Flip::Both, Flip::Rotate270],
+            (Some(_), true) => &[
+                Flip::None,
+                Flip::Horizontal,
+                Flip::Vertical,
+                Flip::Both,
+                Flip::Rotate90,
+                Flip::Rotate270,
+                Flip::Transpose,
+                Flip::Transverse,
+            ],
+        }
+    }
+}
+
+/// How chunk and thumbnail colours are scored against each other
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Metric {
+    pub(crate) algorithm: DifferenceFunction,
+    pub(crate) lab_weights: [f32; 3],
+    pub(crate) sample_weights: SampleWeights,
+    /// How much a difference in `structure` counts, 0 ignores it
+    pub(crate) structure_weight: f32,
+}
+
+impl Metric {
+    /// Difference between two colour grids, each sample scaled by `weights` if given
+    fn score_colors(&self, a: &[[u8; 3]], b: &[[u8; 3]], weights: &[f32]) -> f64 {
+        match self.algorithm {
+            DifferenceFunction::Oklab => {
+                compare_thumbs_oklab(a, b, &self.lab_weights, weights) as f64
+            }
+            DifferenceFunction::Rgb => compare_thumbs_u8(a, b, weights),
+            DifferenceFunction::Cielab => {
+                compare_thumbs_f32(&cielab_colors(a), &cielab_colors(b), weights) as f64
+            }
+            DifferenceFunction::Ciede2000 => {
+                compare_thumbs_ciede2000(&cielab_colors(a), &cielab_colors(b), weights) as f64
+            }
+            DifferenceFunction::Hsv => {
+                compare_thumbs_hsv(&hsv_colors(a), &hsv_colors(b), weights) as f64
+            }
+            DifferenceFunction::AverageColor => {
+                compare_thumbs_f32(&self.chunk_lab(a), &self.chunk_lab(b), &[]) as f64
+            }
+        }
+    }
+
+    /// Weight of each of `len` samples, empty when they all count the same or are
+    /// averaged together before comparing
+    fn weights(&self, len: usize) -> Vec<f32> {
+        match self.algorithm {
+            DifferenceFunction::AverageColor => Vec::new(),
+            _ => self.sample_weights.grid(len),
+        }
+    }
+
+    /// Weighted Oklab, CIELAB or HSV of sampled chunk colours, or their one weighted
+    /// average Oklab colour, if the algorithm compares them
+    fn chunk_lab(&self, pixels: &[[u8; 3]]) -> Vec<[f32; 3]> {
+        let weighted_oklab = || {
+            pixels
+                .iter()
+                .map(|v| lab_to_f32(srgb_to_oklab(oklab::Rgb::from(*v)), &self.lab_weights))
+                .collect::<Vec<_>>()
+        };
+        match self.algorithm {
+            DifferenceFunction::Oklab => weighted_oklab(),
+            DifferenceFunction::AverageColor => vec![mean_lab(&weighted_oklab())],
+            DifferenceFunction::Cielab | DifferenceFunction::Ciede2000 => cielab_colors(pixels),
+            DifferenceFunction::Hsv => hsv_colors(pixels),
+            DifferenceFunction::Rgb => Vec::new(),
+        }
+    }
+
+    /// The colours `score_thumb` compares for the given sampled colours and their
+    /// `chunk_lab`, as indexed by a `KdTree`
+    pub(crate) fn index_colors(&self, pixels: &[[u8; 3]], lab: &[[f32; 3]]) -> Vec<[f32; 3]> {
+        match self.algorithm {
+            DifferenceFunction::Oklab
+            | DifferenceFunction::Cielab
+            | DifferenceFunction::Ciede2000
+            | DifferenceFunction::Hsv
+            | DifferenceFunction::AverageColor => lab.to_vec(),
+            DifferenceFunction::Rgb => pixels.iter().map(|p| p.map(f32::from)).collect(),
+        }
+    }
+
+    /// Add each thumbnail's structure difference from `chunk` to its finite score. A
+    /// weight of 1 makes the largest difference count as much as a black chunk against a
+    /// white thumbnail, with `samples` samples per side
+    fn add_structure(
+        &self,
+        chunk: &DynamicImage,
+        samples: usize,
+        thumbs: &[ThumbnailData],
+        scores: &mut [f64],
+    ) {
+        if self.structure_weight == 0.0 {
+            return;
+        }
+
+        let target = structure(chunk);
+        let unit = self.score_colors(&vec![[0; 3]; samples], &vec![[255; 3]; samples], &[]);
+        let weight = self.structure_weight as f64 * unit;
+        for (score, thumb) in zip(scores, thumbs) {
+            if let Some(thumb_structure) = thumb.structure
+                && score.is_finite()
+            {
+                *score += weight * structure_difference(target, thumb_structure) as f64;
+            }
+        }
+    }
+
+    /// Difference between a chunk and a pooled thumbnail, using the thumbnail's cached
+    /// Oklab, CIELAB or HSV colours and the chunk's from `chunk_lab`, each sample scaled by
+    /// `weights`
+    pub(crate) fn score_thumb(
+        &self,
+        pixels: &[[u8; 3]],
+        lab: &[[f32; 3]],
+        weights: &[f32],
+        thumb: &ThumbnailData,
+    ) -> f64 {
+        match self.algorithm {
+            DifferenceFunction::Oklab => compare_thumbs_f32(lab, &thumb.oklab, weights) as f64,
+            DifferenceFunction::Cielab => compare_thumbs_f32(lab, &thumb.cielab, weights) as f64,
+            DifferenceFunction::Ciede2000 => {
+                compare_thumbs_ciede2000(lab, &thumb.cielab, weights) as f64
+            }
+            DifferenceFunction::Hsv => compare_thumbs_hsv(lab, &thumb.hsv, weights) as f64,
+            DifferenceFunction::Rgb => compare_thumbs_u8(pixels, &thumb.colors, weights),
+            DifferenceFunction::AverageColor => {
+                compare_thumbs_f32(lab, thumb.mean_oklab.as_slice(), &[]) as f64
+            }
+        }
+    }
+}
+
+/// How a chunk is scored against the whole pool
+pub(crate) enum Search {
+    /// Score every thumbnail one after another
+    Scan,
+    /// Score every thumbnail in one pass over the pool laid out for it
+    Batched(PoolColumns),
+    /// Only score the nearest thumbnail, found through a k-d tree
+    Nearest(KdTree),
+    /// Only score the thumbnails of average colour close to the chunk's
+    Buckets(ColorBuckets),
+}
+
+/// The input image and locked thumbnail pool that cells are scored against, shared by
+/// every matching task. The pool and its index are shared with every other image
+/// matched against them too
+pub(crate) struct Matcher {
+    pub(crate) input: RgbImage,
+    /// The order here is the order chunk scores are reported in
+    pub(crate) pool: Arc<Vec<ThumbnailData>>,
+    /// Colours of each pool entry for the coarse pass, empty without one
+    pub(crate) coarse_colors: Arc<Vec<Vec<[u8; 3]>>>,
+    /// Resolution and candidate count of the coarse pass
+    pub(crate) coarse: Option<(u32, usize)>,
+    /// How each cell is scored against the pool
+    pub(crate) search: Arc<Search>,
+    pub(crate) metric: Metric,
+    /// Cell width and height
+    pub(crate) thumbsize: (u32, u32),
+    pub(crate) matchres: u32,
+    pub(crate) stat: SampleStat,
+    pub(crate) filter: ResizeFilter,
+    /// Orientations each thumbnail is tried in, the first being unflipped
+    pub(crate) orientations: &'static [Flip],
+}
+
+impl Matcher {
+    pub(crate) fn chunk(&self, x: u32, y: u32) -> RgbImage {
+        extract_chunk(
+            &self.input,
+            x * self.thumbsize.0,
+            y * self.thumbsize.1,
+            self.thumbsize,
+        )
+    }
+
+    /// Score every pool entry against the cell at `x`, `y`, in pool order. Each entry
+    /// scores as well as its best orientation
+    pub(crate) fn score_cell(&self, x: u32, y: u32) -> Vec<f64> {
+        let chunk = self.chunk(x, y);
+        let mut scores = self.score_chunk(&chunk);
+
+        // Comparing the chunk mirrored is the same as comparing every thumbnail mirrored,
+        // and turning it one way the same as turning every thumbnail back the other
+        for flip in &self.orientations[1..] {
+            let turned = flip.inverse().apply(&chunk);
+            for (score, flipped) in zip(&mut scores, self.score_chunk(&turned)) {
+                *score = score.min(flipped);
+            }
+        }
+
+        scores
+    }
+
+    /// The orientation pool entry `thumb` fits the cell at `x`, `y` best in
+    pub(crate) fn orientation(&self, x: u32, y: u32, thumb: usize) -> Flip {
+        if self.orientations.len() == 1 {
+            return Flip::None;
+        }
+
+        let chunk = self.chunk(x, y);
+        let mut best = (Flip::None, f64::INFINITY);
+        for &flip in self.orientations {
+            let score = process_chunk(
+                &flip.inverse().apply(&chunk),
+                self.matchres,
+                self.stat,
+                self.filter,
+                self.metric,
+                &self.pool[thumb..=thumb],
+                &Search::Scan,
+            )[0];
+            if score < best.1 {
+                best = (flip, score);
+            }
+        }
+
+        best.0
+    }
+
+    fn score_chunk(&self, chunk: &RgbImage) -> Vec<f64> {
+        match self.coarse {
+            Some((coarse_res, coarse_top)) => process_chunk_two_stage(
+                chunk,
+                (coarse_res, self.matchres),
+                coarse_top,
+                (self.stat, self.filter),
+                self.metric,
+                &self.coarse_colors,
+                &self.pool,
+            ),
+            None => process_chunk(
+                chunk,
+                self.matchres,
+                self.stat,
+                self.filter,
+                self.metric,
+                &self.pool,
+                &self.search,
+            ),
+        }
+    }
+}
+
+/// Summed squared RGB difference, each sample scaled by `weights` unless it's empty. The
+/// sum is kept in an `i64`, since a whole cell's worth of samples in full match mode can
+/// add up past `i32::MAX`
+fn compare_thumbs_u8(a: &[[u8; 3]], b: &[[u8; 3]], weights: &[f32]) -> f64 {
+    if a.len() != b.len() {
+        return f64::MAX;
+    }
+
+    let term = |x: &[u8; 3], y: &[u8; 3]| {
+        (x[0] as i32 - y[0] as i32).pow(2u32)
+            + (x[1] as i32 - y[1] as i32).pow(2u32)
+            + (x[2] as i32 - y[2] as i32).pow(2u32)
+    };
+
+    if weights.is_empty() {
+        let mut diff = 0i64;
+        for (x, y) in zip(a, b) {
+            diff += term(x, y) as i64;
+        }
+        return diff as f64;
+    }
+
+    zip(zip(a, b), weights)
+        .map(|((x, y), w)| term(x, y) as f64 * *w as f64)
+        .sum()
+}
+
+/// Summed squared difference, each sample scaled by `weights` unless it's empty
+fn compare_thumbs_f32(a: &[[f32; 3]], b: &[[f32; 3]], weights: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return f32::MAX;
+    }
+
+    let term = |x: &[f32; 3], y: &[f32; 3]| {
+        (x[0] - y[0]).powi(2i32) + (x[1] - y[1]).powi(2i32) + (x[2] - y[2]).powi(2i32)
+    };
+
+    let mut diff = 0f32;
+    if weights.is_empty() {
+        for (x, y) in zip(a, b) {
+            diff += term(x, y);
+        }
+    } else {
+        for ((x, y), w) in zip(zip(a, b), weights) {
+            diff += term(x, y) * w;
+        }
+    }
+
+    diff
+}
+
+fn compare_thumbs_oklab(
+    a: &[[u8; 3]],
+    b: &[[u8; 3]],
+    lab_weights: &[f32; 3],
+    weights: &[f32],
+) -> f32 {
+    let a_rgb = a
+        .iter()
+        .map(|v| lab_to_f32(srgb_to_oklab(oklab::Rgb::from(*v)), lab_weights));
+    let b_rgb = b
+        .iter()
+        .map(|v| lab_to_f32(srgb_to_oklab(oklab::Rgb::from(*v)), lab_weights));
+
+    compare_thumbs_f32(
+        &a_rgb.collect::<Vec<_>>(),
+        &b_rgb.collect::<Vec<_>>(),
+        weights,
+    )
+}
+
+/// Summed CIEDE2000 colour difference between two CIELAB grids, each sample scaled by
+/// `weights` unless it's empty
+fn compare_thumbs_ciede2000(a: &[[f32; 3]], b: &[[f32; 3]], weights: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return f32::MAX;
+    }
+
+    if weights.is_empty() {
+        return zip(a, b).map(|(x, y)| ciede2000(*x, *y) as f32).sum();
+    }
+
+    zip(zip(a, b), weights)
+        .map(|((x, y), w)| ciede2000(*x, *y) as f32 * w)
+        .sum()
+}
+
+/// Summed HSV difference between two HSV grids, each sample scaled by `weights` unless
+/// it's empty
+fn compare_thumbs_hsv(a: &[[f32; 3]], b: &[[f32; 3]], weights: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return f32::MAX;
+    }
+
+    if weights.is_empty() {
+        return zip(a, b).map(|(x, y)| hsv_difference(*x, *y)).sum();
+    }
+
+    zip(zip(a, b), weights)
+        .map(|((x, y), w)| hsv_difference(*x, *y) * w)
+        .sum()
+}
+
+/// How much hue counts against saturation and value in `hsv_difference`
+const HSV_HUE_WEIGHT: f32 = 2.0;
+const HSV_VALUE_WEIGHT: f32 = 0.5;
+
+/// Mean absolute difference between two `structure` descriptors, from 0 to 1
+fn structure_difference(a: [f32; 3], b: [f32; 3]) -> f32 {
+    zip(a, b).map(|(a, b)| (a - b).abs()).sum::<f32>() / 3.0
+}
+
+/// Difference between two HSV colours, each component from 0 to 1. Hue is compared the
+/// short way round the colour wheel, so 359° and 1° are 2° apart, and counts for as much
+/// as both colours are saturated, since the hue of a grey means nothing. Saturation and
+/// value add their absolute difference, value only at half weight so brightness matters
+/// least
+fn hsv_difference([h1, s1, v1]: [f32; 3], [h2, s2, v2]: [f32; 3]) -> f32 {
+    let hue = (h1 - h2).abs();
+    // Half a turn apart at most, scaled to 0..1
+    let hue = 2.0 * hue.min(1.0 - hue);
+
+    HSV_HUE_WEIGHT * hue * s1.min(s2) + (s1 - s2).abs() + HSV_VALUE_WEIGHT * (v1 - v2).abs()
+}
+
+/// CIEDE2000 difference between two CIELAB colours, with unit weighting factors
+fn ciede2000([l1, a1, b1]: [f32; 3], [l2, a2, b2]: [f32; 3]) -> f64 {
+    let (l1, a1, b1) = (l1 as f64, a1 as f64, b1 as f64);
+    let (l2, a2, b2) = (l2 as f64, a2 as f64, b2 as f64);
+
+    let c_bar = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+    let g = 0.5 * (1.0 - (c_bar.powi(7) / (c_bar.powi(7) + 25f64.powi(7))).sqrt());
+    let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+
+    let dl = l2 - l1;
+    let dc = c2 - c1;
+    let dh = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 <= h1 {
+        h2 - h1 + 360.0
+    } else {
+        h2 - h1 - 360.0
+    };
+    let dh = 2.0 * (c1 * c2).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (h_bar - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar).to_radians().cos()
+        + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (c_bar.powi(7) / (c_bar.powi(7) + 25f64.powi(7))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar;
+    let s_h = 1.0 + 0.015 * c_bar * t;
+    let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+
+    ((dl / s_l).powi(2) + (dc / s_c).powi(2) + (dh / s_h).powi(2) + r_t * (dc / s_c) * (dh / s_h))
+        .sqrt()
+}
+
+/// CIELAB `[L*, a*, b*]` (D65 white) of each sRGB colour
+pub(crate) fn cielab_colors(colors: &[[u8; 3]]) -> Vec<[f32; 3]> {
+    colors
+        .iter()
+        .map(|rgb| {
+            let [r, g, b] = rgb.map(|c| srgb_to_linear(c) as f64);
+            let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+            let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+            let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+            let f = |t: f64| {
+                if t > 216.0 / 24389.0 {
+                    t.cbrt()
+                } else {
+                    (24389.0 / 27.0 * t + 16.0) / 116.0
+                }
+            };
+            let (fx, fy, fz) = (f(x), f(y), f(z));
+
+            [
+                (116.0 * fy - 16.0) as f32,
+                (500.0 * (fx - fy)) as f32,
+                (200.0 * (fy - fz)) as f32,
+            ]
+        })
+        .collect()
+}
+
+/// HSV `[hue, saturation, value]` of each sRGB colour, all from 0 to 1. Greys have a hue
+/// of 0
+pub(crate) fn hsv_colors(colors: &[[u8; 3]]) -> Vec<[f32; 3]> {
+    colors
+        .iter()
+        .map(|rgb| {
+            let [r, g, b] = rgb.map(|c| c as f32 / 255.0);
+            let max = r.max(g).max(b);
+            let chroma = max - r.min(g).min(b);
+
+            let hue = if chroma == 0.0 {
+                0.0
+            } else if max == r {
+                ((g - b) / chroma).rem_euclid(6.0)
+            } else if max == g {
+                (b - r) / chroma + 2.0
+            } else {
+                (r - g) / chroma + 4.0
+            };
+            let saturation = if max == 0.0 { 0.0 } else { chroma / max };
+
+            [hue / 6.0, saturation, max]
+        })
+        .collect()
+}
+
+/// Unweighted Oklab `[l, a, b]` of each colour
+pub(crate) fn oklab_colors(colors: &[[u8; 3]]) -> Vec<[f32; 3]> {
+    colors
+        .iter()
+        .map(|v| lab_to_f32(srgb_to_oklab(oklab::Rgb::from(*v)), &[1.0; 3]))
+        .collect()
+}
+
+/// Average of Oklab, CIELAB or any other colours whose components can be averaged
+pub(crate) fn mean_lab(colors: &[[f32; 3]]) -> [f32; 3] {
+    let mut sum = [0.0; 3];
+    for color in colors {
+        for c in 0..3 {
+            sum[c] += color[c];
+        }
+    }
+
+    sum.map(|s| s / colors.len().max(1) as f32)
+}
+
+pub(crate) fn lab_to_f32(lab: Oklab, weights: &[f32; 3]) -> [f32; 3] {
+    [lab.l * weights[0], lab.a * weights[1], lab.b * weights[2]]
+}
+
+/// A tokio runtime running work on `threads` threads, or one per logical CPU
+#[cfg(feature = "tokio")]
+pub(crate) fn runtime(threads: Option<usize>) -> tokio::runtime::Runtime {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = threads {
+        builder
+            .worker_threads(threads)
+            .max_blocking_threads(threads);
+    }
+
+    builder
+        .enable_all()
+        .build()
+        .expect("to start the tokio runtime")
+}
+
+/// Run `f` on a rayon pool of `threads` threads, or on the global pool of one per
+/// logical CPU
+#[cfg(all(feature = "rayon", not(feature = "tokio")))]
+pub(crate) fn in_pool<T: Send>(threads: Option<usize>, f: impl FnOnce() -> T + Send) -> T {
+    match threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("to start the rayon thread pool")
+            .install(f),
+        None => f(),
+    }
+}
+
+/// Match every cell on the tokio runtime, handing scores to `on_result` as they complete.
+/// Returns whether Ctrl-C interrupted the run before every cell was matched, a second one
+/// exits
+#[cfg(feature = "tokio")]
+pub(crate) fn match_cells<F, R>(
+    cells: Vec<(u32, u32)>,
+    threads: Option<usize>,
+    score_cell: F,
+    mut on_result: R,
+) -> bool
+where
+    F: Fn(u32, u32) -> Vec<f64> + Send + Sync + 'static,
+    R: FnMut(u32, u32, Vec<f64>),
+{
+    let runtime = runtime(threads);
+    let score_cell = Arc::new(score_cell);
+
+    runtime.block_on(async move {
+        // Create a set of tasks to process chunks async
+        let mut tasks = task::JoinSet::new();
+
+        for (x, y) in cells {
+            let score_cell = score_cell.clone();
+            tasks.spawn(async move { (x, y, score_cell(x, y)) });
+        }
+
+        let mut interrupted = false;
+        let ctrl_c = crate::interrupt::interrupted();
+        tokio::pin!(ctrl_c);
+
+        loop {
+            let res = tokio::select! {
+                res = tasks.join_next() => match res {
+                    Some(res) => res,
+                    None => break,
+                },
+                _ = &mut ctrl_c, if !interrupted => {
+                    // Chunks that haven't started are dropped, running ones still finish
+                    log::warn!("Interrupted, waiting for in-flight chunks (Ctrl-C again to exit now)...");
+                    interrupted = true;
+                    tasks.abort_all();
+                    continue;
+                }
+            };
+
+            match res {
+                Err(e) if e.is_cancelled() => continue,
+                res => {
+                    let (x, y, scores) = res.expect("thread failed :(");
+                    on_result(x, y, scores);
+                }
+            }
+        }
+
+        interrupted
+    })
+}
+
+/// Match every cell on the rayon thread pool, handing scores to `on_result` as they
+/// complete. Ctrl-C isn't handled without tokio, so this is never interrupted
+#[cfg(all(feature = "rayon", not(feature = "tokio")))]
+pub(crate) fn match_cells<F, R>(
+    cells: Vec<(u32, u32)>,
+    threads: Option<usize>,
+    score_cell: F,
+    mut on_result: R,
+) -> bool
+where
+    F: Fn(u32, u32) -> Vec<f64> + Send + Sync + 'static,
+    R: FnMut(u32, u32, Vec<f64>),
+{
+    use rayon::prelude::*;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            in_pool(threads, || {
+                cells
+                    .into_par_iter()
+                    .for_each_with(sender, |sender, (x, y)| {
+                        sender.send((x, y, score_cell(x, y))).ok();
+                    });
+            })
+        });
+
+        for (x, y, scores) in receiver {
+            on_result(x, y, scores);
+        }
+    });
+
+    false
+}
+
+#[cfg(not(any(feature = "tokio", feature = "rayon")))]
+compile_error!("imagegrid needs either the `tokio` or the `rayon` feature enabled");
+
+/// Match cells one at a time in raster order, handing scores to `on_result`. Each cell's
+/// residual (its target minus the chosen thumbnail's colours) is spread over the targets
+/// of the neighbouring cells still to come, Floyd-Steinberg style
+pub(crate) fn dither_cells<R>(
+    matcher: &Matcher,
+    mut cells: Vec<(u32, u32)>,
+    (x_chunks, y_chunks): (u32, u32),
+    mut on_result: R,
+) where
+    R: FnMut(u32, u32, Vec<f64>),
+{
+    const NEIGHBOURS: [(i64, i64, f32); 4] = [
+        (1, 0, 7.0 / 16.0),
+        (-1, 1, 3.0 / 16.0),
+        (0, 1, 5.0 / 16.0),
+        (1, 1, 1.0 / 16.0),
+    ];
+
+    let Matcher {
+        pool,
+        metric,
+        matchres,
+        stat,
+        filter,
+        search,
+        ..
+    } = matcher;
+    let samples = (matchres * matchres) as usize;
+    let mut errors = vec![vec![[0f32; 3]; samples]; (x_chunks * y_chunks) as usize];
+
+    cells.sort_by_key(|&(x, y)| (y, x));
+    for (x, y) in cells {
+        let chunk = DynamicImage::from(matcher.chunk(x, y));
+
+        let target: Vec<[f32; 3]> = zip(
+            sample_image(&chunk, *matchres, *stat, *filter),
+            &errors[(y * x_chunks + x) as usize],
+        )
+        .map(|(color, error)| std::array::from_fn(|c| color[c] as f32 + error[c]))
+        .collect();
+        let pixels: Vec<[u8; 3]> = target
+            .iter()
+            .map(|color| color.map(|c| c.round().clamp(0.0, 255.0) as u8))
+            .collect();
+
+        let mut scores = score_pixels(&pixels, *metric, pool, search);
+        metric.add_structure(&chunk, pixels.len(), pool, &mut scores);
+        let best = &pool[best_index(&scores).expect("To process image chunk")];
+
+        for (dx, dy, weight) in NEIGHBOURS {
+            let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+            if nx < 0 || nx >= x_chunks as i64 || ny >= y_chunks as i64 {
+                continue;
+            }
+
+            let neighbour = &mut errors[(ny as u32 * x_chunks + nx as u32) as usize];
+            for ((error, target), chosen) in zip(zip(neighbour, &target), &best.colors) {
+                for c in 0..3 {
+                    error[c] += (target[c] - chosen[c] as f32) * weight;
+                }
+            }
+        }
+
+        on_result(x, y, scores);
+    }
+}
+
+/// Score every thumbnail against the chunk, in pool order (lower is better)
+fn process_chunk(
+    chunk: &RgbImage,
+    sampleres: u32,
+    stat: SampleStat,
+    filter: ResizeFilter,
+    metric: Metric,
+    thumbs: &[ThumbnailData],
+    search: &Search,
+) -> Vec<f64> {
+    let chunk = DynamicImage::from(chunk.clone());
+    let pixels = sample_image(&chunk, sampleres, stat, filter);
+
+    let mut scores = score_pixels(&pixels, metric, thumbs, search);
+    metric.add_structure(&chunk, pixels.len(), thumbs, &mut scores);
+    scores
+}
+
+/// Score every thumbnail against sampled chunk colours, in pool order. With an index
+/// only the nearest thumbnail is scored, and with buckets only the candidates they
+/// give. Everything else scores infinity
+pub(crate) fn score_pixels(
+    pixels: &[[u8; 3]],
+    metric: Metric,
+    thumbs: &[ThumbnailData],
+    search: &Search,
+) -> Vec<f64> {
+    let lab = metric.chunk_lab(pixels);
+    let weights = metric.weights(pixels.len());
+
+    let index = match search {
+        Search::Scan => {
+            return thumbs
+                .iter()
+                .map(|ref_thumb| metric.score_thumb(pixels, &lab, &weights, ref_thumb))
+                .collect();
+        }
+        Search::Batched(columns) => return columns.scores(pixels, &lab, &weights),
+        Search::Nearest(index) => index,
+        Search::Buckets(buckets) => return buckets.scores(pixels, &lab, &weights, metric, thumbs),
+    };
+
+    let mut scores = vec![f64::INFINITY; thumbs.len()];
+    if let Some(i) = index.nearest(&metric.index_colors(pixels, &lab)) {
+        scores[i] = metric.score_thumb(pixels, &lab, &weights, &thumbs[i]);
+    }
+
+    scores
+}
+
+/// Score the chunk against every thumbnail's coarse colours, then rescore only the
+/// `top` best of those at the fine resolution. Everything else scores infinity
+fn process_chunk_two_stage(
+    chunk: &RgbImage,
+    (coarse_res, fine_res): (u32, u32),
+    top: usize,
+    (stat, filter): (SampleStat, ResizeFilter),
+    metric: Metric,
+    coarse_colors: &[Vec<[u8; 3]>],
+    thumbs: &[ThumbnailData],
+) -> Vec<f64> {
+    let chunk = DynamicImage::from(chunk.clone());
+
+    let coarse_pixels = sample_image(&chunk, coarse_res, stat, filter);
+    let coarse_weights = metric.weights(coarse_pixels.len());
+    let coarse_scores: Vec<f64> = coarse_colors
+        .iter()
+        .map(|colors| metric.score_colors(&coarse_pixels, colors, &coarse_weights))
+        .collect();
+
+    let mut candidates: Vec<usize> = (0..thumbs.len()).collect();
+    let top = top.clamp(1, candidates.len());
+    // Ties break on pool index so the same candidates make the cut on every run
+    candidates.select_nth_unstable_by(top - 1, |&a, &b| {
+        coarse_scores[a]
+            .total_cmp(&coarse_scores[b])
+            .then(a.cmp(&b))
+    });
+
+    let fine_pixels = sample_image(&chunk, fine_res, stat, filter);
+    let fine_lab = metric.chunk_lab(&fine_pixels);
+    let fine_weights = metric.weights(fine_pixels.len());
+    let mut scores = vec![f64::INFINITY; thumbs.len()];
+    for &i in &candidates[..top] {
+        scores[i] = metric.score_thumb(&fine_pixels, &fine_lab, &fine_weights, &thumbs[i]);
+    }
+    metric.add_structure(&chunk, fine_pixels.len(), thumbs, &mut scores);
+
+    scores
+}
+
+/// Indices of `scores` from best to worst, earlier indices first among ties
+pub(crate) fn ranked(scores: &[f64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|&a, &b| scores[a].total_cmp(&scores[b]));
+    order
+}
+
+/// Whether pool index `thumb` is placed within `radius` cells of `(x, y)`
+pub(crate) fn placed_nearby(
+    placed: &HashMap<(u32, u32), usize>,
+    (x, y): (u32, u32),
+    radius: u32,
+    thumb: usize,
+) -> bool {
+    let xs = x.saturating_sub(radius)..=x.saturating_add(radius);
+    let ys = y.saturating_sub(radius)..=y.saturating_add(radius);
+
+    ys.flat_map(|ny| xs.clone().map(move |nx| (nx, ny)))
+        .any(|cell| cell != (x, y) && placed.get(&cell) == Some(&thumb))
+}
+
+/// Index of the lowest score, the earliest wins ties. Pool order is path order, so an
+/// exact tie always goes to the thumbnail whose path sorts first
+pub(crate) fn best_index(scores: &[f64]) -> Option<usize> {
+    let mut best: Option<(usize, f64)> = None;
+
+    for (i, &score) in scores.iter().enumerate() {
+        if best.is_none_or(|(_, best_score)| score < best_score) {
+            best = Some((i, score));
+        }
+    }
+
+    best.map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::linear_to_srgb;
+
+    #[test]
+    fn unequal_lengths_score_the_maximum() {
+        let rgb = [[10, 20, 30]; 4];
+        assert_eq!(compare_thumbs_u8(&rgb, &rgb[..3], &[]), f64::MAX);
+        assert_eq!(compare_thumbs_u8(&rgb[..3], &rgb, &[1.0; 3]), f64::MAX);
+
+        let lab = [[0.5, 0.1, -0.1]; 4];
+        assert_eq!(compare_thumbs_f32(&lab, &lab[..2], &[]), f32::MAX);
+        assert_eq!(compare_thumbs_f32(&lab[..2], &lab, &[1.0; 2]), f32::MAX);
+    }
+
+    /// Pairs from Sharma, Wu and Dalal's CIEDE2000 test data, with their published
+    /// differences
+    #[test]
+    fn ciede2000_matches_reference_pairs() {
+        let pairs = [
+            ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+            ([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+            ([50.0, 2.8361, -74.0200], [50.0, 0.0, -82.7485], 3.4412),
+            ([50.0, -1.3802, -84.2814], [50.0, 0.0, -82.7485], 1.0),
+            ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+            ([50.0, 2.49, -0.001], [50.0, -2.49, 0.0011], 7.2195),
+            ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+            ([50.0, 2.5, 0.0], [50.0, 3.2592, 0.335], 1.0),
+            (
+                [60.2574, -34.0099, 36.2677],
+                [60.4626, -34.1751, 39.4387],
+                1.2644,
+            ),
+            (
+                [22.7233, 20.0904, -46.694],
+                [23.0331, 14.973, -42.5619],
+                2.0373,
+            ),
+            ([2.0776, 0.0795, -1.135], [0.9033, -0.0636, -0.5514], 0.9082),
+        ];
+
+        for (a, b, expected) in pairs {
+            let difference = ciede2000(a, b);
+            assert!(
+                (difference - expected).abs() < 1e-4,
+                "{a:?} vs {b:?} gave {difference}, expected {expected}"
+            );
+            assert!((ciede2000(b, a) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn hsv_hue_wraps_around() {
+        let near_red = hsv_difference([359.0 / 360.0, 1.0, 1.0], [1.0 / 360.0, 1.0, 1.0]);
+        let opposite = hsv_difference([0.0, 1.0, 1.0], [0.5, 1.0, 1.0]);
+
+        assert!((near_red - HSV_HUE_WEIGHT * 2.0 / 180.0).abs() < 1e-5);
+        assert!((opposite - HSV_HUE_WEIGHT).abs() < 1e-5);
+        // Hue means nothing for greys
+        assert_eq!(hsv_difference([0.0, 0.0, 0.5], [0.5, 0.0, 0.5]), 0.0);
+    }
+
+    #[test]
+    fn cielab_matches_reference_values() {
+        let references = [
+            ([255, 255, 255], [100.0, 0.0, 0.0]),
+            ([0, 0, 0], [0.0, 0.0, 0.0]),
+            ([255, 0, 0], [53.2408, 80.0925, 67.2032]),
+            ([0, 255, 0], [87.7347, -86.1827, 83.1793]),
+            ([0, 0, 255], [32.2970, 79.1875, -107.8602]),
+            ([128, 128, 128], [53.5850, 0.0, 0.0]),
+        ];
+
+        for (rgb, expected) in references {
+            let lab = cielab_colors(&[rgb])[0];
+            for (c, e) in zip(lab, expected) {
+                assert!(
+                    (c - e).abs() < 0.01,
+                    "{rgb:?} gave {lab:?}, expected {expected:?}"
+                );
+            }
+        }
+    }
+
+    /// The sRGB colour of a D65 CIELAB colour, undoing `cielab_colors`
+    fn cielab_to_srgb([l, a, b]: [f32; 3]) -> [u8; 3] {
+        let fy = (l as f64 + 16.0) / 116.0;
+        let (fx, fz) = (fy + a as f64 / 500.0, fy - b as f64 / 200.0);
+        let f_inv = |f: f64| {
+            if f.powi(3) > 216.0 / 24389.0 {
+                f.powi(3)
+            } else {
+                (116.0 * f - 16.0) / (24389.0 / 27.0)
+            }
+        };
+        let (x, y, z) = (f_inv(fx) * 0.95047, f_inv(fy), f_inv(fz) * 1.08883);
+
+        [
+            3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+            -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+            0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+        ]
+        .map(|c| linear_to_srgb(c as f32))
+    }
+
+    #[test]
+    fn cielab_round_trips() {
+        let colors: Vec<[u8; 3]> = (0..=255u8)
+            .step_by(15)
+            .flat_map(|r| (0..=255u8).step_by(15).map(move |g| (r, g)))
+            .flat_map(|(r, g)| (0..=255u8).step_by(15).map(move |b| [r, g, b]))
+            .collect();
+
+        for (rgb, lab) in zip(&colors, cielab_colors(&colors)) {
+            assert_eq!(cielab_to_srgb(lab), *rgb, "{lab:?}");
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    borrow::Cow,
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use image::{ImageEncoder, ImageError, ImageFormat, RgbImage};
+use serde::{Deserialize, Serialize};
+
+/// Output formats that can be picked by name instead of from the file extension
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    /// Always lossless
+    Webp,
+    /// Much smaller than JPEG at the same quality, but slow to encode
+    Avif,
+}
+
+impl From<OutputFormat> for ImageFormat {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Webp => ImageFormat::WebP,
+            OutputFormat::Avif => ImageFormat::Avif,
+        }
+    }
+}
+
+/// Save `image` to `path` as `format`, or in the format its extension names if that's
+/// `None`, recording `comment` in the EXIF metadata of formats that can carry it.
+/// `quality` (1 to 100) only applies to JPEG and AVIF, other formats ignore it
+pub fn save_image(
+    image: &RgbImage,
+    path: &Path,
+    format: Option<ImageFormat>,
+    comment: Option<&str>,
+    quality: Option<u8>,
+) -> image::ImageResult<()> {
+    let format = match format {
+        Some(format) => format,
+        None => ImageFormat::from_path(path)?,
+    };
+    let lossy = matches!(format, ImageFormat::Jpeg | ImageFormat::Avif);
+    if comment.is_none() && (quality.is_none() || !lossy) {
+        return image.save_with_format(path, format);
+    }
+
+    // Encoded in memory first, so a failed encode leaves any existing file alone
+    let mut encoded = Vec::new();
+    let writer = &mut encoded;
+    let exif = comment.map(exif_user_comment);
+
+    // Only some encoders can carry EXIF, everything else is saved as usual
+    match format {
+        ImageFormat::Png => {
+            let mut encoder = image::codecs::png::PngEncoder::new(writer);
+            if let Some(exif) = exif {
+                encoder.set_exif_metadata(exif).ok();
+            }
+            image.write_with_encoder(encoder)?;
+        }
+        ImageFormat::Jpeg => {
+            let mut encoder = match quality {
+                Some(quality) => {
+                    image::codecs::jpeg::JpegEncoder::new_with_quality(writer, quality)
+                }
+                None => image::codecs::jpeg::JpegEncoder::new(writer),
+            };
+            if let Some(exif) = exif {
+                encoder.set_exif_metadata(exif).ok();
+            }
+            image.write_with_encoder(encoder)?;
+        }
+        ImageFormat::WebP => {
+            let mut encoder = image::codecs::webp::WebPEncoder::new_lossless(writer);
+            if let Some(exif) = exif {
+                encoder.set_exif_metadata(exif).ok();
+            }
+            image.write_with_encoder(encoder)?;
+        }
+        // AVIF can't carry EXIF here, but takes a quality like JPEG
+        ImageFormat::Avif => {
+            let encoder = match quality {
+                // The speed `AvifEncoder::new` uses
+                Some(quality) => {
+                    image::codecs::avif::AvifEncoder::new_with_speed_quality(writer, 4, quality)
+                }
+                None => image::codecs::avif::AvifEncoder::new(writer),
+            };
+            image.write_with_encoder(encoder)?;
+        }
+        _ => return image.save_with_format(path, format),
+    }
+
+    fs::write(path, encoded)?;
+    Ok(())
+}
+
+/// Writes an image to a file a band of rows at a time, in the format given or the one
+/// its extension names. PNG and PPM are encoded as each band arrives, so only that band
+/// is held in memory. Other formats are assembled in full and saved by `finish`
+pub struct StripWriter {
+    path: PathBuf,
+    format: ImageFormat,
+    target: StripTarget,
+    comment: Option<String>,
+    quality: Option<u8>,
+}
+
+enum StripTarget {
+    Png(Box<png::StreamWriter<'static, BufWriter<fs::File>>>),
+    Ppm(BufWriter<fs::File>),
+    /// The image so far and how many of its rows are filled
+    Assembled(RgbImage, u32),
+}
+
+impl StripWriter {
+    /// Start writing a `width` by `height` image to `path`, with `format`, `comment` and
+    /// `quality` as `save_image` takes them
+    pub fn create(
+        path: &Path,
+        format: Option<ImageFormat>,
+        (width, height): (u32, u32),
+        comment: Option<&str>,
+        quality: Option<u8>,
+    ) -> image::ImageResult<Self> {
+        let format = match format {
+            Some(format) => format,
+            None => ImageFormat::from_path(path)?,
+        };
+        let is_ppm = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("ppm"));
+
+        let target = match format {
+            ImageFormat::Png => {
+                let mut info = png::Info::with_size(width, height);
+                info.color_type = png::ColorType::Rgb;
+                info.bit_depth = png::BitDepth::Eight;
+                if let Some(comment) = comment {
+                    info.exif_metadata = Some(Cow::Owned(exif_user_comment(comment)));
+                }
+
+                let writer = BufWriter::new(fs::File::create(path)?);
+                // Streamed with `Balanced` this is several times slower than `save_image`, while
+                // `Fast` matches it in both speed and size
+                let mut encoder = png::Encoder::with_info(writer, info)
+                    .map_err(|e| ImageError::IoError(e.into()))?;
+                encoder.set_compression(png::Compression::Fast);
+                encoder.set_filter(png::Filter::Adaptive);
+                let stream = encoder
+                    .write_header()
+                    .and_then(|writer| writer.into_stream_writer())
+                    .map_err(|e| ImageError::IoError(e.into()))?;
+
+                StripTarget::Png(Box::new(stream))
+            }
+            ImageFormat::Pnm if is_ppm => {
+                let mut writer = BufWriter::new(fs::File::create(path)?);
+                write!(writer, "P6\n{} {}\n255\n", width, height)?;
+
+                StripTarget::Ppm(writer)
+            }
+            _ => StripTarget::Assembled(RgbImage::new(width, height), 0),
+        };
+
+        Ok(StripWriter {
+            path: path.to_path_buf(),
+            format,
+            target,
+            comment: comment.map(String::from),
+            quality,
+        })
+    }
+
+    /// Whether bands are encoded as they arrive, rather than held until `finish`
+    pub fn is_streaming(&self) -> bool {
+        !matches!(self.target, StripTarget::Assembled(..))
+    }
+
+    /// Append the next band of rows, which must be as wide as the image
+    pub fn write_strip(&mut self, strip: &RgbImage) -> image::ImageResult<()> {
+        match &mut self.target {
+            StripTarget::Png(stream) => stream.write_all(strip.as_raw())?,
+            StripTarget::Ppm(writer) => writer.write_all(strip.as_raw())?,
+            StripTarget::Assembled(image, filled) => {
+                image::imageops::replace(image, strip, 0, *filled as i64);
+                *filled += strip.height();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finish the file once every row has been written
+    pub fn finish(self) -> image::ImageResult<()> {
+        match self.target {
+            StripTarget::Png(stream) => stream.finish().map_err(|e| ImageError::IoError(e.into())),
+            StripTarget::Ppm(mut writer) => Ok(writer.flush()?),
+            StripTarget::Assembled(image, _) => {
+                let (path, format) = (&self.path, Some(self.format));
+                save_image(&image, path, format, self.comment.as_deref(), self.quality)
+            }
+        }
+    }
+}
+
+/// Build a minimal little-endian EXIF block holding only a UserComment tag
+fn exif_user_comment(comment: &str) -> Vec<u8> {
+    let mut text = b"ASCII\0\0\0".to_vec();
+    text.extend_from_slice(comment.as_bytes());
+
+    let mut exif = Vec::new();
+    // TIFF header, IFD0 follows immediately
+    exif.extend_from_slice(b"II*\0");
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    // IFD0: a single pointer to the Exif IFD at offset 26
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&0x8769u16.to_le_bytes());
+    exif.extend_from_slice(&4u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&26u32.to_le_bytes());
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    // Exif IFD: UserComment stored out of line at offset 44
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&0x9286u16.to_le_bytes());
+    exif.extend_from_slice(&7u16.to_le_bytes());
+    exif.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    exif.extend_from_slice(&44u32.to_le_bytes());
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    exif.extend_from_slice(&text);
+
+    exif
+}
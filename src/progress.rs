@@ -0,0 +1,208 @@
+use std::{
+    io::{IsTerminal, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Stop drawing progress bars and the headings over them, for scripts. Logged messages are
+/// still printed at their level
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Print `text` on stderr as a heading over the progress bars that follow, unless
+/// `set_quiet` turned them off
+pub(crate) fn heading(text: &str) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("{}", text);
+    }
+}
+
+/// Print the `log` records this crate emits on stderr: warnings by default, each imported
+/// thumbnail and skipped file at a `verbose` of 1, each cell's match at 2 and everything
+/// at 3. `RUST_LOG` takes over when it's set, as a comma separated list of levels and
+/// `target=level` pairs. Other crates only log warnings unless it says otherwise. Does
+/// nothing if a logger is already installed
+pub fn init_logger(verbose: u8) {
+    let level = match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut logger = StderrLogger {
+        default: LevelFilter::Warn,
+        targets: vec![(String::from(env!("CARGO_CRATE_NAME")), level)],
+    };
+
+    if let Ok(spec) = std::env::var("RUST_LOG") {
+        logger.targets.clear();
+        for directive in spec.split(',').map(str::trim) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    if let Ok(level) = level.parse() {
+                        logger.targets.push((target.to_string(), level));
+                    }
+                }
+                None => {
+                    if let Ok(level) = directive.parse() {
+                        logger.default = level;
+                    }
+                }
+            }
+        }
+    }
+
+    let max_level = logger
+        .targets
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(logger.default, Ord::max);
+    if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Writes records on a line of their own, starting over any progress bar being drawn,
+/// which is redrawn below on its next tick
+struct StderrLogger {
+    /// Level for targets with no entry in `targets`
+    default: LevelFilter,
+    /// Levels of targets and the modules in them, the most specific one applying
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl StderrLogger {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(name, _)| {
+                target
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.level() {
+            Level::Error => eprintln!("\rError: {}", record.args()),
+            Level::Warn => eprintln!("\rWarning: {}", record.args()),
+            _ => eprintln!("\r{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Width of the bar itself, in characters
+const BAR_WIDTH: usize = 30;
+
+/// How often the bar is redrawn at most, so fast phases don't spend their time printing
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A progress bar on stderr with the rate and time left, drawn only when stderr is a
+/// terminal and `set_quiet` hasn't turned it off
+pub(crate) struct Progress {
+    label: &'static str,
+    total: usize,
+    done: usize,
+    start: Instant,
+    last_draw: Option<Instant>,
+    /// `done` as of the last draw
+    drawn: usize,
+    visible: bool,
+}
+
+impl Progress {
+    pub(crate) fn new(label: &'static str, total: usize) -> Self {
+        Progress {
+            label,
+            total,
+            done: 0,
+            start: Instant::now(),
+            last_draw: None,
+            drawn: 0,
+            visible: !QUIET.load(Ordering::Relaxed) && std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Count one more item as done
+    pub(crate) fn tick(&mut self) {
+        self.done += 1;
+
+        if self
+            .last_draw
+            .is_none_or(|last| last.elapsed() >= REDRAW_INTERVAL || self.done == self.total)
+        {
+            self.draw();
+        }
+    }
+
+    /// Draw the final state and move to the next line, leaving the bar in the scrollback
+    pub(crate) fn finish(mut self) {
+        if self.last_draw.is_some() {
+            if self.drawn != self.done {
+                self.draw();
+            }
+            eprintln!();
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.visible || self.total == 0 {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        self.drawn = self.done;
+
+        let fraction = self.done as f64 / self.total as f64;
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = self.done as f64 / elapsed.max(f64::EPSILON);
+        let time = if self.done < self.total {
+            let left = (self.total - self.done) as f64 / rate.max(f64::EPSILON);
+            format!("ETA {}", clock(left))
+        } else {
+            format!("in {}", clock(elapsed))
+        };
+
+        eprint!(
+            "\r{} [{}{}] {:>3}% {}/{} {:.0}/s {}   ",
+            self.label,
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            (fraction * 100.0) as u32,
+            self.done,
+            self.total,
+            rate,
+            time
+        );
+        std::io::stderr().flush().unwrap();
+    }
+}
+
+/// `seconds` as minutes and seconds, with hours once it gets that long
+fn clock(seconds: f64) -> String {
+    let seconds = seconds.min(u32::MAX as f64) as u32;
+
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
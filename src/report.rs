@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{DifferenceFunction, Mosaic};
+
+/// How many of the most and least reused thumbnails a report lists
+const LISTED_THUMBS: usize = 5;
+
+/// How well a mosaic's thumbnails covered its cells, without compositing anything
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub algorithm: DifferenceFunction,
+    pub grid: (u32, u32),
+    /// Cells with a score, which leaves out cells carried over from a resumed map
+    pub scored_cells: usize,
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub worst: f64,
+    /// Thumbnails matched against, and how many of them were placed at least once
+    pub pool_size: usize,
+    pub distinct_used: usize,
+    /// The most placed thumbnails and how often, most first
+    pub most_used: Vec<(String, u32)>,
+    /// The least placed thumbnails that were placed at all, least first
+    pub least_used: Vec<(String, u32)>,
+    /// Score above which a cell counts as poorly matched
+    pub threshold: f64,
+    /// Every cell scoring above `threshold`, worst first
+    pub poor_cells: Vec<PoorCell>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoorCell {
+    pub x: u32,
+    pub y: u32,
+    pub score: f64,
+    pub path: String,
+}
+
+impl Report {
+    /// Summarise the scores and thumbnail use in `mosaic`. Cells scoring above
+    /// `threshold` are listed as poor matches, the worst 5% of cells if it's `None`
+    pub fn new(mosaic: &Mosaic, threshold: Option<f64>) -> Self {
+        let mut scores: Vec<f64> = mosaic.assignments.iter().filter_map(|a| a.score).collect();
+        scores.sort_by(f64::total_cmp);
+        let percentile = |p: f64| match scores.len() {
+            0 => 0.0,
+            n => scores[((n - 1) as f64 * p).round() as usize],
+        };
+        let p95 = percentile(0.95);
+        let threshold = threshold.unwrap_or(p95);
+
+        let mut uses: HashMap<&str, u32> = HashMap::new();
+        for assignment in &mosaic.assignments {
+            *uses.entry(&assignment.path).or_default() += 1;
+        }
+        // Ties are listed by path so the report doesn't change between runs
+        let mut uses: Vec<(String, u32)> = uses
+            .into_iter()
+            .map(|(path, n)| (path.to_string(), n))
+            .collect();
+        uses.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut least_used = uses.clone();
+        least_used.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        least_used.truncate(LISTED_THUMBS);
+
+        let mut poor_cells: Vec<PoorCell> = mosaic
+            .assignments
+            .iter()
+            .filter_map(|a| {
+                let score = a.score.filter(|&score| score > threshold)?;
+                Some(PoorCell {
+                    x: a.x,
+                    y: a.y,
+                    score,
+                    path: a.path.clone(),
+                })
+            })
+            .collect();
+        poor_cells.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+        Report {
+            algorithm: mosaic.algorithm,
+            grid: mosaic.grid,
+            scored_cells: scores.len(),
+            mean: scores.iter().sum::<f64>() / scores.len().max(1) as f64,
+            median: percentile(0.5),
+            p95,
+            worst: scores.last().copied().unwrap_or(0.0),
+            pool_size: mosaic.pool_size,
+            distinct_used: uses.len(),
+            most_used: uses.into_iter().take(LISTED_THUMBS).collect(),
+            least_used,
+            threshold,
+            poor_cells,
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Match distances ({:?}) over {} of {} cells",
+            self.algorithm,
+            self.scored_cells,
+            self.grid.0 * self.grid.1
+        );
+        println!("{:<12} {:>12}", "mean", format_score(self.mean));
+        println!("{:<12} {:>12}", "median", format_score(self.median));
+        println!("{:<12} {:>12}", "p95", format_score(self.p95));
+        println!("{:<12} {:>12}", "worst", format_score(self.worst));
+
+        println!(
+            "\nUsed {} of {} thumbs, {} never placed",
+            self.distinct_used,
+            self.pool_size,
+            self.pool_size.saturating_sub(self.distinct_used)
+        );
+        println!("Most reused:");
+        for (path, uses) in &self.most_used {
+            println!("  {:>6}  {}", uses, path);
+        }
+        println!("Least reused:");
+        for (path, uses) in &self.least_used {
+            println!("  {:>6}  {}", uses, path);
+        }
+
+        println!(
+            "\n{} cells matched worse than {}",
+            self.poor_cells.len(),
+            format_score(self.threshold)
+        );
+        for cell in self.poor_cells.iter().take(LISTED_THUMBS * 2) {
+            println!(
+                "  ({:>3}, {:>3})  {:>12}  {}",
+                cell.x,
+                cell.y,
+                format_score(cell.score),
+                cell.path
+            );
+        }
+        if self.poor_cells.len() > LISTED_THUMBS * 2 {
+            println!("  ...");
+        }
+    }
+}
+
+/// Scores span from fractions (Oklab) to hundreds of thousands (RGB)
+fn format_score(score: f64) -> String {
+    if score < 10.0 {
+        format!("{:.4}", score)
+    } else {
+        format!("{:.1}", score)
+    }
+}
@@ -0,0 +1,400 @@
+use std::{fs, io::Cursor, iter::zip};
+
+use image::{
+    DynamicImage, GenericImageView, ImageDecoder, ImageReader, Rgb32FImage, RgbImage,
+    imageops::FilterType, metadata::Orientation,
+};
+use oklab::{Oklab, oklab_to_linear_srgb, srgb_to_oklab};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error,
+    canvas::{linear_to_srgb, srgb_to_linear},
+};
+
+/// How the pixels under each sample are reduced to a single colour
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+pub enum SampleStat {
+    /// Filtered average of the covered pixels
+    #[default]
+    Mean,
+    /// Per-channel median of the covered pixels, robust to small bright or dark spots
+    Median,
+    /// Filtered average taken in linear light, so high contrast areas don't average out
+    /// darker and duller than they look
+    LinearMean,
+}
+
+/// Resampling filter used to shrink thumbnails and chunks, and to resize placed tiles
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+pub enum ResizeFilter {
+    /// Fastest, blocky
+    Nearest,
+    /// Linear
+    Triangle,
+    /// Cubic
+    #[default]
+    CatmullRom,
+    /// Soft
+    Gaussian,
+    /// Sharpest, slowest
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Copy the `size` (width, height) cell at `x`, `y`. Cells overhanging the image edge are
+/// filled by mirroring the pixels they do cover, so their features aren't skewed by padding
+pub(crate) fn extract_chunk(image: &RgbImage, x: u32, y: u32, size: (u32, u32)) -> RgbImage {
+    let width = size.0.min(image.width() - x);
+    let height = size.1.min(image.height() - y);
+    let chunk = image.view(x, y, width, height).to_image();
+
+    if (width, height) == size {
+        return chunk;
+    }
+
+    let reflect = |i: u32, n: u32| {
+        let i = i % (2 * n);
+        if i < n { i } else { 2 * n - 1 - i }
+    };
+
+    RgbImage::from_fn(size.0, size.1, |x, y| {
+        *chunk.get_pixel(reflect(x, width), reflect(y, height))
+    })
+}
+
+pub(crate) fn resample_colors(
+    colors: &[[u8; 3]],
+    from: u32,
+    to: u32,
+    stat: SampleStat,
+    filter: ResizeFilter,
+) -> Vec<[u8; 3]> {
+    let grid = RgbImage::from_fn(from, from, |x, y| {
+        image::Rgb(colors[(y * from + x) as usize])
+    });
+
+    let resampled = match stat {
+        SampleStat::LinearMean => resize_linear(&grid, to, filter),
+        SampleStat::Mean | SampleStat::Median => {
+            image::imageops::resize(&grid, to, to, filter.into())
+        }
+    };
+
+    rgb_thumb_to_pixels(&resampled)
+}
+
+/// Reduce an image to a `res`x`res` grid of colours using the chosen statistic. Only
+/// the mean is resampled through `filter`
+pub(crate) fn sample_image(
+    image: &DynamicImage,
+    res: u32,
+    stat: SampleStat,
+    filter: ResizeFilter,
+) -> Vec<[u8; 3]> {
+    match stat {
+        SampleStat::Mean => rgb_thumb_to_pixels(&get_thumb(image, res, filter)),
+        SampleStat::Median => median_grid(&image.to_rgb8(), res),
+        SampleStat::LinearMean => {
+            rgb_thumb_to_pixels(&resize_linear(&image.to_rgb8(), res, filter))
+        }
+    }
+}
+
+/// Resize `image` to `res`x`res` with its pixels converted to linear light and back
+fn resize_linear(image: &RgbImage, res: u32, filter: ResizeFilter) -> RgbImage {
+    let linear = Rgb32FImage::from_fn(image.width(), image.height(), |x, y| {
+        image::Rgb(image.get_pixel(x, y).0.map(srgb_to_linear))
+    });
+    let resized = image::imageops::resize(&linear, res, res, filter.into());
+
+    RgbImage::from_fn(res, res, |x, y| {
+        image::Rgb(resized.get_pixel(x, y).0.map(linear_to_srgb))
+    })
+}
+
+/// Per-channel median of the full resolution pixels under each grid cell
+fn median_grid(image: &RgbImage, res: u32) -> Vec<[u8; 3]> {
+    let (width, height) = image.dimensions();
+    let bounds = |i: u32, size: u32| {
+        let start = (i * size / res).min(size - 1);
+        let end = ((i + 1) * size / res).max(start + 1);
+        start..end
+    };
+
+    let mut colors = Vec::with_capacity((res * res) as usize);
+    let mut channels: [Vec<u8>; 3] = Default::default();
+
+    for y in 0..res {
+        for x in 0..res {
+            channels.iter_mut().for_each(Vec::clear);
+
+            for py in bounds(y, height) {
+                for px in bounds(x, width) {
+                    let pixel = image.get_pixel(px, py);
+                    for (c, channel) in channels.iter_mut().enumerate() {
+                        channel.push(pixel[c]);
+                    }
+                }
+            }
+
+            colors.push(channels.each_mut().map(|channel| {
+                let mid = channel.len() / 2;
+                *channel.select_nth_unstable(mid).1
+            }));
+        }
+    }
+
+    colors
+}
+
+fn get_thumb(
+    image: &DynamicImage,
+    res: u32,
+    filter: ResizeFilter,
+) -> image::ImageBuffer<image::Rgb<u8>, Vec<u8>> {
+    image
+        .clone()
+        .resize_exact(res, res, filter.into())
+        .to_rgb8()
+}
+
+/// Decode the image at `p` as `decode_image` does
+pub(crate) fn load_image<P>(p: P, ignore_exif: bool) -> Result<(DynamicImage, bool), Error>
+where
+    P: AsRef<std::path::Path>,
+{
+    let p = p.as_ref();
+    let raw_image = fs::read(p).map_err(|e| Error::Io(p.to_path_buf(), e))?;
+
+    decode_image(&raw_image, ignore_exif).map_err(|e| Error::Image(p.display().to_string(), e))
+}
+
+/// Decode an encoded image, turned upright as its EXIF orientation says unless
+/// `ignore_exif`. Also returns whether that orientation turns or mirrors it at all
+pub(crate) fn decode_image(
+    raw_image: &[u8],
+    ignore_exif: bool,
+) -> image::ImageResult<(DynamicImage, bool)> {
+    let reader = ImageReader::new(Cursor::new(raw_image))
+        .with_guessed_format()
+        .expect("Cursor io never fails");
+
+    let mut decoder = reader.into_decoder()?;
+    // A broken EXIF block isn't worth losing the image over
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    if !ignore_exif {
+        image.apply_orientation(orientation);
+    }
+
+    Ok((image, orientation != Orientation::NoTransforms))
+}
+
+/// `image` composited over `background` if it has an alpha channel, so transparent areas
+/// take that colour rather than whatever the decoder left under them
+pub(crate) fn flatten_alpha(image: DynamicImage, background: [u8; 3]) -> DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+
+    let rgba = image.to_rgba8();
+    let flat = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y).0;
+        let alpha = pixel[3] as f32 / 255.0;
+        image::Rgb(std::array::from_fn(|c| {
+            (pixel[c] as f32 * alpha + background[c] as f32 * (1.0 - alpha)).round() as u8
+        }))
+    });
+
+    DynamicImage::ImageRgb8(flat)
+}
+
+/// How thumbnails shot under different light are evened out before they're sampled and
+/// placed
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+pub enum WhiteBalance {
+    /// Keep every thumbnail's colours as they are
+    #[default]
+    None,
+    /// Scale each channel so the whole image averages out to a neutral grey
+    GrayWorld,
+    /// Scale each channel so the image's brightest pixels come out neutral
+    WhitePatch,
+}
+
+/// Fraction of each channel's values below the level `WhiteBalance::WhitePatch` takes as
+/// white, so a few clipped highlights don't decide it
+const WHITE_PATCH_PERCENTILE: f32 = 0.99;
+
+/// Most a channel is scaled by either way, so a thumbnail that really is mostly one
+/// colour, like a sunset, is toned down rather than turned grey
+const MAX_WHITE_BALANCE_GAIN: f32 = 2.0;
+
+/// `image` with each channel scaled in linear light as `mode` says. Channels are scaled
+/// relative to each other, so the overall brightness stays about the same. Alpha is
+/// dropped, as it is when sampling and placing
+pub(crate) fn white_balance(image: DynamicImage, mode: WhiteBalance) -> DynamicImage {
+    if mode == WhiteBalance::None {
+        return image;
+    }
+
+    let mut rgb = image.to_rgb8();
+    let mut histograms = [[0u64; 256]; 3];
+    for pixel in rgb.pixels() {
+        for c in 0..3 {
+            histograms[c][pixel[c] as usize] += 1;
+        }
+    }
+    let linear: [f32; 256] = std::array::from_fn(|v| srgb_to_linear(v as u8));
+    let total = (rgb.width() as u64 * rgb.height() as u64).max(1);
+
+    let levels: [f32; 3] = std::array::from_fn(|c| match mode {
+        WhiteBalance::GrayWorld => {
+            let sum: f64 = zip(&histograms[c], &linear)
+                .map(|(&n, &l)| n as f64 * l as f64)
+                .sum();
+            (sum / total as f64) as f32
+        }
+        _ => {
+            let threshold = (total as f32 * WHITE_PATCH_PERCENTILE) as u64;
+            let mut seen = 0;
+            let level = histograms[c].iter().position(|&n| {
+                seen += n;
+                seen > threshold
+            });
+            linear[level.unwrap_or(255)]
+        }
+    });
+    let target = match mode {
+        WhiteBalance::GrayWorld => levels.iter().sum::<f32>() / 3.0,
+        _ => levels.iter().copied().fold(0.0, f32::max),
+    };
+    let gains = levels.map(|level| match level > 0.0 {
+        true => (target / level).clamp(1.0 / MAX_WHITE_BALANCE_GAIN, MAX_WHITE_BALANCE_GAIN),
+        false => 1.0,
+    });
+
+    let lookup: [[u8; 256]; 3] =
+        std::array::from_fn(|c| std::array::from_fn(|v| linear_to_srgb(linear[v] * gains[c])));
+    for pixel in rgb.pixels_mut() {
+        for c in 0..3 {
+            pixel[c] = lookup[c][pixel[c] as usize];
+        }
+    }
+
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// `image` with its Oklab chroma scaled by `saturation` and its lightness spread away
+/// from its average lightness by `contrast`, both 1 leaving it as it is. Colours pushed
+/// out of gamut are clamped. Alpha is dropped
+pub(crate) fn adjust_input(image: DynamicImage, saturation: f32, contrast: f32) -> DynamicImage {
+    if saturation == 1.0 && contrast == 1.0 {
+        return image;
+    }
+
+    let mut rgb = image.to_rgb8();
+    let lab = |pixel: &image::Rgb<u8>| srgb_to_oklab(oklab::Rgb::from(pixel.0));
+    let total = (rgb.width() as u64 * rgb.height() as u64).max(1);
+    let mean = (rgb.pixels().map(|pixel| lab(pixel).l as f64).sum::<f64>() / total as f64) as f32;
+
+    for pixel in rgb.pixels_mut() {
+        let Oklab { l, a, b } = lab(pixel);
+        let linear = oklab_to_linear_srgb(Oklab {
+            l: mean + (l - mean) * contrast,
+            a: a * saturation,
+            b: b * saturation,
+        });
+        pixel.0 = [linear.r, linear.g, linear.b].map(linear_to_srgb);
+    }
+
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// Side of the luma grid `structure` is measured on, about the scale of detail a tile
+/// shows at its cell size
+const STRUCTURE_RES: u32 = 8;
+
+/// How much detail `image` has at cell scale, which averaged samples don't capture: the
+/// standard deviation of its luma and the mean absolute difference between horizontal
+/// and vertical neighbours, each from 0 to 1, on a small greyscale copy
+pub(crate) fn structure(image: &DynamicImage) -> [f32; 3] {
+    let small = image
+        .resize_exact(STRUCTURE_RES, STRUCTURE_RES, FilterType::Triangle)
+        .to_luma8();
+    let luma = |x: u32, y: u32| small.get_pixel(x, y)[0] as f32 / 255.0;
+
+    let n = (STRUCTURE_RES * STRUCTURE_RES) as f32;
+    let mean = small.pixels().map(|p| p[0] as f32 / 255.0).sum::<f32>() / n;
+    let variance = small
+        .pixels()
+        .map(|p| (p[0] as f32 / 255.0 - mean).powi(2))
+        .sum::<f32>()
+        / n;
+
+    let (mut dx, mut dy) = (0f32, 0f32);
+    for y in 0..STRUCTURE_RES {
+        for x in 1..STRUCTURE_RES {
+            dx += (luma(x, y) - luma(x - 1, y)).abs();
+            dy += (luma(y, x) - luma(y, x - 1)).abs();
+        }
+    }
+    let pairs = (STRUCTURE_RES * (STRUCTURE_RES - 1)) as f32;
+
+    [variance.sqrt(), dx / pairs, dy / pairs]
+}
+
+fn rgb_thumb_to_pixels(thumb: &RgbImage) -> Vec<[u8; 3]> {
+    Vec::from_iter(thumb.enumerate_pixels().map(|(_x, _y, pixel)| pixel.0))
+}
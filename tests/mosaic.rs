@@ -0,0 +1,186 @@
+use std::{fs, path::PathBuf};
+
+use image::{Rgb, RgbImage};
+use imagegrid::{Config, DbFormat, Mosaic, generate_mosaic};
+
+/// A scratch directory of generated input and thumbnail images, removed again on drop
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("imagegrid-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("thumbs")).unwrap();
+
+        Fixture { dir }
+    }
+
+    fn input(&self, image: &RgbImage) -> PathBuf {
+        let path = self.dir.join("input.png");
+        image.save(&path).unwrap();
+        path
+    }
+
+    fn thumb(&self, name: &str, image: &RgbImage) {
+        image.save(self.dir.join("thumbs").join(name)).unwrap();
+    }
+
+    /// A render of `input` against every thumbnail, indexed into this directory
+    fn config(&self, input: PathBuf) -> Config {
+        Config {
+            input,
+            thumbs: vec![self.dir.join("thumbs/*.png").to_string_lossy().into_owned()],
+            db_path: Some(self.dir.join("thumbdata")),
+            ..Config::default()
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn solid(width: u32, height: u32, color: [u8; 3]) -> RgbImage {
+    RgbImage::from_pixel(width, height, Rgb(color))
+}
+
+#[test]
+fn ties_go_to_the_first_path() {
+    let fixture = Fixture::new("ties");
+    // Written out of order, so neither creation nor directory order decides
+    fixture.thumb("b.png", &solid(8, 8, [90, 120, 150]));
+    fixture.thumb("a.png", &solid(8, 8, [90, 120, 150]));
+    fixture.thumb("c.png", &solid(8, 8, [250, 10, 10]));
+    let config = fixture.config(fixture.input(&solid(64, 64, [90, 120, 150])));
+
+    for _ in 0..2 {
+        let mosaic = generate_mosaic(&config).unwrap();
+        assert_eq!(mosaic.assignments.len(), 4);
+        for cell in &mosaic.assignments {
+            assert!(cell.path.ends_with("a.png"), "{} won a tie", cell.path);
+        }
+    }
+}
+
+#[test]
+fn wide_cells_tile_without_overlapping() {
+    let fixture = Fixture::new("wide-cells");
+    let colors = [[250, 20, 20], [20, 250, 20], [20, 20, 250], [240, 240, 240]];
+    for (i, color) in colors.iter().enumerate() {
+        fixture.thumb(&format!("{i}.png"), &solid(12, 12, *color));
+    }
+    // 16x8 cells, each coloured like one of the thumbnails
+    let color_at = |x: u32, y: u32| colors[((x + y) % 4) as usize];
+    let input = RgbImage::from_fn(64, 32, |px, py| Rgb(color_at(px / 16, py / 8)));
+    let config = Config {
+        thumb_width: Some(16),
+        thumb_height: Some(8),
+        ..fixture.config(fixture.input(&input))
+    };
+
+    let mosaic = generate_mosaic(&config).unwrap();
+    assert_eq!(mosaic.grid, (4, 4));
+    assert_eq!(mosaic.tile_size, (16, 8));
+    assert_eq!(mosaic.image.dimensions(), (64, 32));
+
+    let mut cells: Vec<(u32, u32)> = mosaic.assignments.iter().map(|a| (a.x, a.y)).collect();
+    cells.sort();
+    cells.dedup();
+    assert_eq!(cells.len(), 16);
+
+    for cell in &mosaic.assignments {
+        let expected = color_at(cell.x, cell.y);
+        assert!(
+            cell.path
+                .ends_with(&format!("{}.png", (cell.x + cell.y) % 4))
+        );
+        // The whole cell shows its tile, so no tile spills into a neighbour
+        for py in cell.y * 8..(cell.y + 1) * 8 {
+            for px in cell.x * 16..(cell.x + 1) * 16 {
+                assert_eq!(mosaic.image.get_pixel(px, py).0, expected, "({px}, {py})");
+            }
+        }
+    }
+}
+
+#[test]
+fn indexing_the_same_thumbnails_writes_the_same_bytes() {
+    let fixture = Fixture::new("same-db");
+    for i in 0..24u32 {
+        let thumb = RgbImage::from_fn(20 + i, 16, |x, y| {
+            Rgb([(x * 11 + i * 7) as u8, (y * 13) as u8, (i * 10) as u8])
+        });
+        fixture.thumb(&format!("{i}.png"), &thumb);
+    }
+    let input = fixture.input(&solid(64, 64, [128, 64, 32]));
+
+    for format in [DbFormat::Ron, DbFormat::Bincode] {
+        let written: Vec<Vec<u8>> = ["first", "second"]
+            .iter()
+            .map(|run| {
+                let db_path = fixture.dir.join(format!("{run}-{format:?}"));
+                let config = Config {
+                    db_path: Some(db_path.clone()),
+                    db_format: Some(format),
+                    threads: Some(4),
+                    ..fixture.config(input.clone())
+                };
+                generate_mosaic(&config).unwrap();
+                fs::read(db_path).unwrap()
+            })
+            .collect();
+
+        assert_eq!(written[0], written[1], "{format:?}");
+    }
+}
+
+#[test]
+fn renders_come_out_in_raster_order_and_repeat_exactly() {
+    let fixture = Fixture::new("raster-order");
+    for i in 0..16u32 {
+        let thumb = RgbImage::from_fn(12, 12, |x, y| {
+            Rgb([(i * 16) as u8, (x * 20) as u8, (255 - i * 16 - y) as u8])
+        });
+        fixture.thumb(&format!("{i}.png"), &thumb);
+    }
+    let input = RgbImage::from_fn(80, 48, |x, y| Rgb([(x * 3) as u8, (y * 5) as u8, 90]));
+    let config = Config {
+        thumbsize: 8,
+        threads: Some(4),
+        ..fixture.config(fixture.input(&input))
+    };
+
+    let first = generate_mosaic(&config).unwrap();
+    assert_eq!(first.grid, (10, 6));
+    let cells: Vec<(u32, u32)> = first.assignments.iter().map(|a| (a.x, a.y)).collect();
+    let raster: Vec<(u32, u32)> = (0..6).flat_map(|y| (0..10).map(move |x| (x, y))).collect();
+    assert_eq!(cells, raster);
+
+    let second = generate_mosaic(&config).unwrap();
+    assert_eq!(first.image.as_raw(), second.image.as_raw());
+    let paths = |mosaic: &Mosaic| -> Vec<String> {
+        mosaic.assignments.iter().map(|a| a.path.clone()).collect()
+    };
+    assert_eq!(paths(&first), paths(&second));
+}
+
+#[test]
+fn cells_larger_than_the_input_are_refused() {
+    let fixture = Fixture::new("large-cells");
+    fixture.thumb("a.png", &solid(8, 8, [10, 20, 30]));
+    fixture.thumb("b.png", &solid(8, 8, [30, 20, 10]));
+    let config = Config {
+        thumbsize: 300,
+        ..fixture.config(fixture.input(&solid(200, 130, [20, 20, 20])))
+    };
+
+    let error = generate_mosaic(&config).err().unwrap();
+    assert!(matches!(
+        error,
+        imagegrid::Error::CellLargerThanInput((300, 300), (200, 130))
+    ));
+}